@@ -3,7 +3,10 @@ use std::path::Path;
 use anyhow::{bail, Context};
 use ffmpeg_next::ChannelLayout;
 
-pub fn decode_channels<P: AsRef<Path>>(p: &P) -> anyhow::Result<(Vec<Vec<i16>>, u16)> {
+use crate::Sink;
+
+/// decodes the file and passes the samples to `sink` as they are decoded, returns the sampling rate
+pub fn decode_streaming<P: AsRef<Path>>(p: &P, sink: &mut Sink) -> anyhow::Result<u16> {
     ffmpeg_next::init().expect("couldn't init ffmpeg");
     // println!("ffmpeg format version: {}", ffmpeg_next::format::version());
     // println!(
@@ -53,7 +56,6 @@ pub fn decode_channels<P: AsRef<Path>>(p: &P) -> anyhow::Result<(Vec<Vec<i16>>,
     i16_raw_frame.set_channel_layout(encoder.channel_layout());
     i16_raw_frame.set_rate(encoder.rate());
     i16_raw_frame.set_format(encoder.format());
-    let mut out_channels = vec![Vec::new(); decoder.channels() as usize];
     for (_, packet) in opened.packets() {
         if packet.stream() == audio_stream_index {
             decoder
@@ -64,7 +66,7 @@ pub fn decode_channels<P: AsRef<Path>>(p: &P) -> anyhow::Result<(Vec<Vec<i16>>,
                 &mut resampler,
                 &mut mp3_raw_frame,
                 &mut i16_raw_frame,
-                &mut out_channels,
+                sink,
             )?;
         }
     }
@@ -74,9 +76,9 @@ pub fn decode_channels<P: AsRef<Path>>(p: &P) -> anyhow::Result<(Vec<Vec<i16>>,
         &mut resampler,
         &mut mp3_raw_frame,
         &mut i16_raw_frame,
-        &mut out_channels,
+        sink,
     )?;
-    Ok((out_channels, decoder.rate() as u16))
+    Ok(decoder.rate() as u16)
 }
 
 // Ok(true) means continue, Ok(false) means break
@@ -100,7 +102,7 @@ fn receive_decoded_frames(
     resampler: &mut ffmpeg_next::software::resampling::Context,
     mp3_raw_frame: &mut ffmpeg_next::frame::Audio,
     i16_raw_frame: &mut ffmpeg_next::frame::Audio,
-    out: &mut [Vec<i16>],
+    sink: &mut Sink,
 ) -> anyhow::Result<()> {
     while map_receive_result(decoder.receive_frame(mp3_raw_frame))? {
         // ???
@@ -110,10 +112,10 @@ fn receive_decoded_frames(
         let mut delay = resampler
             .run(mp3_raw_frame, i16_raw_frame)
             .context("resampler failed")?;
-        send_to_encode(i16_raw_frame, out)?;
+        send_to_encode(i16_raw_frame, sink)?;
         while delay.is_some() {
             delay = resampler.flush(i16_raw_frame)?;
-            send_to_encode(i16_raw_frame, out)?;
+            send_to_encode(i16_raw_frame, sink)?;
         }
     }
     Ok(())
@@ -121,11 +123,10 @@ fn receive_decoded_frames(
 
 fn send_to_encode(
     i16_raw_frame: &mut ffmpeg_next::frame::Audio,
-    out: &mut [Vec<i16>],
+    sink: &mut Sink,
 ) -> anyhow::Result<()> {
-    assert_eq!(out.len(), i16_raw_frame.planes());
-    for (plane_idx, chn) in out.iter_mut().enumerate() {
-        chn.extend_from_slice(i16_raw_frame.plane(plane_idx));
-    }
-    Ok(())
+    let planes: Vec<&[i16]> = (0..i16_raw_frame.planes())
+        .map(|plane_idx| i16_raw_frame.plane(plane_idx))
+        .collect();
+    sink(&planes)
 }
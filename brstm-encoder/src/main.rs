@@ -1,25 +1,156 @@
 use std::{fs::File, io::BufWriter};
 
 use anyhow::{bail, Context};
-use brstm::encoder::encode_brstm;
-use clap::Parser;
+use brstm::{
+    encoder::{
+        encode_brstm_with_options, CoefficientAnalyzer, EncodeOptions, Quality, StreamEncoder,
+        DEFAULT_BLOCK_SIZE,
+    },
+    structs::Codec,
+};
+use clap::{Parser, ValueEnum};
 
+#[cfg(not(any(feature = "ffmpeg", feature = "native-wav")))]
+compile_error!("at least one of the features \"ffmpeg\" or \"native-wav\" has to be enabled");
+
+#[cfg(feature = "ffmpeg")]
 mod ffmpeg;
 
 #[derive(Parser)]
 #[command(version)]
 /// Encodes WAV files to BRSTM
+///
+/// The ADPCM coefficients are analyzed with up to 24 MiB per channel, longer inputs
+/// (above about 14.7 million samples) only use an evenly spread part of the samples for that
 pub struct Args {
-    /// Path to the wav file to encode
+    /// Path to the audio file to encode
     input_path: String,
     /// Path to the output brstm file, default <filename>.brstm
     brstm_path: Option<String>,
     #[arg(short = 'l', long)]
-    /// If set, specifies the loop point
+    /// If set, specifies the loop point, otherwise the loop of a WAV smpl chunk is used
     r#loop: Option<u32>,
     #[arg(short = 'e', long)]
     /// If set, specifies the end point
     end: Option<u32>,
+    #[arg(short = 'c', long, value_enum, default_value_t = CodecArg::Adpcm)]
+    /// Codec to use, PCM is lossless but a lot bigger
+    codec: CodecArg,
+    #[arg(short = 'b', long, default_value_t = DEFAULT_BLOCK_SIZE)]
+    /// Bytes of a block of a single channel, has to be a multiple of 32
+    block_size: u32,
+    #[arg(short = 'q', long)]
+    /// Search for the ADPCM encoding with the smallest error, less hiss in quiet tracks but a lot slower
+    quality: bool,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum CodecArg {
+    Adpcm,
+    Pcm16,
+    Pcm8,
+}
+
+impl CodecArg {
+    fn to_codec(self) -> Codec {
+        match self {
+            Self::Pcm8 => Codec::Pcm8,
+            Self::Pcm16 => Codec::Pcm16,
+            Self::Adpcm => Codec::Adpcm,
+        }
+    }
+}
+
+#[cfg(feature = "native-wav")]
+struct DecodedAudio {
+    channels: Vec<Vec<i16>>,
+    sampling_rate: u16,
+    /// loop start and (exclusive) loop end
+    loop_points: Option<(u32, u32)>,
+}
+
+/// where the samples come from, ffmpeg input isn't kept in memory but decoded again for every pass
+enum Input {
+    #[cfg(feature = "native-wav")]
+    Decoded(DecodedAudio),
+    #[cfg(feature = "ffmpeg")]
+    Ffmpeg(String),
+}
+
+/// the samples of all channels, one slice per channel
+type Sink<'a> = dyn FnMut(&[&[i16]]) -> anyhow::Result<()> + 'a;
+
+impl Input {
+    fn loop_points(&self) -> Option<(u32, u32)> {
+        match self {
+            #[cfg(feature = "native-wav")]
+            Self::Decoded(decoded) => decoded.loop_points,
+            #[cfg(feature = "ffmpeg")]
+            Self::Ffmpeg(_) => None,
+        }
+    }
+
+    /// passes the first `end` samples to `sink`, returns the sampling rate
+    fn for_each_chunk(&self, end: Option<u32>, sink: &mut Sink) -> anyhow::Result<u16> {
+        let mut remaining = end.map_or(usize::MAX, |end| end as usize);
+        let mut truncated = |chunk: &[&[i16]]| {
+            let len = chunk.first().map_or(0, |c| c.len()).min(remaining);
+            remaining -= len;
+            if len == 0 {
+                return Ok(());
+            }
+            let chunk: Vec<&[i16]> = chunk.iter().map(|c| &c[..len.min(c.len())]).collect();
+            sink(&chunk)
+        };
+        match self {
+            #[cfg(feature = "native-wav")]
+            Self::Decoded(decoded) => {
+                let chunk: Vec<&[i16]> = decoded.channels.iter().map(Vec::as_slice).collect();
+                truncated(&chunk)?;
+                Ok(decoded.sampling_rate)
+            }
+            #[cfg(feature = "ffmpeg")]
+            Self::Ffmpeg(path) => ffmpeg::decode_streaming(path, &mut truncated),
+        }
+    }
+}
+
+#[cfg(feature = "native-wav")]
+fn decode_wav(path: &str) -> anyhow::Result<DecodedAudio> {
+    let mut f =
+        std::io::BufReader::new(File::open(path).with_context(|| format!("couldn't open {path}"))?);
+    let wav = brstm::wav::read_wav(&mut f).with_context(|| format!("couldn't read {path}"))?;
+    Ok(DecodedAudio {
+        channels: wav.channels,
+        sampling_rate: wav
+            .sample_rate
+            .try_into()
+            .context("sample rate is too high")?,
+        loop_points: wav.loop_points,
+    })
+}
+
+fn open_input(path: &str) -> anyhow::Result<Input> {
+    #[cfg(feature = "native-wav")]
+    if path
+        .rsplit_once('.')
+        .is_some_and(|(_, ext)| ext.eq_ignore_ascii_case("wav"))
+    {
+        match decode_wav(path) {
+            Ok(decoded) => return Ok(Input::Decoded(decoded)),
+            // WAVs that aren't PCM can still be decoded by ffmpeg
+            #[cfg(feature = "ffmpeg")]
+            Err(e) => println!("{e:#}, falling back to ffmpeg"),
+            #[cfg(not(feature = "ffmpeg"))]
+            Err(e) => return Err(e),
+        }
+    }
+    #[cfg(feature = "ffmpeg")]
+    {
+        Ok(Input::Ffmpeg(path.to_string()))
+    }
+    #[cfg(not(feature = "ffmpeg"))]
+    bail!("only WAV files are supported without ffmpeg")
 }
 
 fn main() -> anyhow::Result<()> {
@@ -35,18 +166,46 @@ fn main() -> anyhow::Result<()> {
                 .0
         )
     };
-    let (mut channels, sampling_rate) = ffmpeg::decode_channels(&args.input_path).unwrap();
-    if channels.is_empty() {
-        bail!("no channels");
-    }
+    let input = open_input(&args.input_path)?;
+    let loop_points = input.loop_points();
 
-    if let Some(end) = args.end {
-        for channel in channels.iter_mut() {
-            channel.truncate(end as usize);
+    // the stream ends at the loop end
+    let end = args.end.or(loop_points.map(|(_, end)| end));
+    let loop_point = args.r#loop.or(loop_points.map(|(start, _)| start));
+
+    // first pass, the coefficients need all samples before anything can be encoded
+    let mut analyzers: Vec<CoefficientAnalyzer> = Vec::new();
+    let mut channels: Vec<Vec<i16>> = Vec::new();
+    let mut sample_count = 0;
+    let options = EncodeOptions {
+        codec: args.codec.to_codec(),
+        block_size: args.block_size,
+        quality: if args.quality {
+            Quality::High
+        } else {
+            Quality::Standard
+        },
+    };
+    let codec = options.codec;
+    let sampling_rate = input.for_each_chunk(end, &mut |chunk| {
+        sample_count += chunk[0].len();
+        if codec == Codec::Adpcm {
+            analyzers.resize_with(chunk.len(), CoefficientAnalyzer::new);
+            for (analyzer, samples) in analyzers.iter_mut().zip(chunk) {
+                analyzer.push(samples);
+            }
+        } else {
+            channels.resize_with(chunk.len(), Vec::new);
+            for (channel, samples) in channels.iter_mut().zip(chunk) {
+                channel.extend_from_slice(samples);
+            }
         }
+        Ok(())
+    })?;
+    if analyzers.is_empty() && channels.is_empty() {
+        bail!("no channels");
     }
-    let sample_count = channels[0].len();
-    if let Some(loop_point) = args.r#loop {
+    if let Some(loop_point) = loop_point {
         println!(
             "encoding {} to {}, samples: {}, loop: {}",
             args.input_path, brstm_path, sample_count, loop_point
@@ -57,12 +216,29 @@ fn main() -> anyhow::Result<()> {
             args.input_path, brstm_path, sample_count
         );
     }
-    let out_brstm =
-        encode_brstm(&channels, sampling_rate, args.r#loop).context("error encoding brstm")?;
     let mut out_file =
         BufWriter::new(File::create(&brstm_path).context("error creating out file")?);
-    out_brstm
-        .write_brstm(&mut out_file)
-        .context("error writing out file")?;
+    if codec == Codec::Adpcm {
+        let coefficients: Vec<[i16; 16]> = analyzers.into_iter().map(|a| a.finish()).collect();
+        let mut encoder = StreamEncoder::with_options(
+            out_file,
+            &coefficients,
+            sampling_rate,
+            sample_count.try_into().context("too many samples")?,
+            loop_point,
+            &options,
+        )
+        .context("error encoding brstm")?;
+        input.for_each_chunk(end, &mut |chunk| {
+            encoder.push(chunk).context("error encoding brstm")
+        })?;
+        encoder.finish().context("error writing out file")?;
+    } else {
+        let out_brstm = encode_brstm_with_options(&channels, sampling_rate, loop_point, &options)
+            .context("error encoding brstm")?;
+        out_brstm
+            .write_brstm(&mut out_file)
+            .context("error writing out file")?;
+    }
     Ok(())
 }
@@ -1,19 +1,18 @@
-use std::{env::args, fs::File};
+use std::{env::args, fs};
 
-use brstm::BrstmInformation;
-
-pub fn try_main() -> binrw::BinResult<()> {
-    let mut files = args().skip(1);
-    let in_filename = files.next().expect("no in filename");
-    let mut f = File::open(&in_filename)?;
-    let src = BrstmInformation::from_reader(&mut f)?;
-    drop(f);
-    if !src.check_tracks_valid() {
-        println!("{in_filename} has invalid tracks!!!");
-    }
-    Ok(())
-}
+use brstm::validate::validate;
 
 pub fn main() {
-    try_main().unwrap();
+    for in_filename in args().skip(1) {
+        let bytes = fs::read(&in_filename).unwrap();
+        let report = validate(&bytes);
+        if report.findings.is_empty() {
+            println!("{in_filename}: ok");
+            continue;
+        }
+        println!("{in_filename}:");
+        for finding in report.findings.iter() {
+            println!("  {finding}");
+        }
+    }
 }
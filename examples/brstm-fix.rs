@@ -1,15 +1,20 @@
 use std::{env::args, fs::File};
 
-use brstm::BrstmInformation;
+use brstm::repair::{repair_file, RepairError};
 
-pub fn try_main() -> binrw::BinResult<()> {
+pub fn try_main() -> Result<(), RepairError> {
     let mut files = args().skip(1);
     let in_filename = files.next().expect("no in filename");
     let out_filename = files.next().expect("no out filename");
     let mut f = File::open(in_filename)?;
-    let mut src = BrstmInformation::from_reader(&mut f)?.into_with_data(&mut f)?;
+    let (src, repairs) = repair_file(&mut f)?;
     drop(f);
-    src.info.fix_tracks();
+    if repairs.is_empty() {
+        println!("nothing to repair");
+    }
+    for repair in repairs.iter() {
+        println!("{repair}");
+    }
     let mut outf = File::create(out_filename)?;
     src.write_brstm(&mut outf)?;
     Ok(())
@@ -0,0 +1,29 @@
+use std::{env::args, fs::File, io::BufWriter};
+
+use brstm::{decoder::LoopPlayback, wav::write_wav, BrstmInformation};
+
+/// renders what the game plays, including the loops, into a wav: <in> <out> [loop count, default 2]
+pub fn main() {
+    let mut args = args().skip(1);
+    let in_filename = args.next().expect("no in filename");
+    let out_filename = args.next().expect("no out filename");
+    let loops = args
+        .next()
+        .map_or(2, |l| l.parse::<u32>().expect("loop count is not a number"));
+    let mut f = File::open(in_filename).unwrap();
+    let src = BrstmInformation::from_reader(&mut f)
+        .unwrap()
+        .into_with_data(&mut f)
+        .unwrap();
+    let mut channels = vec![Vec::new(); src.info.channel_count().into()];
+    for chunk in LoopPlayback::new(&src, Some(loops)).unwrap() {
+        if chunk.after_loop {
+            println!("loop at {}", channels[0].len());
+        }
+        for (channel, samples) in channels.iter_mut().zip(chunk.samples) {
+            channel.extend(samples);
+        }
+    }
+    let mut outf = BufWriter::new(File::create(out_filename).unwrap());
+    write_wav(&mut outf, &channels, src.info.info.sample_rate.into(), None).unwrap();
+}
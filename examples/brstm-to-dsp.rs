@@ -0,0 +1,19 @@
+use std::{env::args, fs::File};
+
+use brstm::{dsp::split_brstm, BrstmInformation};
+
+/// splits a brstm into one dsp file per channel: <out prefix>_<channel>.dsp
+pub fn main() {
+    let mut files = args().skip(1);
+    let in_filename = files.next().expect("no in filename");
+    let out_prefix = files.next().expect("no out prefix");
+    let mut f = File::open(in_filename).unwrap();
+    let src = BrstmInformation::from_reader(&mut f)
+        .unwrap()
+        .into_with_data(&mut f)
+        .unwrap();
+    for (channel, dsp) in split_brstm(&src).unwrap().iter().enumerate() {
+        let mut outf = File::create(format!("{out_prefix}_{channel}.dsp")).unwrap();
+        dsp.write_dsp(&mut outf).unwrap();
+    }
+}
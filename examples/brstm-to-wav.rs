@@ -0,0 +1,26 @@
+use std::{env::args, fs::File, io::BufWriter};
+
+use brstm::{
+    wav::{brstm_to_wav, track_to_wav},
+    BrstmInformation,
+};
+
+/// decodes a brstm into a wav, optionally only a single track: <in> <out> [track]
+pub fn main() {
+    let mut args = args().skip(1);
+    let in_filename = args.next().expect("no in filename");
+    let out_filename = args.next().expect("no out filename");
+    let track = args
+        .next()
+        .map(|t| t.parse::<usize>().expect("track is not a number"));
+    let mut f = File::open(in_filename).unwrap();
+    let src = BrstmInformation::from_reader(&mut f)
+        .unwrap()
+        .into_with_data(&mut f)
+        .unwrap();
+    let mut outf = BufWriter::new(File::create(out_filename).unwrap());
+    match track {
+        Some(track) => track_to_wav(&src, track, &mut outf).unwrap(),
+        None => brstm_to_wav(&src, &mut outf).unwrap(),
+    }
+}
@@ -0,0 +1,15 @@
+use std::{env::args, fs::File};
+
+use brstm::dsp::{build_brstm, DspFile};
+
+/// builds a brstm out of dsp files, each file is one channel: <out> <in dsps>...
+pub fn main() {
+    let mut files = args().skip(1);
+    let out_filename = files.next().expect("no out filename");
+    let dsps: Vec<_> = files
+        .map(|filename| DspFile::from_reader(&mut File::open(filename).unwrap()).unwrap())
+        .collect();
+    let brstm = build_brstm(&dsps).unwrap();
+    let mut outf = File::create(out_filename).unwrap();
+    brstm.write_brstm(&mut outf).unwrap();
+}
@@ -0,0 +1,115 @@
+//! measures the ADPCM encoder: cargo run --release --example encode-bench [seconds of audio]
+//! The frame encoder benefits a lot from wider vector instructions, for example with
+//! `RUSTFLAGS="-C target-cpu=native"`
+use std::{
+    env::args,
+    hint::black_box,
+    io::Cursor,
+    time::{Duration, Instant},
+};
+
+use brstm::{
+    adpcm::{encode_frame, encode_frame_reference, History, PACKET_SAMPLES},
+    encoder::{encode_brstm, CoefficientAnalyzer, Quality, StreamEncoder},
+};
+
+/// low passed noise with a sawtooth, so all coefficient sets get used
+fn test_signal(channel: u32, len: usize) -> Vec<i16> {
+    let mut state = 0x1234_5678u32 ^ channel;
+    let mut filtered = 0i32;
+    (0..len)
+        .map(|i| {
+            state ^= state << 13;
+            state ^= state >> 17;
+            state ^= state << 5;
+            filtered += ((state >> 16) as i32 - 0x8000 - filtered) / 8;
+            let saw = (i as i32 * (60 + channel as i32 * 17)) % 16000 - 8000;
+            (filtered / 2 + saw) as i16
+        })
+        .collect()
+}
+
+fn report(name: &str, samples: usize, start: Instant) -> Duration {
+    let elapsed = start.elapsed();
+    println!(
+        "{name}: {elapsed:.2?}, {:.2} Msamples/s",
+        samples as f64 / elapsed.as_secs_f64() / 1_000_000.0
+    );
+    elapsed
+}
+
+/// encodes every channel frame by frame, like the encoder does within a block
+fn encode_frames(
+    channels: &[Vec<i16>],
+    coefficients: &[[i16; 16]],
+    encode: impl Fn(&[i16], &[i16; 16], &mut History) -> [u8; 8],
+) -> Vec<Vec<u8>> {
+    channels
+        .iter()
+        .zip(coefficients)
+        .map(|(channel, coefs)| {
+            let mut history = History::default();
+            channel
+                .chunks(PACKET_SAMPLES)
+                .flat_map(|frame| encode(frame, coefs, &mut history))
+                .collect()
+        })
+        .collect()
+}
+
+pub fn main() {
+    let seconds: usize = args().nth(1).map_or(60, |s| s.parse().unwrap());
+    let channels: Vec<Vec<i16>> = (0..2).map(|c| test_signal(c, seconds * 32000)).collect();
+    let samples = channels.iter().map(Vec::len).sum();
+
+    let start = Instant::now();
+    let coefficients: Vec<[i16; 16]> = channels
+        .iter()
+        .map(|channel| {
+            let mut analyzer = CoefficientAnalyzer::new();
+            analyzer.push(channel);
+            analyzer.finish()
+        })
+        .collect();
+    report("correlate", samples, start);
+
+    // the same frames with the integer encoder and the floating point reference
+    let start = Instant::now();
+    let integer = black_box(encode_frames(
+        &channels,
+        &coefficients,
+        |frame, coefs, history| encode_frame(frame, coefs, history, Quality::Standard),
+    ));
+    let integer_time = report("integer frames", samples, start);
+    let start = Instant::now();
+    let reference = black_box(encode_frames(
+        &channels,
+        &coefficients,
+        encode_frame_reference,
+    ));
+    let reference_time = report("reference frames", samples, start);
+    assert!(integer == reference, "the encoders disagree");
+    println!(
+        "speedup: {:.2}x",
+        reference_time.as_secs_f64() / integer_time.as_secs_f64()
+    );
+
+    // only the frame encoding, with the coefficients from above
+    let start = Instant::now();
+    let mut encoder = StreamEncoder::new(
+        Cursor::new(Vec::new()),
+        &coefficients,
+        32000,
+        channels[0].len() as u32,
+        None,
+    )
+    .unwrap();
+    let channel_refs: Vec<&[i16]> = channels.iter().map(Vec::as_slice).collect();
+    encoder.push(&channel_refs).unwrap();
+    black_box(encoder.finish().unwrap());
+    report("encode frames", samples, start);
+
+    let start = Instant::now();
+    black_box(encode_brstm(&channels, 32000, None).unwrap());
+    report("encode_brstm", samples, start);
+}
@@ -11,13 +11,20 @@ pub fn main() {
         let mut dest = Vec::with_capacity(orig.len());
         println!("{src}");
         let mut cursor = Cursor::new(&orig);
-        let parsed = BrstmInformation::from_reader(&mut cursor).unwrap();
+        let parsed = BrstmInformation::from_reader_lossless(&mut cursor).unwrap();
         let data_parsed = parsed.into_with_data(&mut cursor).unwrap();
         data_parsed
-            .write_brstm(&mut Cursor::new(&mut dest))
+            .write_brstm_lossless(&mut Cursor::new(&mut dest))
             .unwrap();
         if orig != dest {
             println!("missmatch: {src}");
         }
+        dest.clear();
+        data_parsed
+            .write_brstm(&mut Cursor::new(&mut dest))
+            .unwrap();
+        if orig != dest {
+            println!("normalized: {src}");
+        }
     }
 }
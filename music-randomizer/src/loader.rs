@@ -57,7 +57,7 @@ pub enum SongCategory {
 
 impl SongCategory {
     pub fn categorize(brstm: &BrstmInformation) -> Self {
-        if brstm.info.loop_flag == 1 {
+        if brstm.info.is_looping() {
             Self::Looping
         } else if brstm.info.total_samples
             < brstm.info.sample_rate as u32 * NONLOOPNING_SHORT_CUTOFF_SECONDS
@@ -81,7 +81,7 @@ pub struct MusicPack {
     pub replacements: HashMap<String, Rc<CustomMusicInfo>>,
 }
 
-pub fn read_all_music_packs(dir: &Path) -> binrw::BinResult<Vec<MusicPack>> {
+pub fn read_all_music_packs(dir: &Path) -> brstm::Result<Vec<MusicPack>> {
     let mut dirs = Vec::new();
     for result in fs::read_dir(dir)? {
         let entry = result?;
@@ -106,7 +106,7 @@ pub fn read_all_music_packs(dir: &Path) -> binrw::BinResult<Vec<MusicPack>> {
 }
 
 // the file order is not deterministic!
-pub fn read_music_pack(dir: &Path) -> binrw::BinResult<MusicPack> {
+pub fn read_music_pack(dir: &Path) -> brstm::Result<MusicPack> {
     // get all the song paths
     let mut songs = Vec::new();
     read_music_dir_rec(dir, 5, &mut songs)?;
@@ -175,7 +175,7 @@ pub fn read_music_dir_rec(
     dir: &Path,
     max_depth: usize,
     songs: &mut Vec<Rc<CustomMusicInfo>>,
-) -> binrw::BinResult<()> {
+) -> brstm::Result<()> {
     let new_depth = if let Some(new_depth) = max_depth.checked_sub(1) {
         new_depth
     } else {
@@ -191,9 +191,12 @@ pub fn read_music_dir_rec(
                 if path_meta.is_dir() {
                     read_music_dir_rec(&path, new_depth, songs)?;
                 } else if path_meta.is_file() && path.extension().is_some_and(|e| e == "brstm") {
-                    let read_file = || -> binrw::BinResult<_> {
+                    let read_file = || -> brstm::Result<_> {
                         let f = File::open(&path)?;
-                        let mut result = BrstmInformation::from_reader(&mut BufReader::new(f))?;
+                        // like the games, this doesn't care about an inconsistent block layout,
+                        // it's repaired when the song is patched in
+                        let mut result =
+                            BrstmInformation::from_reader_unchecked(&mut BufReader::new(f))?;
                         result.fix_tracks();
                         Ok(result)
                     };
@@ -7,6 +7,7 @@ use std::{
 };
 
 use brstm::{
+    repair::repair,
     reshaper::{calc_reshape, reshape, AdditionalTrackKind},
     BrstmInformation,
 };
@@ -253,7 +254,7 @@ pub fn execute_patches(
     patches: Vec<PatchEntry>,
     vanilla_path: &Path,
     dest_folder: &Path,
-) -> binrw::BinResult<()> {
+) -> brstm::Result<()> {
     for patch in patches {
         // TODO: this hopefully doesn't actually need a heap allocation
         let new_name = patch.custom.name().unwrap_or("<<INVALID>>").to_owned();
@@ -284,13 +285,26 @@ pub fn execute_patches(
                 };
                 // TODO: use unwrap_or_clone once that gets stabilized
                 let custom_info = Rc::try_unwrap(c).unwrap_or_else(|rc| (*rc).clone());
-                match custom_info.brstm_info.into_with_data(&mut f) {
+                let mut song = match custom_info.brstm_info.into_with_data_unchecked(&mut f) {
                     Err(e) => {
                         error!("Error reading song from {}, skipping: {e:?}", &new_name);
                         continue;
                     }
                     Ok(f) => f,
+                };
+                // the layout wasn't checked when loading
+                match repair(&mut song) {
+                    Err(e) => {
+                        error!("Error repairing song {}, skipping: {e}", &new_name);
+                        continue;
+                    }
+                    Ok(repairs) => {
+                        for repair in repairs {
+                            debug!("repaired {}: {repair}", &new_name);
+                        }
+                    }
                 }
+                song
             }
             PatchTarget::Vanilla(v) => {
                 let mut f = File::open(construct_path(vanilla_path, v.name))?;
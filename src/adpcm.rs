@@ -0,0 +1,306 @@
+//! The Nintendo DSP-ADPCM codec, independent of any container.
+//!
+//! A frame is 8 bytes: one header byte followed by 14 signed 4 bit samples, high nibble first.
+//! The header byte (the `ps`) holds the index of the coefficient pair in the upper and the scale
+//! exponent in the lower nibble. Every sample is predicted from the 2 decoded samples before it
+//! with one of 8 coefficient pairs. Callers pass them as the 16 values of a BRSTM channel
+//! information, pair `i` being the values `2 * i` (for the previous sample) and `2 * i + 1` (for
+//! the one before that), see [`crate::structs::AdpcmChannelInformation`].
+
+use std::ops::Range;
+
+use crate::gc_dspadpcm::{
+    dsp_correlate_coefs, dsp_encode_frame, dsp_encode_frame_reference, dsp_encode_frame_search,
+};
+
+pub use crate::gc_dspadpcm::{PACKET_BYTES, PACKET_SAMPLES};
+
+/// how the nibbles of the ADPCM frames are chosen
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Quality {
+    /// like the reference encoder, every sample is rounded on its own
+    #[default]
+    Standard,
+    /// searches for the nibbles with the smallest error of the whole frame, reduces the hiss of
+    /// quiet tracks but is a lot slower
+    High,
+}
+
+/// the last 2 decoded samples before the next one, the state that carries from one frame to the
+/// next. The same values as the ADPC entries and loop history of a BRSTM
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct History {
+    /// the previous sample
+    pub yn1: i16,
+    /// the sample before the previous one
+    pub yn2: i16,
+}
+
+impl History {
+    pub fn new(yn1: i16, yn2: i16) -> Self {
+        Self { yn1, yn2 }
+    }
+
+    fn push(&mut self, sample: i16) {
+        self.yn2 = self.yn1;
+        self.yn1 = sample;
+    }
+}
+
+pub(crate) fn flatten_coefs(coefs: &[[i16; 2]; 8]) -> [i16; 16] {
+    let mut adpcm_coefficients = [0; 16];
+    for (dst, src) in adpcm_coefficients.iter_mut().zip(coefs.iter().flatten()) {
+        *dst = *src;
+    }
+    adpcm_coefficients
+}
+
+pub(crate) fn pair_coefs(coefs: &[i16; 16]) -> [[i16; 2]; 8] {
+    let mut pairs = [[0; 2]; 8];
+    for (pair, src) in pairs.iter_mut().zip(coefs.chunks_exact(2)) {
+        *pair = [src[0], src[1]];
+    }
+    pairs
+}
+
+/// calculates the coefficients for the samples of a channel, like the reference encoder.
+/// [`crate::encoder::CoefficientAnalyzer`] does the same for samples that come in chunks
+pub fn correlate_coefs(samples: &[i16]) -> [i16; 16] {
+    flatten_coefs(&dsp_correlate_coefs(samples))
+}
+
+/// bytes needed to encode that many samples, only the last frame can be partial
+pub fn encoded_len(sample_count: usize) -> usize {
+    sample_count.div_ceil(PACKET_SAMPLES) * PACKET_BYTES
+}
+
+/// encodes up to 14 samples into one frame and advances the history to the decoded samples,
+/// which is what a decoder sees at the start of the next frame. Frames with less than 14
+/// samples are padded with zero nibbles
+pub fn encode_frame(
+    samples: &[i16],
+    coefs: &[i16; 16],
+    history: &mut History,
+    quality: Quality,
+) -> [u8; PACKET_BYTES] {
+    let encode = match quality {
+        Quality::Standard => dsp_encode_frame,
+        Quality::High => dsp_encode_frame_search,
+    };
+    encode_frame_with(samples, coefs, history, encode)
+}
+
+/// the same as [`encode_frame`] with [`Quality::Standard`], but with the floating point math of
+/// the reference encoder, to compare with. It's a lot slower and never finishes for
+/// coefficients beyond ±16384
+pub fn encode_frame_reference(
+    samples: &[i16],
+    coefs: &[i16; 16],
+    history: &mut History,
+) -> [u8; PACKET_BYTES] {
+    encode_frame_with(samples, coefs, history, dsp_encode_frame_reference)
+}
+
+/// the frame encoders of [`crate::gc_dspadpcm`], on the history and samples of a frame
+type FrameEncoder = fn(&mut [i16; 16], usize, &[[i16; 2]; 8]) -> [u8; PACKET_BYTES];
+
+fn encode_frame_with(
+    samples: &[i16],
+    coefs: &[i16; 16],
+    history: &mut History,
+    encode: FrameEncoder,
+) -> [u8; PACKET_BYTES] {
+    assert!(
+        samples.len() <= PACKET_SAMPLES,
+        "a frame has at most 14 samples, got {}",
+        samples.len()
+    );
+    let mut conv_samps = [0i16; 16];
+    conv_samps[0] = history.yn2;
+    conv_samps[1] = history.yn1;
+    conv_samps[2..][..samples.len()].copy_from_slice(samples);
+
+    // replaces the samples with the decoded ones
+    let frame = encode(&mut conv_samps, samples.len(), &pair_coefs(coefs));
+    for sample in &conv_samps[2..][..samples.len()] {
+        history.push(*sample);
+    }
+    frame
+}
+
+/// decodes the first `sample_count` samples of a frame, starting from the history that is then
+/// advanced past them
+pub fn decode_frame(
+    frame: &[u8; PACKET_BYTES],
+    sample_count: usize,
+    coefs: &[i16; 16],
+    history: &mut History,
+    out_buf: &mut Vec<i16>,
+) {
+    assert!(
+        sample_count <= PACKET_SAMPLES,
+        "a frame has at most 14 samples, got {sample_count}"
+    );
+    decode(frame, sample_count as u32, coefs, history, out_buf);
+}
+
+/// encodes the samples frame by frame, continuing from the history. Splitting the samples in
+/// multiples of 14 and encoding them one after another gives the same bytes
+pub fn encode(
+    samples: &[i16],
+    coefs: &[i16; 16],
+    history: &mut History,
+    quality: Quality,
+) -> Vec<u8> {
+    let mut out = Vec::with_capacity(encoded_len(samples.len()));
+    for frame in samples.chunks(PACKET_SAMPLES) {
+        out.extend_from_slice(&encode_frame(frame, coefs, history, quality));
+    }
+    out
+}
+
+/// decodes the first `sample_count` samples of consecutive frames, continuing from the history.
+/// Panics if `data` is shorter than [`encoded_len`] of the sample count
+pub fn decode(
+    data: &[u8],
+    sample_count: u32,
+    coefs: &[i16; 16],
+    history: &mut History,
+    out_buf: &mut Vec<i16>,
+) {
+    let needed = encoded_len(sample_count as usize);
+    assert!(
+        data.len() >= needed,
+        "{sample_count} samples need {needed} bytes, got {}",
+        data.len()
+    );
+    let (_, yn1, yn2) = decode_adpcm_samples(
+        data,
+        0..sample_count,
+        0,
+        history.yn1,
+        history.yn2,
+        coefs,
+        out_buf,
+    );
+    *history = History { yn1, yn2 };
+}
+
+/// decodes the samples in the range of a single ADPCM block, starting with the specified
+/// predictor/scale and history, returns the predictor/scale and history after the last sample.
+/// The predictor/scale is replaced by the header at the start of every frame, so it only
+/// matters when starting in the middle of a frame
+pub(crate) fn decode_adpcm_samples(
+    data: &[u8],
+    samples: Range<u32>,
+    ps: u8,
+    yn1: i16,
+    yn2: i16,
+    coeffs: &[i16; 16],
+    out_buf: &mut Vec<i16>,
+) -> (u8, i16, i16) {
+    // see https://github.com/libertyernie/brawltools/blob/master/BrawlLib/Wii/Audio/ADPCMState.cs
+    let mut cps = ps;
+    let mut cyn1 = yn1;
+    let mut cyn2 = yn2;
+    for sample_idx in samples {
+        // every 8 byte frame has one header byte and 14 samples
+        let frame_offset = (sample_idx / 14) as usize * 8;
+        let sample_in_frame = sample_idx % 14;
+        if sample_in_frame == 0 {
+            cps = data[frame_offset];
+        }
+        let byte = data[frame_offset + 1 + sample_in_frame as usize / 2];
+        let mut out_sample = if sample_in_frame % 2 == 0 {
+            (byte >> 4) as i32
+        } else {
+            (byte & 0xF) as i32
+        };
+        if out_sample >= 8 {
+            out_sample -= 16;
+        }
+        let scale = 1 << (cps & 0xF);
+        let c_index = (cps >> 4) << 1;
+        // extreme coefficients can overflow an i32, which doesn't happen with real encoders
+        let decoded = (0x400
+            + (((scale * out_sample) as i64) << 11)
+            + coeffs[c_index.clamp(0, 15) as usize] as i64 * cyn1 as i64
+            + coeffs[(c_index + 1).clamp(0, 15) as usize] as i64 * cyn2 as i64)
+            >> 11;
+
+        cyn2 = cyn1;
+        cyn1 = decoded.clamp(i16::MIN as i64, i16::MAX as i64) as i16;
+        out_buf.push(cyn1);
+    }
+    (cps, cyn1, cyn2)
+}
+
+#[cfg(test)]
+mod test {
+    use super::{correlate_coefs, decode, decode_frame, encode, encode_frame, History, Quality};
+    use crate::encoder::encode_brstm;
+
+    fn input(len: usize) -> Vec<i16> {
+        (0..len)
+            .map(|i| ((i as f64 * 0.05).sin() * 9000.0 + (i as f64 * 0.31).cos() * 3000.0) as i16)
+            .collect()
+    }
+
+    #[test]
+    pub fn matches_encoder() {
+        // shorter than one block, so the whole channel is one continuous stream of frames
+        let samples = input(1000);
+        let brstm = encode_brstm(std::slice::from_ref(&samples), 32000, None).unwrap();
+        let coefs = correlate_coefs(&samples);
+        assert_eq!(coefs, brstm.info.channels[0].adpcm_coefficients);
+
+        let mut history = History::default();
+        let data = encode(&samples, &coefs, &mut history, Quality::Standard);
+        let (block, _) = brstm.get_data_block_with_samplecount(0, 0);
+        assert_eq!(data, block[..data.len()]);
+
+        // the history is what the decoder ends up with
+        let mut decoded = Vec::new();
+        let mut decode_history = History::default();
+        decode(&data, 1000, &coefs, &mut decode_history, &mut decoded);
+        assert_eq!(decoded, brstm.get_pcm(0));
+        assert_eq!(decode_history, history);
+        assert_eq!(history, History::new(decoded[999], decoded[998]));
+    }
+
+    #[test]
+    pub fn frames_continue() {
+        let samples = input(14 * 20 + 5);
+        let coefs = correlate_coefs(&samples);
+        for quality in [Quality::Standard, Quality::High] {
+            let start = History::new(samples[1], samples[0]);
+            let data = encode(&samples[2..], &coefs, &mut { start }, quality);
+
+            let mut history = start;
+            let mut decoded = Vec::new();
+            for (frame, packet) in data.chunks_exact(8).zip(samples[2..].chunks(14)) {
+                let frame: &[u8; 8] = frame.try_into().unwrap();
+                let mut encode_history = history;
+                assert_eq!(
+                    encode_frame(packet, &coefs, &mut encode_history, quality),
+                    *frame
+                );
+                decode_frame(frame, packet.len(), &coefs, &mut history, &mut decoded);
+                assert_eq!(encode_history, history);
+            }
+            assert_eq!(decoded.len(), samples.len() - 2);
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "29 samples need 24 bytes, got 16")]
+    pub fn decode_too_short() {
+        decode(
+            &[0; 16],
+            29,
+            &[0; 16],
+            &mut History::default(),
+            &mut Vec::new(),
+        );
+    }
+}
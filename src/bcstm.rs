@@ -0,0 +1,993 @@
+use std::io::{Read, Seek, SeekFrom, Write};
+
+use binrw::{binrw, BinReaderExt, BinResult, BinWriterExt, Endian};
+use thiserror::Error;
+
+use crate::{
+    brstm::align_next_32,
+    structs::{AdpcmChannelInformation, Channels, Codec, Head1, TrackDescription, TrackDescriptionV1},
+    BrstmInfoWithData, BrstmInformation,
+};
+
+// note: CSTM files consist of a header with references to an INFO, a SEEK and a DATA block.
+// Everything is little endian, each block is padded to a multiple of 0x20.
+// Offsets inside the INFO block are relative to whatever structure holds the reference.
+
+pub const INFO_BLOCK_ID: u16 = 0x4000;
+pub const SEEK_BLOCK_ID: u16 = 0x4001;
+pub const DATA_BLOCK_ID: u16 = 0x4002;
+pub const BYTE_TABLE_ID: u16 = 0x0100;
+pub const REFERENCE_TABLE_ID: u16 = 0x0101;
+pub const DSP_ADPCM_INFO_ID: u16 = 0x0300;
+pub const SAMPLE_DATA_ID: u16 = 0x1F00;
+pub const STREAM_INFO_ID: u16 = 0x4100;
+pub const TRACK_INFO_ID: u16 = 0x4101;
+pub const CHANNEL_INFO_ID: u16 = 0x4102;
+
+pub const DEFAULT_BCSTM_VERSION: u32 = 0x0202_0000;
+
+pub const CSTM_MAGIC: [u8; 4] = *b"CSTM";
+
+#[binrw]
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Reference {
+    pub type_id: u16,
+    #[brw(pad_before = 2)]
+    pub offset: i32,
+}
+
+impl Reference {
+    pub fn byte_len() -> u32 {
+        8
+    }
+
+    pub fn new(type_id: u16, offset: u32) -> Self {
+        Self {
+            type_id,
+            offset: offset as i32,
+        }
+    }
+
+    /// reference that doesn't point anywhere
+    pub fn null() -> Self {
+        Self {
+            type_id: 0,
+            offset: -1,
+        }
+    }
+
+    pub fn is_null(&self) -> bool {
+        self.offset == -1
+    }
+}
+
+#[binrw]
+#[derive(Debug, Default, Clone, Copy)]
+pub struct SizedReference {
+    pub type_id: u16,
+    #[brw(pad_before = 2)]
+    pub offset: u32,
+    pub size: u32,
+}
+
+impl SizedReference {
+    pub fn byte_len() -> u32 {
+        12
+    }
+}
+
+/// reads the magic, which has to match the expected one
+#[binrw::parser(reader)]
+fn parse_magic(expected: [u8; 4]) -> BinResult<[u8; 4]> {
+    let pos = reader.stream_position()?;
+    let mut found = [0; 4];
+    reader.read_exact(&mut found)?;
+    if found != expected {
+        return Err(binrw::Error::BadMagic {
+            pos,
+            found: Box::new(found),
+        });
+    }
+    Ok(found)
+}
+
+/// file header of CSTM and FSTM, which only differ in the magic and the byte order.
+/// The expected magic is passed in when reading, the byte order with the endian
+#[binrw]
+#[br(import(expected_magic: [u8; 4]))]
+#[br(assert(bom == 0xFEFF))]
+#[derive(Debug, Default, Clone)]
+pub struct CstmHeader {
+    #[br(parse_with = parse_magic, args(expected_magic))]
+    pub magic: [u8; 4],
+    #[br(temp)]
+    #[bw(calc = 0xFEFF)]
+    bom: u16,
+    pub header_length: u16,
+    pub version: u32,
+    pub file_length: u32,
+    #[br(temp)]
+    #[bw(calc = blocks.len() as u16)]
+    block_count: u16,
+    #[brw(pad_before = 2)]
+    #[br(count = block_count)]
+    pub blocks: Vec<SizedReference>,
+}
+
+impl CstmHeader {
+    /// length including the padding up to the first block
+    pub fn byte_len(block_count: u32) -> u32 {
+        align_next_32(0x14 + block_count * SizedReference::byte_len())
+    }
+
+    pub fn find_block(&self, type_id: u16) -> Option<&SizedReference> {
+        self.blocks.iter().find(|block| block.type_id == type_id)
+    }
+}
+
+#[binrw]
+#[brw(magic = b"INFO")]
+#[derive(Debug, Default, Clone)]
+pub struct InfoBlockHeader {
+    pub block_size: u32,
+    pub stream_info: Reference,
+    pub track_info_table: Reference,
+    pub channel_info_table: Reference,
+}
+
+impl InfoBlockHeader {
+    pub fn byte_len() -> u32 {
+        0x20
+    }
+}
+
+#[binrw]
+#[derive(Debug, Default, Clone)]
+pub struct CstmStreamInfo {
+    pub codec: u8,
+    pub loop_flag: u8,
+    pub num_channels: u8,
+    /// only used by FSTM, always 0 for CSTM
+    pub region_count: u8,
+    pub sample_rate: u32,
+    pub loop_start: u32,
+    // also called the loop end
+    pub total_samples: u32,
+    pub total_blocks: u32,
+    pub blocks_size: u32,
+    pub blocks_samples: u32,
+    pub final_block_size: u32,
+    pub final_block_samples: u32,
+    pub final_block_size_padded: u32,
+    pub seek_bytes_per_entry: u32,
+    pub seek_samples_per_entry: u32,
+    // relative to the start of the DATA block + 8
+    pub sample_data: Reference,
+}
+
+impl CstmStreamInfo {
+    pub fn byte_len() -> u32 {
+        0x38
+    }
+}
+
+#[binrw]
+#[derive(Debug, Default, Clone)]
+pub struct ReferenceTable {
+    #[br(temp)]
+    #[bw(calc = entries.len() as u32)]
+    count: u32,
+    #[br(count = count)]
+    pub entries: Vec<Reference>,
+}
+
+impl ReferenceTable {
+    pub fn byte_len(count: u32) -> u32 {
+        4 + count * Reference::byte_len()
+    }
+}
+
+#[binrw]
+#[derive(Debug, Default, Clone)]
+pub struct ByteTable {
+    #[br(temp)]
+    #[bw(calc = entries.len() as u32)]
+    count: u32,
+    #[br(count = count)]
+    #[brw(align_after = 4)]
+    pub entries: Vec<u8>,
+}
+
+impl ByteTable {
+    pub fn byte_len(count: u32) -> u32 {
+        4 + ((count + 3) & !3)
+    }
+}
+
+#[binrw]
+#[derive(Debug, Default, Clone)]
+pub struct TrackInfo {
+    pub volume: u8,
+    pub pan: u8,
+    // relative to the start of the track info
+    #[brw(pad_before = 2)]
+    pub channel_index_table: Reference,
+}
+
+impl TrackInfo {
+    pub fn byte_len() -> u32 {
+        12
+    }
+}
+
+#[binrw]
+#[derive(Debug, Default, Clone)]
+pub struct DspAdpcmInfo {
+    pub adpcm_coefficients: [i16; 16],
+    pub predictor_scale: u16,
+    pub history_sample1: i16,
+    pub history_sample2: i16,
+    pub loop_predictor_scale: u16,
+    pub loop_history_sample1: i16,
+    #[brw(pad_after = 2)]
+    pub loop_history_sample2: i16,
+}
+
+impl DspAdpcmInfo {
+    pub fn byte_len() -> u32 {
+        0x2E
+    }
+}
+
+impl From<&AdpcmChannelInformation> for DspAdpcmInfo {
+    fn from(channel: &AdpcmChannelInformation) -> Self {
+        Self {
+            adpcm_coefficients: channel.adpcm_coefficients,
+            predictor_scale: channel.initial_predictor as u16,
+            history_sample1: channel.history_sample1,
+            history_sample2: channel.history_sample2,
+            loop_predictor_scale: channel.loop_predictor as u16,
+            loop_history_sample1: channel.loop_history_sample1,
+            loop_history_sample2: channel.loop_history_sample2,
+        }
+    }
+}
+
+impl From<&DspAdpcmInfo> for AdpcmChannelInformation {
+    fn from(info: &DspAdpcmInfo) -> Self {
+        Self {
+            adpcm_coefficients: info.adpcm_coefficients,
+            initial_predictor: info.predictor_scale as i16,
+            history_sample1: info.history_sample1,
+            history_sample2: info.history_sample2,
+            loop_predictor: info.loop_predictor_scale as i16,
+            loop_history_sample1: info.loop_history_sample1,
+            loop_history_sample2: info.loop_history_sample2,
+            ..Default::default()
+        }
+    }
+}
+
+#[binrw]
+#[brw(magic = b"SEEK")]
+#[derive(Debug, Default, Clone)]
+pub struct SeekBlockHeader {
+    pub block_size: u32,
+}
+
+#[binrw]
+#[brw(magic = b"DATA")]
+#[derive(Debug, Default, Clone)]
+pub struct DataBlockHeader {
+    pub block_size: u32,
+}
+
+#[derive(Debug, Clone)]
+pub struct CstmTrack {
+    pub volume: u8,
+    pub pan: u8,
+    pub channels: Vec<u8>,
+}
+
+impl Default for CstmTrack {
+    fn default() -> Self {
+        Self {
+            volume: 0x7F,
+            pan: 64,
+            channels: Vec::new(),
+        }
+    }
+}
+
+#[derive(Error, Debug)]
+#[non_exhaustive]
+pub enum ConversionError {
+    #[error("Only DSP-ADPCM streams can be converted, got codec {0}")]
+    UnsupportedCodec(u8),
+    #[error("Sample rate {0} is too high for a BRSTM")]
+    SampleRateTooHigh(u32),
+    #[error("Track {track} has {count} channels, only mono and stereo tracks are supported")]
+    UnsupportedTrackChannels { track: usize, count: usize },
+    #[error("Seek entries with {0} bytes are not supported")]
+    UnsupportedSeekEntrySize(u32),
+}
+
+fn invalid_layout(pos: u64, message: String) -> crate::Error {
+    crate::Error::InvalidLayout { pos, message }
+}
+
+/// resolves a reference relative to `base`, making sure it points to the expected type
+pub(crate) fn resolve_reference(
+    base: u32,
+    reference: &Reference,
+    expected_type: u16,
+) -> Result<u64, crate::Error> {
+    if reference.is_null() || reference.offset < 0 {
+        return Err(invalid_layout(
+            base.into(),
+            format!("Expected reference to {expected_type:#06X}, but it's null"),
+        ));
+    }
+    if reference.type_id != expected_type {
+        return Err(invalid_layout(
+            base.into(),
+            format!(
+                "Expected reference to {expected_type:#06X}, got {:#06X}",
+                reference.type_id
+            ),
+        ));
+    }
+    Ok(u64::from(base) + reference.offset as u64)
+}
+
+/// reads the track and channel information out of the INFO block, the stream info
+/// itself is left to the caller since it differs between formats
+pub(crate) fn read_tracks_and_channels<RS: Read + Seek>(
+    f: &mut RS,
+    endian: Endian,
+    info_base: u32,
+    info_header: &InfoBlockHeader,
+) -> Result<(Vec<CstmTrack>, Vec<DspAdpcmInfo>), crate::Error> {
+    let mut tracks = Vec::new();
+    // the track table is optional
+    if !info_header.track_info_table.is_null() {
+        let table_off =
+            resolve_reference(info_base, &info_header.track_info_table, REFERENCE_TABLE_ID)?;
+        f.seek(SeekFrom::Start(table_off))?;
+        let table: ReferenceTable = f.read_type(endian)?;
+        for track_ref in table.entries.iter() {
+            let track_off = resolve_reference(table_off as u32, track_ref, TRACK_INFO_ID)?;
+            f.seek(SeekFrom::Start(track_off))?;
+            let track: TrackInfo = f.read_type(endian)?;
+            let byte_table_off =
+                resolve_reference(track_off as u32, &track.channel_index_table, BYTE_TABLE_ID)?;
+            f.seek(SeekFrom::Start(byte_table_off))?;
+            let channel_indices: ByteTable = f.read_type(endian)?;
+            tracks.push(CstmTrack {
+                volume: track.volume,
+                pan: track.pan,
+                channels: channel_indices.entries,
+            });
+        }
+    }
+
+    let table_off = resolve_reference(
+        info_base,
+        &info_header.channel_info_table,
+        REFERENCE_TABLE_ID,
+    )?;
+    f.seek(SeekFrom::Start(table_off))?;
+    let table: ReferenceTable = f.read_type(endian)?;
+    let mut channels = Vec::with_capacity(table.entries.len());
+    for channel_ref in table.entries.iter() {
+        let channel_off = resolve_reference(table_off as u32, channel_ref, CHANNEL_INFO_ID)?;
+        f.seek(SeekFrom::Start(channel_off))?;
+        let codec_info: Reference = f.read_type(endian)?;
+        // PCM streams don't have any codec specific info
+        if codec_info.is_null() {
+            channels.push(DspAdpcmInfo::default());
+        } else {
+            let info_off = resolve_reference(channel_off as u32, &codec_info, DSP_ADPCM_INFO_ID)?;
+            f.seek(SeekFrom::Start(info_off))?;
+            channels.push(f.read_type(endian)?);
+        }
+    }
+    Ok((tracks, channels))
+}
+
+/// size of the track table, track infos, channel table, channel infos and DSP-ADPCM infos,
+/// which only ADPCM streams have
+pub(crate) fn tracks_and_channels_byte_len(
+    tracks: &[CstmTrack],
+    channel_count: u32,
+    codec: u8,
+) -> u32 {
+    let track_table_len = if tracks.is_empty() {
+        0
+    } else {
+        ReferenceTable::byte_len(tracks.len() as u32)
+    };
+    let track_infos_len: u32 = tracks
+        .iter()
+        .map(|track| TrackInfo::byte_len() + ByteTable::byte_len(track.channels.len() as u32))
+        .sum();
+    let dsp_info_len = if codec == Codec::Adpcm as u8 {
+        DspAdpcmInfo::byte_len()
+    } else {
+        0
+    };
+    track_table_len
+        + track_infos_len
+        + ReferenceTable::byte_len(channel_count)
+        + channel_count * (Reference::byte_len() + dsp_info_len)
+}
+
+/// writes everything after the stream info, `tables_off` is relative to `info_base`
+/// returns the references to the track and channel table.
+/// The channel infos of PCM streams are null references without a DSP-ADPCM info
+pub(crate) fn write_tracks_and_channels<WS: Write + Seek>(
+    ws: &mut WS,
+    endian: Endian,
+    tables_off: u32,
+    tracks: &[CstmTrack],
+    channels: &[DspAdpcmInfo],
+    codec: u8,
+) -> BinResult<(Reference, Reference)> {
+    let is_adpcm = codec == Codec::Adpcm as u8;
+    let channel_count = channels.len() as u32;
+    let (track_table_ref, channel_table_off) = if tracks.is_empty() {
+        (Reference::null(), tables_off)
+    } else {
+        (
+            Reference::new(REFERENCE_TABLE_ID, tables_off),
+            tables_off + ReferenceTable::byte_len(tracks.len() as u32),
+        )
+    };
+    let track_infos_off = channel_table_off + ReferenceTable::byte_len(channel_count);
+
+    // tracks first, each track info is directly followed by its channel index table
+    let mut track_table = ReferenceTable::default();
+    let mut cur_off = track_infos_off;
+    for track in tracks.iter() {
+        track_table
+            .entries
+            .push(Reference::new(TRACK_INFO_ID, cur_off - tables_off));
+        cur_off += TrackInfo::byte_len() + ByteTable::byte_len(track.channels.len() as u32);
+    }
+    let channel_infos_off = cur_off;
+    let dsp_infos_off = channel_infos_off + channel_count * Reference::byte_len();
+    let channel_table = ReferenceTable {
+        entries: (0..channel_count)
+            .map(|i| {
+                Reference::new(
+                    CHANNEL_INFO_ID,
+                    channel_infos_off + i * Reference::byte_len() - channel_table_off,
+                )
+            })
+            .collect(),
+    };
+
+    if !tracks.is_empty() {
+        ws.write_type(&track_table, endian)?;
+    }
+    ws.write_type(&channel_table, endian)?;
+    for track in tracks.iter() {
+        ws.write_type(
+            &TrackInfo {
+                volume: track.volume,
+                pan: track.pan,
+                channel_index_table: Reference::new(BYTE_TABLE_ID, TrackInfo::byte_len()),
+            },
+            endian,
+        )?;
+        ws.write_type(
+            &ByteTable {
+                entries: track.channels.clone(),
+            },
+            endian,
+        )?;
+    }
+    for i in 0..channel_count {
+        let channel_info_off = channel_infos_off + i * Reference::byte_len();
+        let dsp_info_off = dsp_infos_off + i * DspAdpcmInfo::byte_len();
+        let codec_info = if is_adpcm {
+            Reference::new(DSP_ADPCM_INFO_ID, dsp_info_off - channel_info_off)
+        } else {
+            Reference::null()
+        };
+        ws.write_type(&codec_info, endian)?;
+    }
+    if is_adpcm {
+        for channel in channels.iter() {
+            ws.write_type(channel, endian)?;
+        }
+    }
+    Ok((
+        track_table_ref,
+        Reference::new(REFERENCE_TABLE_ID, channel_table_off),
+    ))
+}
+
+/// writes zeros until the stream is at `pos`
+pub(crate) fn pad_to<WS: Write + Seek>(ws: &mut WS, pos: u32) -> BinResult<()> {
+    let cur = ws.stream_position()?;
+    if cur < pos.into() {
+        ws.write_all(&vec![0; (u64::from(pos) - cur) as usize])?;
+    }
+    Ok(())
+}
+
+/// swaps the byte order of every 16 bit value, ignores a trailing odd byte
+pub(crate) fn swap_i16_bytes(bytes: &[u8]) -> Vec<u8> {
+    bytes
+        .chunks_exact(2)
+        .flat_map(|pair| [pair[1], pair[0]])
+        .collect()
+}
+
+pub(crate) fn tracks_from_brstm(brstm: &BrstmInformation) -> Vec<CstmTrack> {
+    brstm
+        .tracks
+        .iter()
+        .map(|track| {
+            let info_v1 = track.info_v1.clone().unwrap_or_default();
+            let channels = match track.channels {
+                Channels::Mono(c) => vec![c],
+                Channels::Stereo(l, r) => vec![l, r],
+            };
+            CstmTrack {
+                volume: info_v1.track_volume,
+                pan: info_v1.track_panning,
+                channels,
+            }
+        })
+        .collect()
+}
+
+pub(crate) fn tracks_to_brstm(
+    tracks: &[CstmTrack],
+) -> Result<Vec<TrackDescription>, ConversionError> {
+    tracks
+        .iter()
+        .enumerate()
+        .map(|(idx, track)| {
+            let channels = match track.channels.as_slice() {
+                [c] => Channels::Mono(*c),
+                [l, r] => Channels::Stereo(*l, *r),
+                other => {
+                    return Err(ConversionError::UnsupportedTrackChannels {
+                        track: idx,
+                        count: other.len(),
+                    })
+                }
+            };
+            Ok(TrackDescription {
+                info_v1: Some(TrackDescriptionV1 {
+                    track_volume: track.volume,
+                    track_panning: track.pan,
+                }),
+                channels,
+            })
+        })
+        .collect()
+}
+
+/// number of bytes of the seek table, without the padding of the SEEK block
+pub(crate) fn seek_table_len(info: &CstmStreamInfo, channel_count: usize) -> usize {
+    info.total_blocks as usize * channel_count * 4
+}
+
+/// converts the HEAD1 of a BRSTM into the stream info, which is the same for CSTM and FSTM.
+/// Returns it with the seek table, which is big endian like the ADPC section
+pub(crate) fn stream_from_brstm(
+    brstm: &BrstmInfoWithData,
+) -> Result<(CstmStreamInfo, &[u8]), ConversionError> {
+    let head1 = &brstm.info.info;
+    if head1.codec != Codec::Adpcm {
+        return Err(ConversionError::UnsupportedCodec(head1.codec as u8));
+    }
+    let channel_count = brstm.info.channels.len();
+    let info = CstmStreamInfo {
+        codec: head1.codec as u8,
+        loop_flag: head1.loop_flag,
+        num_channels: channel_count as u8,
+        region_count: 0,
+        sample_rate: head1.sample_rate.into(),
+        loop_start: head1.loop_start,
+        total_samples: head1.total_samples,
+        total_blocks: head1.total_blocks,
+        blocks_size: head1.blocks_size,
+        blocks_samples: head1.blocks_samples,
+        final_block_size: head1.final_block_size,
+        final_block_samples: head1.final_block_samples,
+        final_block_size_padded: head1.final_block_size_padded,
+        seek_bytes_per_entry: head1.adpc_bytes_per_entry,
+        seek_samples_per_entry: head1.adpc_samples_per_entry,
+        // filled in later
+        sample_data: Reference::default(),
+    };
+    let seek_len = seek_table_len(&info, channel_count).min(brstm.adpcm_bytes.len());
+    Ok((info, &brstm.adpcm_bytes[..seek_len]))
+}
+
+/// builds a BRSTM out of a CSTM or FSTM stream, `seek_bytes` have to be big endian
+pub(crate) fn stream_to_brstm(
+    info: &CstmStreamInfo,
+    tracks: &[CstmTrack],
+    channels: &[DspAdpcmInfo],
+    seek_bytes: &[u8],
+    data_bytes: &[u8],
+) -> Result<BrstmInfoWithData, ConversionError> {
+    if info.codec != Codec::Adpcm as u8 {
+        return Err(ConversionError::UnsupportedCodec(info.codec));
+    }
+    if info.seek_bytes_per_entry != 4 {
+        return Err(ConversionError::UnsupportedSeekEntrySize(
+            info.seek_bytes_per_entry,
+        ));
+    }
+    let sample_rate = info
+        .sample_rate
+        .try_into()
+        .map_err(|_| ConversionError::SampleRateTooHigh(info.sample_rate))?;
+    let seek_len = seek_table_len(info, channels.len()).min(seek_bytes.len());
+    let head1 = Head1 {
+        codec: Codec::Adpcm,
+        loop_flag: info.loop_flag,
+        num_channels: channels.len() as u8,
+        sample_rate,
+        loop_start: info.loop_start,
+        total_samples: info.total_samples,
+        total_blocks: info.total_blocks,
+        blocks_size: info.blocks_size,
+        blocks_samples: info.blocks_samples,
+        final_block_size: info.final_block_size,
+        final_block_samples: info.final_block_samples,
+        final_block_size_padded: info.final_block_size_padded,
+        adpc_samples_per_entry: info.seek_samples_per_entry,
+        adpc_bytes_per_entry: 4,
+        // filled in later
+        audio_offset: 0,
+    };
+    let mut brstm_info = BrstmInformation {
+        info: head1,
+        tracks: tracks_to_brstm(tracks)?,
+        channels: channels.iter().map(AdpcmChannelInformation::from).collect(),
+        // filled in later
+        adpcm_offset: 0,
+        adpcm_size: 0,
+        data_offset: 0,
+        data_size: 0,
+        original: None,
+    };
+    // the track table is optional, without it guess stereo or mono tracks
+    brstm_info.fix_tracks();
+    Ok(BrstmInfoWithData {
+        info: brstm_info,
+        adpcm_bytes: seek_bytes[..seek_len].to_vec(),
+        data_bytes: data_bytes.to_vec(),
+    })
+}
+
+#[derive(Debug, Clone)]
+pub struct BcstmInformation {
+    pub version: u32,
+    pub info: CstmStreamInfo,
+    pub tracks: Vec<CstmTrack>,
+    pub channels: Vec<DspAdpcmInfo>,
+    // does not include the block header
+    pub(crate) seek_offset: u32,
+    pub(crate) seek_size: u32,
+    pub(crate) data_offset: u32,
+    pub(crate) data_size: u32,
+}
+
+impl BcstmInformation {
+    pub fn from_reader<RS: Read + Seek>(f: &mut RS) -> Result<Self, crate::Error> {
+        let header: CstmHeader = f.read_le_args((CSTM_MAGIC,))?;
+        let find_block = |type_id: u16, name: &str| {
+            header
+                .find_block(type_id)
+                .copied()
+                .ok_or_else(|| invalid_layout(0, format!("Missing {name} block")))
+        };
+        let info_block = find_block(INFO_BLOCK_ID, "INFO")?;
+        let seek_block = find_block(SEEK_BLOCK_ID, "SEEK")?;
+        let data_block = find_block(DATA_BLOCK_ID, "DATA")?;
+
+        f.seek(SeekFrom::Start(info_block.offset.into()))?;
+        let info_header: InfoBlockHeader = f.read_le()?;
+        // everything in the INFO block is relative to the start of the block + 8
+        let info_base = info_block.offset + 8;
+        let stream_info_off =
+            resolve_reference(info_base, &info_header.stream_info, STREAM_INFO_ID)?;
+        f.seek(SeekFrom::Start(stream_info_off))?;
+        let info: CstmStreamInfo = f.read_le()?;
+        let (tracks, channels) =
+            read_tracks_and_channels(f, Endian::Little, info_base, &info_header)?;
+
+        f.seek(SeekFrom::Start(seek_block.offset.into()))?;
+        let seek_header: SeekBlockHeader = f.read_le()?;
+        f.seek(SeekFrom::Start(data_block.offset.into()))?;
+        let data_header: DataBlockHeader = f.read_le()?;
+        let data_start = resolve_reference(0, &info.sample_data, SAMPLE_DATA_ID)? as u32 + 8;
+
+        Ok(BcstmInformation {
+            version: header.version,
+            info,
+            tracks,
+            channels,
+            seek_offset: seek_block.offset + 8,
+            seek_size: seek_header.block_size.saturating_sub(8),
+            data_offset: data_block.offset + data_start,
+            data_size: data_header.block_size.saturating_sub(data_start),
+        })
+    }
+
+    pub fn into_with_data<RS: Read + Seek>(self, f: &mut RS) -> std::io::Result<BcstmInfoWithData> {
+        // the SEEK block is padded, only keep the actual entries
+        let seek_len = self.seek_table_len().min(self.seek_size as usize);
+        let mut seek_bytes = vec![0; seek_len];
+        f.seek(SeekFrom::Start(self.seek_offset.into()))?;
+        f.read_exact(&mut seek_bytes)?;
+        let mut data_bytes = vec![0; self.data_size as usize];
+        f.seek(SeekFrom::Start(self.data_offset.into()))?;
+        f.read_exact(&mut data_bytes)?;
+        Ok(BcstmInfoWithData {
+            info: self,
+            seek_bytes,
+            data_bytes,
+        })
+    }
+
+    fn seek_table_len(&self) -> usize {
+        seek_table_len(&self.info, self.channels.len())
+    }
+}
+
+pub struct BcstmInfoWithData {
+    pub info: BcstmInformation,
+    /// history samples for each block and channel, little endian
+    pub seek_bytes: Vec<u8>,
+    pub data_bytes: Vec<u8>,
+}
+
+impl BcstmInfoWithData {
+    pub fn write_bcstm<WS: Write + Seek>(&self, ws: &mut WS) -> Result<(), crate::Error> {
+        let endian = Endian::Little;
+        let channel_count = self.info.channels.len() as u32;
+        let seek_len = (self.seek_bytes.len() as u32).min(self.info.seek_table_len() as u32);
+
+        // first, calculate all offsets
+        let info_off = CstmHeader::byte_len(3);
+        // relative to the INFO block + 8
+        let stream_info_off = InfoBlockHeader::byte_len() - 8;
+        let tables_off = stream_info_off + CstmStreamInfo::byte_len();
+        let info_size = align_next_32(
+            8 + tables_off
+                + tracks_and_channels_byte_len(
+                    &self.info.tracks,
+                    channel_count,
+                    self.info.info.codec,
+                ),
+        );
+        let seek_off = info_off + info_size;
+        let seek_size = align_next_32(8 + seek_len);
+        let data_off = seek_off + seek_size;
+        let data_size = align_next_32(0x20 + self.data_bytes.len() as u32);
+        let file_length = data_off + data_size;
+
+        let blocks = vec![
+            SizedReference {
+                type_id: INFO_BLOCK_ID,
+                offset: info_off,
+                size: info_size,
+            },
+            SizedReference {
+                type_id: SEEK_BLOCK_ID,
+                offset: seek_off,
+                size: seek_size,
+            },
+            SizedReference {
+                type_id: DATA_BLOCK_ID,
+                offset: data_off,
+                size: data_size,
+            },
+        ];
+        ws.seek(SeekFrom::Start(0))?;
+        ws.write_type(
+            &CstmHeader {
+                magic: CSTM_MAGIC,
+                header_length: info_off as u16,
+                version: self.info.version,
+                file_length,
+                blocks,
+            },
+            endian,
+        )?;
+        pad_to(ws, info_off)?;
+
+        // the header needs the table references, so write the tables first
+        ws.seek(SeekFrom::Start((info_off + 8 + tables_off).into()))?;
+        let (track_info_table, channel_info_table) = write_tracks_and_channels(
+            ws,
+            endian,
+            tables_off,
+            &self.info.tracks,
+            &self.info.channels,
+            self.info.info.codec,
+        )?;
+        pad_to(ws, seek_off)?;
+
+        ws.seek(SeekFrom::Start(info_off.into()))?;
+        ws.write_type(
+            &InfoBlockHeader {
+                block_size: info_size,
+                stream_info: Reference::new(STREAM_INFO_ID, stream_info_off),
+                track_info_table,
+                channel_info_table,
+            },
+            endian,
+        )?;
+        ws.write_type(
+            &CstmStreamInfo {
+                num_channels: channel_count as u8,
+                region_count: 0,
+                seek_bytes_per_entry: 4,
+                // data starts 0x20 into the DATA block
+                sample_data: Reference::new(SAMPLE_DATA_ID, 0x18),
+                ..self.info.info.clone()
+            },
+            endian,
+        )?;
+
+        ws.seek(SeekFrom::Start(seek_off.into()))?;
+        ws.write_type(
+            &SeekBlockHeader {
+                block_size: seek_size,
+            },
+            endian,
+        )?;
+        ws.write_all(&self.seek_bytes[..seek_len as usize])?;
+        pad_to(ws, data_off)?;
+
+        ws.write_type(
+            &DataBlockHeader {
+                block_size: data_size,
+            },
+            endian,
+        )?;
+        pad_to(ws, data_off + 0x20)?;
+        ws.write_all(&self.data_bytes)?;
+        pad_to(ws, file_length)?;
+        ws.flush()?;
+        Ok(())
+    }
+
+    /// converts a BRSTM into a BCSTM, the DSP-ADPCM data is reused as is
+    pub fn from_brstm(brstm: &BrstmInfoWithData) -> Result<Self, ConversionError> {
+        let (info, seek_bytes) = stream_from_brstm(brstm)?;
+        Ok(BcstmInfoWithData {
+            info: BcstmInformation {
+                version: DEFAULT_BCSTM_VERSION,
+                info,
+                tracks: tracks_from_brstm(&brstm.info),
+                channels: brstm.info.channels.iter().map(DspAdpcmInfo::from).collect(),
+                // filled in later
+                seek_offset: 0,
+                seek_size: 0,
+                data_offset: 0,
+                data_size: 0,
+            },
+            seek_bytes: swap_i16_bytes(seek_bytes),
+            data_bytes: brstm.data_bytes.clone(),
+        })
+    }
+
+    /// converts this BCSTM into a BRSTM, the DSP-ADPCM data is reused as is
+    pub fn to_brstm(&self) -> Result<BrstmInfoWithData, ConversionError> {
+        stream_to_brstm(
+            &self.info.info,
+            &self.info.tracks,
+            &self.info.channels,
+            &swap_i16_bytes(&self.seek_bytes),
+            &self.data_bytes,
+        )
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::Cursor;
+
+    use crate::{
+        encoder::encode_brstm,
+        structs::{Channels, Codec},
+        BrstmInformation,
+    };
+
+    use super::{BcstmInfoWithData, BcstmInformation};
+
+    #[test]
+    pub fn brstm_bcstm_roundtrip() {
+        let channels: Vec<Vec<i16>> = (0..2)
+            .map(|c| {
+                // 4 blocks, so the SEEK block is padded
+                (0..50_000)
+                    .map(|i| ((i as f64 * 0.01 * (c + 1) as f64).sin() * 10_000.0) as i16)
+                    .collect()
+            })
+            .collect();
+        let brstm = encode_brstm(&channels, 32000, Some(1234)).unwrap();
+
+        let mut bcstm_buf = Vec::new();
+        BcstmInfoWithData::from_brstm(&brstm)
+            .unwrap()
+            .write_bcstm(&mut Cursor::new(&mut bcstm_buf))
+            .unwrap();
+        assert_eq!(&bcstm_buf[..4], b"CSTM");
+
+        let mut cursor = Cursor::new(&bcstm_buf);
+        let bcstm = BcstmInformation::from_reader(&mut cursor)
+            .unwrap()
+            .into_with_data(&mut cursor)
+            .unwrap();
+        assert_eq!(bcstm.info.info.loop_start, 1234);
+        assert_eq!(bcstm.info.tracks.len(), 1);
+        assert_eq!(bcstm.info.tracks[0].channels, vec![0, 1]);
+        assert_eq!(bcstm.seek_bytes.len(), 4 * 2 * 4);
+
+        let converted = bcstm.to_brstm().unwrap();
+        assert_eq!(brstm.adpcm_bytes, converted.adpcm_bytes);
+        let mut brstm_buf = Vec::new();
+        converted
+            .write_brstm(&mut Cursor::new(&mut brstm_buf))
+            .unwrap();
+        let mut cursor = Cursor::new(&brstm_buf);
+        let reread = BrstmInformation::from_reader(&mut cursor)
+            .unwrap()
+            .into_with_data(&mut cursor)
+            .unwrap();
+        for channel in 0..2 {
+            assert_eq!(brstm.get_pcm(channel), reread.get_pcm(channel));
+            assert_eq!(
+                brstm.info.channels[channel as usize].adpcm_coefficients,
+                reread.info.channels[channel as usize].adpcm_coefficients
+            );
+        }
+        assert_eq!(brstm.data_bytes, reread.data_bytes);
+    }
+
+    #[test]
+    pub fn pcm_channels_and_missing_track_table() {
+        let channels = vec![vec![100i16; 5000]; 2];
+        let brstm = encode_brstm(&channels, 32000, None).unwrap();
+        let bcstm = || BcstmInfoWithData::from_brstm(&brstm).unwrap();
+        let reread = |bcstm: &BcstmInfoWithData| {
+            let mut buf = Vec::new();
+            bcstm.write_bcstm(&mut Cursor::new(&mut buf)).unwrap();
+            let mut cursor = Cursor::new(&buf);
+            BcstmInformation::from_reader(&mut cursor)
+                .unwrap()
+                .into_with_data(&mut cursor)
+                .unwrap()
+        };
+
+        // PCM channels have no DSP-ADPCM info, the reader only skips it for null references
+        let mut pcm = bcstm();
+        pcm.info.info.codec = Codec::Pcm16 as u8;
+        let reread_pcm = reread(&pcm);
+        assert_eq!(reread_pcm.info.channels.len(), 2);
+        for channel in reread_pcm.info.channels.iter() {
+            assert_eq!(channel.adpcm_coefficients, [0; 16]);
+        }
+
+        let mut no_tracks = bcstm();
+        no_tracks.info.tracks.clear();
+        let reread_no_tracks = reread(&no_tracks);
+        assert!(reread_no_tracks.info.tracks.is_empty());
+        let converted = reread_no_tracks.to_brstm().unwrap();
+        assert_eq!(converted.info.tracks.len(), 1);
+        assert_eq!(converted.info.tracks[0].channels, Channels::Stereo(0, 1));
+    }
+}
@@ -0,0 +1,507 @@
+use std::io::{Read, Seek, SeekFrom, Write};
+
+use binrw::{binrw, BinReaderExt, BinWriterExt, Endian};
+
+use crate::{
+    bcstm::{
+        pad_to, read_tracks_and_channels, resolve_reference, seek_table_len, stream_from_brstm,
+        stream_to_brstm, swap_i16_bytes, tracks_and_channels_byte_len, tracks_from_brstm,
+        write_tracks_and_channels, ConversionError, CstmHeader, CstmStreamInfo, CstmTrack,
+        DataBlockHeader, DspAdpcmInfo, InfoBlockHeader, Reference, SeekBlockHeader, SizedReference,
+        DATA_BLOCK_ID, INFO_BLOCK_ID, SAMPLE_DATA_ID, SEEK_BLOCK_ID, STREAM_INFO_ID,
+    },
+    brstm::align_next_32,
+    BrstmInfoWithData, Error,
+};
+
+// note: FSTM has the same layout as CSTM, but is big endian on the Wii U and little endian on
+// the Switch. Newer versions add a REGN block and extend the stream info.
+
+pub const REGION_BLOCK_ID: u16 = 0x4003;
+
+/// Wii U version without any region info
+pub const DEFAULT_BFSTM_VERSION: u32 = 0x0003_0000;
+/// starting with this version, the stream info contains region info and the unaligned loop points
+pub const REGION_INFO_VERSION: u32 = 0x0004_0000;
+/// starting with this version, the stream info ends with a checksum
+pub const CHECKSUM_VERSION: u32 = 0x0005_0000;
+
+pub const REGION_ENTRY_SIZE: u16 = 0x100;
+
+pub const FSTM_MAGIC: [u8; 4] = *b"FSTM";
+
+#[binrw]
+#[derive(Debug, Default, Clone)]
+pub struct FstmRegionInfo {
+    pub entry_size: u16,
+    // relative to the start of the REGN block + 8
+    #[brw(pad_before = 2)]
+    pub region_data: Reference,
+}
+
+#[binrw]
+#[derive(Debug, Default, Clone)]
+pub struct UnalignedLoop {
+    pub loop_start: u32,
+    pub loop_end: u32,
+}
+
+#[binrw]
+#[br(import(version: u32))]
+#[bw(import(version: u32))]
+#[derive(Debug, Default, Clone)]
+pub struct FstmStreamInfo {
+    /// the same fields as in a CSTM, including the region count
+    pub base: CstmStreamInfo,
+    #[brw(if(version >= REGION_INFO_VERSION))]
+    pub region_info: Option<FstmRegionInfo>,
+    #[brw(if(version >= REGION_INFO_VERSION))]
+    pub unaligned_loop: Option<UnalignedLoop>,
+    #[brw(if(version >= CHECKSUM_VERSION))]
+    pub checksum: Option<u32>,
+}
+
+impl FstmStreamInfo {
+    pub fn byte_len(version: u32) -> u32 {
+        let mut len = CstmStreamInfo::byte_len();
+        if version >= REGION_INFO_VERSION {
+            len += 0x14;
+        }
+        if version >= CHECKSUM_VERSION {
+            len += 4;
+        }
+        len
+    }
+}
+
+#[binrw]
+#[derive(Debug, Default, Clone, Copy)]
+pub struct AdpcmContext {
+    pub predictor_scale: u16,
+    pub history_sample1: i16,
+    pub history_sample2: i16,
+}
+
+/// a region of the stream, each channel stores the ADPCM context at the region start
+#[binrw]
+#[derive(Debug, Default, Clone)]
+pub struct FstmRegion {
+    pub start_sample: u32,
+    pub end_sample: u32,
+    // pad to the full entry size
+    #[brw(pad_after = 0x98)]
+    pub adpcm_contexts: [AdpcmContext; 16],
+}
+
+#[binrw]
+#[brw(magic = b"REGN")]
+#[derive(Debug, Default, Clone)]
+pub struct RegionBlockHeader {
+    pub block_size: u32,
+}
+
+fn invalid_layout(pos: u64, message: String) -> Error {
+    Error::InvalidLayout { pos, message }
+}
+
+#[derive(Debug, Clone)]
+pub struct BfstmInformation {
+    /// byte order of the file, big endian for the Wii U, little endian for the Switch
+    pub endian: Endian,
+    /// determines which optional fields are present, see [`REGION_INFO_VERSION`] and [`CHECKSUM_VERSION`]
+    pub version: u32,
+    pub info: FstmStreamInfo,
+    pub tracks: Vec<CstmTrack>,
+    pub channels: Vec<DspAdpcmInfo>,
+    /// only written for versions that support region info
+    pub regions: Vec<FstmRegion>,
+    // does not include the block header
+    pub(crate) seek_offset: u32,
+    pub(crate) seek_size: u32,
+    pub(crate) data_offset: u32,
+    pub(crate) data_size: u32,
+}
+
+impl BfstmInformation {
+    pub fn from_reader<RS: Read + Seek>(f: &mut RS) -> Result<Self, Error> {
+        // the byte order mark decides about the endian of everything else
+        let mut magic_bom = [0; 6];
+        f.read_exact(&mut magic_bom)?;
+        let endian = match magic_bom[4..] {
+            [0xFE, 0xFF] => Endian::Big,
+            [0xFF, 0xFE] => Endian::Little,
+            _ => {
+                return Err(invalid_layout(
+                    4,
+                    format!("Invalid byte order mark {:02X?}", &magic_bom[4..]),
+                ))
+            }
+        };
+        f.seek(SeekFrom::Start(0))?;
+        let header: CstmHeader = f.read_type_args(endian, (FSTM_MAGIC,))?;
+        let find_block = |type_id: u16, name: &str| {
+            header
+                .find_block(type_id)
+                .copied()
+                .ok_or_else(|| invalid_layout(0, format!("Missing {name} block")))
+        };
+        let info_block = find_block(INFO_BLOCK_ID, "INFO")?;
+        let seek_block = find_block(SEEK_BLOCK_ID, "SEEK")?;
+        let data_block = find_block(DATA_BLOCK_ID, "DATA")?;
+
+        f.seek(SeekFrom::Start(info_block.offset.into()))?;
+        let info_header: InfoBlockHeader = f.read_type(endian)?;
+        // everything in the INFO block is relative to the start of the block + 8
+        let info_base = info_block.offset + 8;
+        let stream_info_off =
+            resolve_reference(info_base, &info_header.stream_info, STREAM_INFO_ID)?;
+        f.seek(SeekFrom::Start(stream_info_off))?;
+        let info: FstmStreamInfo = f.read_type_args(endian, (header.version,))?;
+        let (tracks, channels) = read_tracks_and_channels(f, endian, info_base, &info_header)?;
+
+        let mut regions = Vec::with_capacity(info.base.region_count.into());
+        if let (Some(region_info), Some(region_block)) =
+            (&info.region_info, header.find_block(REGION_BLOCK_ID))
+        {
+            if !region_info.region_data.is_null() {
+                let regions_off =
+                    u64::from(region_block.offset + 8) + region_info.region_data.offset as u64;
+                for i in 0..u64::from(info.base.region_count) {
+                    f.seek(SeekFrom::Start(
+                        regions_off + i * u64::from(region_info.entry_size),
+                    ))?;
+                    regions.push(f.read_type(endian)?);
+                }
+            }
+        }
+
+        f.seek(SeekFrom::Start(seek_block.offset.into()))?;
+        let seek_header: SeekBlockHeader = f.read_type(endian)?;
+        f.seek(SeekFrom::Start(data_block.offset.into()))?;
+        let data_header: DataBlockHeader = f.read_type(endian)?;
+        let data_start = resolve_reference(0, &info.base.sample_data, SAMPLE_DATA_ID)? as u32 + 8;
+
+        Ok(BfstmInformation {
+            endian,
+            version: header.version,
+            info,
+            tracks,
+            channels,
+            regions,
+            seek_offset: seek_block.offset + 8,
+            seek_size: seek_header.block_size.saturating_sub(8),
+            data_offset: data_block.offset + data_start,
+            data_size: data_header.block_size.saturating_sub(data_start),
+        })
+    }
+
+    pub fn into_with_data<RS: Read + Seek>(self, f: &mut RS) -> std::io::Result<BfstmInfoWithData> {
+        let seek_len = self.seek_table_len().min(self.seek_size as usize);
+        let mut seek_bytes = vec![0; seek_len];
+        f.seek(SeekFrom::Start(self.seek_offset.into()))?;
+        f.read_exact(&mut seek_bytes)?;
+        if self.endian == Endian::Little {
+            seek_bytes = swap_i16_bytes(&seek_bytes);
+        }
+        let mut data_bytes = vec![0; self.data_size as usize];
+        f.seek(SeekFrom::Start(self.data_offset.into()))?;
+        f.read_exact(&mut data_bytes)?;
+        Ok(BfstmInfoWithData {
+            info: self,
+            seek_bytes,
+            data_bytes,
+        })
+    }
+
+    fn seek_table_len(&self) -> usize {
+        seek_table_len(&self.info.base, self.channels.len())
+    }
+
+    fn has_region_block(&self) -> bool {
+        self.version >= REGION_INFO_VERSION && !self.regions.is_empty()
+    }
+}
+
+pub struct BfstmInfoWithData {
+    pub info: BfstmInformation,
+    /// history samples for each block and channel, always big endian like the BRSTM ADPC section
+    pub seek_bytes: Vec<u8>,
+    pub data_bytes: Vec<u8>,
+}
+
+impl BfstmInfoWithData {
+    pub fn write_bfstm<WS: Write + Seek>(&self, ws: &mut WS) -> Result<(), Error> {
+        let endian = self.info.endian;
+        let version = self.info.version;
+        let channel_count = self.info.channels.len() as u32;
+        let seek_len = (self.seek_bytes.len() as u32).min(self.info.seek_table_len() as u32);
+        let has_region_block = self.info.has_region_block();
+        let block_count = if has_region_block { 4 } else { 3 };
+
+        // first, calculate all offsets
+        let info_off = CstmHeader::byte_len(block_count);
+        // relative to the INFO block + 8
+        let stream_info_off = InfoBlockHeader::byte_len() - 8;
+        let tables_off = stream_info_off + FstmStreamInfo::byte_len(version);
+        let info_size = align_next_32(
+            8 + tables_off
+                + tracks_and_channels_byte_len(
+                    &self.info.tracks,
+                    channel_count,
+                    self.info.info.base.codec,
+                ),
+        );
+        let seek_off = info_off + info_size;
+        let seek_size = align_next_32(8 + seek_len);
+        let region_off = seek_off + seek_size;
+        let region_size = if has_region_block {
+            align_next_32(0x20 + self.info.regions.len() as u32 * u32::from(REGION_ENTRY_SIZE))
+        } else {
+            0
+        };
+        let data_off = region_off + region_size;
+        let data_size = align_next_32(0x20 + self.data_bytes.len() as u32);
+        let file_length = data_off + data_size;
+
+        let mut blocks = vec![
+            SizedReference {
+                type_id: INFO_BLOCK_ID,
+                offset: info_off,
+                size: info_size,
+            },
+            SizedReference {
+                type_id: SEEK_BLOCK_ID,
+                offset: seek_off,
+                size: seek_size,
+            },
+        ];
+        if has_region_block {
+            blocks.push(SizedReference {
+                type_id: REGION_BLOCK_ID,
+                offset: region_off,
+                size: region_size,
+            });
+        }
+        blocks.push(SizedReference {
+            type_id: DATA_BLOCK_ID,
+            offset: data_off,
+            size: data_size,
+        });
+        ws.seek(SeekFrom::Start(0))?;
+        ws.write_type(
+            &CstmHeader {
+                magic: FSTM_MAGIC,
+                header_length: info_off as u16,
+                version,
+                file_length,
+                blocks,
+            },
+            endian,
+        )?;
+        pad_to(ws, info_off)?;
+
+        // the header needs the table references, so write the tables first
+        ws.seek(SeekFrom::Start((info_off + 8 + tables_off).into()))?;
+        let (track_info_table, channel_info_table) = write_tracks_and_channels(
+            ws,
+            endian,
+            tables_off,
+            &self.info.tracks,
+            &self.info.channels,
+            self.info.info.base.codec,
+        )?;
+        pad_to(ws, seek_off)?;
+
+        ws.seek(SeekFrom::Start(info_off.into()))?;
+        ws.write_type(
+            &InfoBlockHeader {
+                block_size: info_size,
+                stream_info: Reference::new(STREAM_INFO_ID, stream_info_off),
+                track_info_table,
+                channel_info_table,
+            },
+            endian,
+        )?;
+        let info = &self.info.info;
+        let region_info = (version >= REGION_INFO_VERSION).then(|| FstmRegionInfo {
+            entry_size: REGION_ENTRY_SIZE,
+            region_data: if has_region_block {
+                // regions start 0x20 into the REGN block
+                Reference::new(0, 0x18)
+            } else {
+                Reference::null()
+            },
+        });
+        let unaligned_loop = (version >= REGION_INFO_VERSION).then(|| {
+            info.unaligned_loop.clone().unwrap_or(UnalignedLoop {
+                loop_start: info.base.loop_start,
+                loop_end: info.base.total_samples,
+            })
+        });
+        let checksum = (version >= CHECKSUM_VERSION).then(|| info.checksum.unwrap_or(0));
+        ws.write_type_args(
+            &FstmStreamInfo {
+                base: CstmStreamInfo {
+                    num_channels: channel_count as u8,
+                    region_count: if has_region_block {
+                        self.info.regions.len() as u8
+                    } else {
+                        0
+                    },
+                    seek_bytes_per_entry: 4,
+                    // data starts 0x20 into the DATA block
+                    sample_data: Reference::new(SAMPLE_DATA_ID, 0x18),
+                    ..info.base.clone()
+                },
+                region_info,
+                unaligned_loop,
+                checksum,
+            },
+            endian,
+            (version,),
+        )?;
+
+        ws.seek(SeekFrom::Start(seek_off.into()))?;
+        ws.write_type(
+            &SeekBlockHeader {
+                block_size: seek_size,
+            },
+            endian,
+        )?;
+        let seek_bytes = &self.seek_bytes[..seek_len as usize];
+        if endian == Endian::Little {
+            ws.write_all(&swap_i16_bytes(seek_bytes))?;
+        } else {
+            ws.write_all(seek_bytes)?;
+        }
+        pad_to(ws, region_off)?;
+
+        if has_region_block {
+            ws.write_type(
+                &RegionBlockHeader {
+                    block_size: region_size,
+                },
+                endian,
+            )?;
+            pad_to(ws, region_off + 0x20)?;
+            for region in self.info.regions.iter() {
+                ws.write_type(region, endian)?;
+            }
+            pad_to(ws, data_off)?;
+        }
+
+        ws.write_type(
+            &DataBlockHeader {
+                block_size: data_size,
+            },
+            endian,
+        )?;
+        pad_to(ws, data_off + 0x20)?;
+        ws.write_all(&self.data_bytes)?;
+        pad_to(ws, file_length)?;
+        ws.flush()?;
+        Ok(())
+    }
+
+    /// converts a BRSTM into a big endian BFSTM without region info,
+    /// change `endian` and `version` of the info to target the Switch
+    pub fn from_brstm(brstm: &BrstmInfoWithData) -> Result<Self, ConversionError> {
+        let (base, seek_bytes) = stream_from_brstm(brstm)?;
+        Ok(BfstmInfoWithData {
+            info: BfstmInformation {
+                endian: Endian::Big,
+                version: DEFAULT_BFSTM_VERSION,
+                info: FstmStreamInfo {
+                    base,
+                    region_info: None,
+                    unaligned_loop: None,
+                    checksum: None,
+                },
+                tracks: tracks_from_brstm(&brstm.info),
+                channels: brstm.info.channels.iter().map(DspAdpcmInfo::from).collect(),
+                regions: Vec::new(),
+                // filled in later
+                seek_offset: 0,
+                seek_size: 0,
+                data_offset: 0,
+                data_size: 0,
+            },
+            seek_bytes: seek_bytes.to_vec(),
+            data_bytes: brstm.data_bytes.clone(),
+        })
+    }
+
+    /// converts this BFSTM into a BRSTM, the DSP-ADPCM data is reused as is,
+    /// regions are dropped since BRSTM has no equivalent
+    pub fn to_brstm(&self) -> Result<BrstmInfoWithData, ConversionError> {
+        stream_to_brstm(
+            &self.info.info.base,
+            &self.info.tracks,
+            &self.info.channels,
+            &self.seek_bytes,
+            &self.data_bytes,
+        )
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::Cursor;
+
+    use binrw::Endian;
+
+    use crate::{bcstm::BcstmInformation, encoder::encode_brstm, Error};
+
+    use super::{BfstmInfoWithData, BfstmInformation, FstmRegion, CHECKSUM_VERSION};
+
+    #[test]
+    pub fn brstm_bfstm_roundtrip() {
+        let channels: Vec<Vec<i16>> = (0..2)
+            .map(|c| {
+                // 4 blocks, so the SEEK block is padded
+                (0..45_000)
+                    .map(|i| ((i as f64 * 0.02 * (c + 1) as f64).sin() * 8_000.0) as i16)
+                    .collect()
+            })
+            .collect();
+        let brstm = encode_brstm(&channels, 48000, Some(100)).unwrap();
+
+        let wii_u = BfstmInfoWithData::from_brstm(&brstm).unwrap();
+        let mut switch = BfstmInfoWithData::from_brstm(&brstm).unwrap();
+        switch.info.endian = Endian::Little;
+        switch.info.version = CHECKSUM_VERSION;
+        switch.info.regions.push(FstmRegion {
+            start_sample: 0,
+            end_sample: 45_000,
+            ..Default::default()
+        });
+
+        for (bfstm, bom) in [(wii_u, [0xFE, 0xFF]), (switch, [0xFF, 0xFE])] {
+            let mut buf = Vec::new();
+            bfstm.write_bfstm(&mut Cursor::new(&mut buf)).unwrap();
+            assert_eq!(&buf[..4], b"FSTM");
+            assert_eq!(buf[4..6], bom);
+            // the header is shared with CSTM, but the magic still has to match
+            assert!(matches!(
+                BcstmInformation::from_reader(&mut Cursor::new(&buf)),
+                Err(Error::BadMagic { pos: 0 })
+            ));
+
+            let mut cursor = Cursor::new(&buf);
+            let reread = BfstmInformation::from_reader(&mut cursor)
+                .unwrap()
+                .into_with_data(&mut cursor)
+                .unwrap();
+            assert_eq!(reread.info.regions.len(), bfstm.info.regions.len());
+            assert_eq!(reread.info.info.base.sample_rate, 48000);
+            assert_eq!(reread.seek_bytes.len(), 4 * 2 * 4);
+
+            let converted = reread.to_brstm().unwrap();
+            assert_eq!(brstm.adpcm_bytes, converted.adpcm_bytes);
+            assert_eq!(brstm.data_bytes, converted.data_bytes);
+            for channel in 0..2 {
+                assert_eq!(brstm.get_pcm(channel), converted.get_pcm(channel));
+            }
+        }
+    }
+}
@@ -1,14 +1,22 @@
-use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::{
+    fmt,
+    io::{self, Cursor, Read, Seek, SeekFrom, Write},
+    ops::Range,
+};
 
-use binrw::{BinReaderExt, BinResult, BinWriterExt};
+use binrw::{BinReaderExt, BinWriterExt};
 
 use crate::structs::{
-    AdpcHeader, AdpcmChannelInformation, BrstmHeader, ChannelInfoOffset, Channels, DataHeader,
-    Head1, Head2, Head3, HeadChunkOffsets, HeadSectionHeader, TrackDescription, TrackDescriptionV1,
-    TrackInfoOffset,
+    AdpcHeader, AdpcmChannelInformation, BrstmHeader, ChannelInfoOffset, Channels, Codec,
+    DataHeader, Head1, Head2, Head3, HeadChunkOffsets, HeadSectionHeader, TrackDescription,
+    TrackDescriptionV1, TrackInfoOffset,
+};
+use crate::{
+    adpcm::{decode, History},
+    Error,
 };
 
-fn align_next_32(off: u32) -> u32 {
+pub(crate) fn align_next_32(off: u32) -> u32 {
     (off + 0x1F) & !0x1F
 }
 
@@ -22,22 +30,144 @@ pub struct BrstmInformation {
     pub(crate) adpcm_size: u32,
     pub(crate) data_offset: u32,
     pub(crate) data_size: u32,
+    // only set when read with `from_reader_lossless`
+    pub(crate) original: Option<Box<OriginalLayout>>,
+}
+
+/// everything needed to reproduce a file exactly as it was read, see
+/// [`BrstmInformation::write_brstm_lossless`]
+#[derive(Clone)]
+pub(crate) struct OriginalLayout {
+    // the information as it was read, to detect modifications
+    info: Head1,
+    tracks: Vec<TrackDescription>,
+    channels: Vec<AdpcmChannelInformation>,
+    adpcm_size: u32,
+    data_size: u32,
+    // all bytes of the file that aren't ADPC or DATA payload (headers, padding, unknown data),
+    // with their offset
+    segments: Vec<(u64, Vec<u8>)>,
+}
+
+impl fmt::Debug for OriginalLayout {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // the information is the same as in the BrstmInformation unless it was modified,
+        // the segments would just be a lot of bytes
+        f.debug_struct("OriginalLayout")
+            .field(
+                "segments",
+                &self
+                    .segments
+                    .iter()
+                    .map(|(offset, bytes)| (*offset, bytes.len()))
+                    .collect::<Vec<_>>(),
+            )
+            .finish_non_exhaustive()
+    }
+}
+
+impl OriginalLayout {
+    /// returns None if the payloads overlap or are out of bounds, then the layout can't be
+    /// reproduced anyways
+    fn capture<RS: Read + Seek>(f: &mut RS, info: &BrstmInformation) -> io::Result<Option<Self>> {
+        let file_len = f.seek(SeekFrom::End(0))?;
+        let mut payloads = [
+            (info.adpcm_offset as u64, info.adpcm_size as u64),
+            (info.data_offset as u64, info.data_size as u64),
+        ];
+        payloads.sort();
+        let mut segments = Vec::with_capacity(3);
+        let mut pos = 0;
+        for (offset, size) in payloads {
+            if offset < pos || offset + size > file_len {
+                return Ok(None);
+            }
+            segments.push((pos, offset - pos));
+            pos = offset + size;
+        }
+        segments.push((pos, file_len - pos));
+        let segments = segments
+            .into_iter()
+            .map(|(offset, len)| {
+                let mut buf = vec![0; len as usize];
+                f.seek(SeekFrom::Start(offset))?;
+                f.read_exact(&mut buf)?;
+                Ok((offset, buf))
+            })
+            .collect::<io::Result<_>>()?;
+        Ok(Some(OriginalLayout {
+            info: info.info.clone(),
+            tracks: info.tracks.clone(),
+            channels: info.channels.clone(),
+            adpcm_size: info.adpcm_size,
+            data_size: info.data_size,
+            segments,
+        }))
+    }
 }
 
 impl BrstmInformation {
-    pub fn from_reader<RS: Read + Seek>(f: &mut RS) -> BinResult<Self> {
+    /// reads the information of a BRSTM, the block layout in Head1 has to be consistent with the
+    /// codec and sample count (see [`Head1::validate`]), otherwise this fails with
+    /// [`Error::InvalidLayout`]. Older versions loaded such files and only failed (or panicked)
+    /// when accessing the data, callers that need to tolerate them, like a loader that only
+    /// looks at the tracks and loop points, can use [`BrstmInformation::from_reader_unchecked`].
+    /// [`crate::repair::repair_file`] fixes the layout of such files
+    pub fn from_reader<RS: Read + Seek>(f: &mut RS) -> Result<Self, Error> {
+        let (info, head1_off) = Self::read_unchecked(f)?;
+        info.info
+            .validate()
+            .map_err(|message| Error::InvalidLayout {
+                pos: head1_off,
+                message,
+            })?;
+        Ok(info)
+    }
+
+    /// like [`BrstmInformation::from_reader`], but doesn't check the block layout. Only the
+    /// checked accessors like [`BrstmInfoWithData::try_get_pcm`] can be relied on for such files
+    pub fn from_reader_unchecked<RS: Read + Seek>(f: &mut RS) -> Result<Self, Error> {
+        Ok(Self::read_unchecked(f)?.0)
+    }
+
+    /// reads without checking the block layout, also returns the offset of Head1
+    pub(crate) fn read_unchecked<RS: Read + Seek>(f: &mut RS) -> Result<(Self, u64), Error> {
+        // check the magic and version first, to report them properly instead of a failed assert
+        let start = f.stream_position()?;
+        let mut magic_version = [0; 8];
+        f.read_exact(&mut magic_version)?;
+        if &magic_version[..4] != b"RSTM" {
+            return Err(Error::BadMagic { pos: start });
+        }
+        let version = u16::from_be_bytes([magic_version[6], magic_version[7]]);
+        if version != 0x0100 {
+            return Err(Error::UnsupportedVersion {
+                pos: start + 6,
+                version,
+            });
+        }
+        f.seek(SeekFrom::Start(start))?;
         let header = f.read_be::<BrstmHeader>()?;
         f.seek(SeekFrom::Start(header.head_offset.into()))?;
         let head: HeadSectionHeader = f.read_be()?;
-        let head_base_offset = header.head_offset + 8;
-        let head1_off = head_base_offset + head.head_chunks[0].head_chunk_offset;
-        f.seek(SeekFrom::Start(head1_off.into()))?;
+        // offsets from the file can be anything, so calculate them without overflowing
+        let head_base_offset = u64::from(header.head_offset) + 8;
+        let head1_off = head_base_offset + u64::from(head.head_chunks[0].head_chunk_offset);
+        f.seek(SeekFrom::Start(head1_off))?;
+        let codec: u8 = f.read_be()?;
+        if Codec::try_from(codec).is_err() {
+            return Err(Error::UnsupportedCodec {
+                pos: head1_off,
+                codec,
+            });
+        }
+        f.seek(SeekFrom::Start(head1_off))?;
         let head1: Head1 = f.read_be()?;
-        let head2_off = head_base_offset + head.head_chunks[1].head_chunk_offset;
-        f.seek(SeekFrom::Start(head2_off.into()))?;
+        let head2_off = head_base_offset + u64::from(head.head_chunks[1].head_chunk_offset);
+        f.seek(SeekFrom::Start(head2_off))?;
         let head2: Head2 = f.read_be()?;
-        let head3_off = head_base_offset + head.head_chunks[2].head_chunk_offset;
-        f.seek(SeekFrom::Start(head3_off.into()))?;
+        let head3_off = head_base_offset + u64::from(head.head_chunks[2].head_chunk_offset);
+        f.seek(SeekFrom::Start(head3_off))?;
         let head3: Head3 = f.read_be()?;
         let head2_tracks = head2.track_info_offsets.len();
         let head3_tracks = head3.info_offsets.len();
@@ -45,37 +175,50 @@ impl BrstmInformation {
         let mut channels = Vec::with_capacity(head3_tracks);
         for track_adpcm_off in head3.info_offsets.iter() {
             f.seek(SeekFrom::Start(
-                (head_base_offset + track_adpcm_off.offset).into(),
+                head_base_offset + u64::from(track_adpcm_off.offset),
             ))?;
             let adpcm_info: AdpcmChannelInformation = f.read_be()?;
             channels.push(adpcm_info);
         }
         for (idx, track_desc_off) in head2.track_info_offsets.iter().enumerate() {
             if track_desc_off.track_info_type != head2.track_info_type {
-                return Err(binrw::Error::AssertFail {
-                    pos: 0,
-                    message: format!(
-                        "Differing track description type for channel {idx}: {} vs {}",
-                        track_desc_off.track_info_type, head2.track_info_type
-                    ),
+                return Err(Error::TrackTypeMismatch {
+                    pos: head2_off,
+                    track: idx,
+                    found: track_desc_off.track_info_type,
+                    expected: head2.track_info_type,
                 });
             }
             f.seek(SeekFrom::Start(
-                (head_base_offset + track_desc_off.offset).into(),
+                head_base_offset + u64::from(track_desc_off.offset),
             ))?;
             let track = f.read_be_args::<TrackDescription>((track_desc_off.track_info_type,))?;
 
             tracks.push(track);
         }
-        Ok(BrstmInformation {
+        let info = BrstmInformation {
             info: head1,
             tracks,
             channels,
-            adpcm_offset: header.adpc_offset + 8,
-            adpcm_size: header.adpc_size - 8,
-            data_offset: header.data_offset + 0x20,
-            data_size: header.data_size - 0x20,
-        })
+            // an offset at the very end of the range is out of bounds anyways
+            adpcm_offset: header.adpc_offset.saturating_add(8),
+            // PCM streams might not have an ADPC section
+            adpcm_size: header.adpc_size.saturating_sub(8),
+            data_offset: header.data_offset.saturating_add(0x20),
+            data_size: header.data_size.saturating_sub(0x20),
+            original: None,
+        };
+        Ok((info, head1_off))
+    }
+
+    /// like [`BrstmInformation::from_reader`], but also keeps everything of the file that isn't
+    /// ADPC or DATA payload (headers, padding, unknown data and trailing bytes), so that
+    /// [`Self::write_brstm_lossless`] can reproduce it. That's an extra copy of those bytes,
+    /// which only pays off if the file is written again
+    pub fn from_reader_lossless<RS: Read + Seek>(f: &mut RS) -> Result<Self, Error> {
+        let info = Self::from_reader(f)?;
+        let original = OriginalLayout::capture(f, &info)?.map(Box::new);
+        Ok(BrstmInformation { original, ..info })
     }
 
     pub fn write_brstm<WS: Write + Seek>(
@@ -83,8 +226,101 @@ impl BrstmInformation {
         ws: &mut WS,
         adpcm_bytes: &[u8],
         data_bytes: &[u8],
-    ) -> binrw::BinResult<()> {
+    ) -> Result<(), Error> {
         ws.seek(SeekFrom::Start(0))?;
+        self.write_brstm_sequential(ws, adpcm_bytes, data_bytes)
+    }
+
+    /// writes the same file as [`Self::write_brstm`], but strictly front to back, so any writer
+    /// works, like a pipe or a compressor
+    pub fn write_brstm_sequential<W: Write>(
+        &self,
+        w: &mut W,
+        adpcm_bytes: &[u8],
+        data_bytes: &[u8],
+    ) -> Result<(), Error> {
+        let channel_count = self.channels.len() as u32;
+        let head1 = self.normalized_head1()?;
+        // if the header describes a consistent layout, the data follows it. It can only differ
+        // from the derived one in the padding of the final blocks, which then have to be moved
+        let given = Head1 {
+            num_channels: head1.num_channels,
+            ..self.info.clone()
+        };
+        let given_layout = if given.validate().is_ok() {
+            &given
+        } else {
+            &head1
+        };
+        let needed_data_len = given_layout.data_len();
+        if data_bytes.len() < needed_data_len {
+            return Err(Error::InconsistentData(format!(
+                "Audio data is {} bytes, but {} blocks of {channel_count} channels need {needed_data_len}",
+                data_bytes.len(),
+                head1.total_blocks
+            )));
+        }
+        let needed_adpc_len = head1.total_blocks as usize * channel_count as usize * 4;
+        if head1.codec == Codec::Adpcm && adpcm_bytes.len() < needed_adpc_len {
+            return Err(Error::InconsistentData(format!(
+                "ADPC table is {} bytes, but {} blocks of {channel_count} channels need {needed_adpc_len}",
+                adpcm_bytes.len(),
+                head1.total_blocks
+            )));
+        }
+        // anything after the final blocks isn't audio
+        let final_start = head1.block_data_offset(0, head1.total_blocks - 1);
+        let given_stride = given_layout.final_block_size_padded as usize;
+        let stride = head1.final_block_size_padded as usize;
+        let data_len = head1.data_len();
+        self.write_head_sections(w, head1, adpcm_bytes, data_len)?;
+        w.write_all(&data_bytes[..final_start])?;
+        for channel in 0..channel_count as usize {
+            let block =
+                &data_bytes[final_start + channel * given_stride..][..given_stride.min(stride)];
+            w.write_all(block)?;
+            w.write_all(&[0; 0x20][..stride - block.len()])?;
+        }
+        w.flush()?;
+        Ok(())
+    }
+
+    /// the block layout is always derived from the sample count, checks that the tracks only
+    /// reference existing channels
+    pub(crate) fn normalized_head1(&self) -> Result<Head1, Error> {
+        let mut head1 = Head1 {
+            num_channels: self.channels.len() as u8,
+            ..self.info.clone()
+        };
+        head1
+            .derive_block_layout()
+            .and_then(|_| head1.validate())
+            .map_err(Error::InconsistentData)?;
+        for (track, desc) in self.tracks.iter().enumerate() {
+            let channel = desc
+                .channels
+                .left_channel_id()
+                .max(desc.channels.right_channel_id());
+            if channel as usize >= self.channels.len() {
+                return Err(Error::InvalidChannelReference {
+                    track,
+                    channel,
+                    channel_count: self.channels.len(),
+                });
+            }
+        }
+        Ok(head1)
+    }
+
+    /// writes everything before the audio data: the HEAD and ADPC section and the header of
+    /// the DATA section. `head1` has to come from [`Self::normalized_head1`]
+    pub(crate) fn write_head_sections<W: Write>(
+        &self,
+        w: &mut W,
+        mut head1: Head1,
+        adpcm_bytes: &[u8],
+        data_len: usize,
+    ) -> Result<(), Error> {
         let channel_count = self.channels.len() as u32;
         let any_has_v1 = self.tracks.iter().any(|t| t.get_version() == 1);
         let track_desc_bytes = if any_has_v1 { 12 } else { 4 };
@@ -101,7 +337,7 @@ impl BrstmInformation {
         let adpcm_section_off =
             align_next_32(channel_infos_off + AdpcmChannelInformation::byte_len() * channel_count);
         let data_section_off = align_next_32(adpcm_section_off + adpc_section_len_aligned);
-        let file_length = align_next_32(data_section_off + data_bytes.len() as u32 + 0x20);
+        let file_length = align_next_32(data_section_off + data_len as u32 + 0x20);
         let header = BrstmHeader {
             file_length,
             head_offset: head_header_off,
@@ -111,9 +347,10 @@ impl BrstmInformation {
             adpc_size: adpc_section_len_aligned,
             data_offset: data_section_off,
             // plus header
-            data_size: data_bytes.len() as u32 + 0x20,
+            data_size: data_len as u32 + 0x20,
         };
-        ws.seek(SeekFrom::Start(0))?;
+        // the headers are small, so they are put together in memory where seeking is possible
+        let mut ws = Cursor::new(Vec::with_capacity(adpcm_section_off as usize + 8));
         ws.write_be(&header)?;
 
         let head_header = HeadSectionHeader {
@@ -134,11 +371,7 @@ impl BrstmInformation {
         ws.seek(SeekFrom::Start(head_header_off.into()))?;
         ws.write_be(&head_header)?;
 
-        let head1 = Head1 {
-            audio_offset: data_section_off + 0x20,
-            num_channels: channel_count as u8,
-            ..self.info // TODO: also need to fix total samples and blocks?
-        };
+        head1.audio_offset = data_section_off + 0x20;
         ws.seek(SeekFrom::Start(head1_off.into()))?;
         ws.write_be(&head1)?;
 
@@ -201,21 +434,74 @@ impl BrstmInformation {
         };
         ws.seek(SeekFrom::Start(adpcm_section_off.into()))?;
         ws.write_be(&adpcm_header)?;
-        ws.write_all(adpcm_bytes)?;
+        w.write_all(ws.get_ref())?;
+        w.write_all(adpcm_bytes)?;
         // pad to next 32
         let pad = [0; 32];
-        ws.write_all(&pad[..(adpc_section_len_aligned - adpc_section_len) as usize])?;
+        w.write_all(&pad[..(adpc_section_len_aligned - adpc_section_len) as usize])?;
 
+        // the DATA section directly follows the padded ADPC section
+        debug_assert_eq!(
+            data_section_off,
+            adpcm_section_off + adpc_section_len_aligned
+        );
         let data_header = DataHeader {
-            data_len: data_bytes.len() as u32 + 0x20,
+            data_len: data_len as u32 + 0x20,
         };
-        ws.seek(SeekFrom::Start(data_section_off.into()))?;
+        let mut ws = Cursor::new(Vec::with_capacity(0x20));
         ws.write_be(&data_header)?;
-        ws.write_all(data_bytes)?;
+        w.write_all(ws.get_ref())?;
+        Ok(())
+    }
+
+    /// if this was read with [`Self::from_reader_lossless`] and nothing was modified since,
+    /// reproduces the original file byte for byte
+    /// (including unusual padding, section order and section sizes), otherwise the same as
+    /// [`Self::write_brstm`]. The audio data may have been modified as long as the length is
+    /// the same
+    pub fn write_brstm_lossless<WS: Write + Seek>(
+        &self,
+        ws: &mut WS,
+        adpcm_bytes: &[u8],
+        data_bytes: &[u8],
+    ) -> Result<(), Error> {
+        let original = match self.original.as_deref() {
+            Some(original)
+                if self.is_unmodified()
+                    && original.adpcm_size as usize == adpcm_bytes.len()
+                    && original.data_size as usize == data_bytes.len() =>
+            {
+                original
+            }
+            _ => return self.write_brstm(ws, adpcm_bytes, data_bytes),
+        };
+        let mut parts: Vec<(u64, &[u8])> = original
+            .segments
+            .iter()
+            .map(|(offset, bytes)| (*offset, bytes.as_slice()))
+            .collect();
+        parts.push((self.adpcm_offset.into(), adpcm_bytes));
+        parts.push((self.data_offset.into(), data_bytes));
+        // the order is only ambiguous for empty parts
+        parts.sort_by_key(|(offset, _)| *offset);
+        ws.seek(SeekFrom::Start(0))?;
+        for (_, bytes) in parts {
+            ws.write_all(bytes)?;
+        }
         ws.flush()?;
         Ok(())
     }
 
+    /// if this was read with [`Self::from_reader_lossless`] and hasn't been modified since,
+    /// see [`Self::write_brstm_lossless`]
+    pub fn is_unmodified(&self) -> bool {
+        self.original.as_deref().is_some_and(|original| {
+            original.info == self.info
+                && original.tracks == self.tracks
+                && original.channels == self.channels
+        })
+    }
+
     pub fn channel_count(&self) -> u8 {
         self.channels.len() as u8
     }
@@ -243,7 +529,8 @@ impl BrstmInformation {
     /// - channels are referenced that don't exist
     /// - channels exist but aren't referenced
     pub fn check_tracks_valid(&self) -> bool {
-        let mut referenced_channels: Vec<_> = std::iter::repeat_n(false, self.channels.len()).collect();
+        let mut referenced_channels: Vec<_> =
+            std::iter::repeat_n(false, self.channels.len()).collect();
         // iterate over all tracks, mark each found channel as referenced and return false when a non
         // existing channel is referenced
         for track in self.tracks.iter() {
@@ -285,7 +572,7 @@ impl BrstmInformation {
         if !self.check_tracks_valid() {
             // rebuild tracks
             // first, guess if it's stereo or mono
-            if self.channels.len() > 1 && self.channels.len() % 2 == 0 {
+            if self.channels.len() > 1 && self.channels.len().is_multiple_of(2) {
                 // Stereo
                 self.tracks = (0..self.channels.len() / 2)
                     .map(|i| TrackDescription {
@@ -307,12 +594,28 @@ impl BrstmInformation {
         made_change
     }
 
-    pub fn into_with_data<RS: Read + Seek>(self, f: &mut RS) -> io::Result<BrstmInfoWithData> {
-        let mut adpcm_bytes = vec![0; self.adpcm_size as usize];
-        f.seek(SeekFrom::Start(self.adpcm_offset.into()))?;
+    /// reads the ADPC and DATA payload, which have to fit the block layout,
+    /// see [`BrstmInfoWithData::check_layout`]
+    pub fn into_with_data<RS: Read + Seek>(self, f: &mut RS) -> Result<BrstmInfoWithData, Error> {
+        let brstm = self.into_with_data_unchecked(f)?;
+        brstm.check_layout()?;
+        Ok(brstm)
+    }
+
+    /// reads the payload without checking it against the block layout, for files read with
+    /// [`BrstmInformation::from_reader_unchecked`]
+    pub fn into_with_data_unchecked<RS: Read + Seek>(
+        self,
+        f: &mut RS,
+    ) -> Result<BrstmInfoWithData, Error> {
+        // the sizes come from the file, check them before allocating anything
+        let file_len = f.seek(SeekFrom::End(0))?;
+        let (adpcm_range, data_range) = self.payload_ranges(file_len)?;
+        let mut adpcm_bytes = vec![0; adpcm_range.len()];
+        f.seek(SeekFrom::Start(adpcm_range.start as u64))?;
         f.read_exact(&mut adpcm_bytes)?;
-        let mut data_bytes = vec![0; self.data_size as usize];
-        f.seek(SeekFrom::Start(self.data_offset.into()))?;
+        let mut data_bytes = vec![0; data_range.len()];
+        f.seek(SeekFrom::Start(data_range.start as u64))?;
         f.read_exact(&mut data_bytes)?;
         Ok(BrstmInfoWithData {
             info: self,
@@ -320,6 +623,32 @@ impl BrstmInformation {
             data_bytes,
         })
     }
+
+    /// byte ranges of the ADPC and DATA payload in the file, which have to be inside of it
+    pub(crate) fn payload_ranges(
+        &self,
+        file_len: u64,
+    ) -> Result<(Range<usize>, Range<usize>), Error> {
+        let sections = [
+            ("ADPC", self.adpcm_offset, self.adpcm_size),
+            ("DATA", self.data_offset, self.data_size),
+        ];
+        for (section, offset, size) in sections {
+            if u64::from(offset) + u64::from(size) > file_len {
+                return Err(Error::InvalidLayout {
+                    pos: offset.into(),
+                    message: format!(
+                        "{section} section with {size} bytes extends past the end of the file at {file_len:#X}"
+                    ),
+                });
+            }
+        }
+        let range = |offset: u32, size: u32| offset as usize..offset as usize + size as usize;
+        Ok((
+            range(self.adpcm_offset, self.adpcm_size),
+            range(self.data_offset, self.data_size),
+        ))
+    }
 }
 
 pub struct BrstmInfoWithData {
@@ -329,88 +658,297 @@ pub struct BrstmInfoWithData {
 }
 
 impl BrstmInfoWithData {
-    pub fn write_brstm<WS: Write + Seek>(&self, ws: &mut WS) -> binrw::BinResult<()> {
+    pub fn write_brstm<WS: Write + Seek>(&self, ws: &mut WS) -> Result<(), Error> {
         self.info
             .write_brstm(ws, &self.adpcm_bytes, &self.data_bytes)
     }
 
+    /// see [`BrstmInformation::write_brstm_sequential`]
+    pub fn write_brstm_sequential<W: Write>(&self, w: &mut W) -> Result<(), Error> {
+        self.info
+            .write_brstm_sequential(w, &self.adpcm_bytes, &self.data_bytes)
+    }
+
+    /// see [`BrstmInformation::write_brstm_lossless`]
+    pub fn write_brstm_lossless<WS: Write + Seek>(&self, ws: &mut WS) -> Result<(), Error> {
+        self.info
+            .write_brstm_lossless(ws, &self.adpcm_bytes, &self.data_bytes)
+    }
+
+    pub(crate) fn audio(&self) -> AudioData<'_, '_> {
+        AudioData {
+            info: &self.info,
+            adpcm_bytes: &self.adpcm_bytes,
+            data_bytes: &self.data_bytes,
+        }
+    }
+
+    /// makes sure the header is consistent and the ADPC table and the audio data are big enough
+    /// for the block layout, then the accessors can't panic for existing channels.
+    /// This is always the case for data returned by [`BrstmInformation::into_with_data`]
+    pub fn check_layout(&self) -> Result<(), Error> {
+        self.audio().check_layout()
+    }
+
+    /// panics if the entry doesn't exist, see [`Self::try_get_adpc_bytes`]
     pub fn get_adpc_bytes(&self, channel: u8, block_index: u32) -> &[u8; 4] {
-        let adpc_offset =
-            block_index as usize * 4 * self.info.info.num_channels as usize + channel as usize * 4;
+        self.audio().get_adpc_bytes(channel, block_index)
+    }
 
-        self.adpcm_bytes[adpc_offset..][..4].try_into().unwrap()
+    pub fn try_get_adpc_bytes(&self, channel: u8, block_index: u32) -> Result<&[u8; 4], Error> {
+        self.audio().try_get_adpc_bytes(channel, block_index)
     }
 
+    /// panics if the entry doesn't exist, see [`Self::try_get_adpc_values`]
     pub fn get_adpc_values(&self, channel: u8, block_index: u32) -> (i16, i16) {
-        let bytes = self.get_adpc_bytes(channel, block_index);
+        self.audio().get_adpc_values(channel, block_index)
+    }
 
-        (
-            i16::from_be_bytes(bytes[..2].try_into().unwrap()),
-            i16::from_be_bytes(bytes[2..4].try_into().unwrap()),
-        )
+    pub fn try_get_adpc_values(&self, channel: u8, block_index: u32) -> Result<(i16, i16), Error> {
+        self.audio().try_get_adpc_values(channel, block_index)
     }
 
+    /// panics if the block doesn't exist, see [`Self::try_get_data_block`]
     pub fn get_data_block(&self, channel: u8, block_index: u32) -> &[u8] {
-        let (data_offset, block_size) = if block_index == self.info.info.total_blocks - 1 {
-            (
-                block_index as usize
-                    * self.info.info.num_channels as usize
-                    * self.info.info.blocks_size as usize
-                    + channel as usize * self.info.info.final_block_size_padded as usize,
-                self.info.info.final_block_size_padded,
-            )
-        } else {
-            (
-                (block_index as usize * self.info.info.num_channels as usize + channel as usize)
-                    * self.info.info.blocks_size as usize,
-                self.info.info.blocks_size,
-            )
-        };
-        &self.data_bytes[data_offset..][..block_size as usize]
+        self.audio().get_data_block(channel, block_index)
+    }
+
+    pub fn try_get_data_block(&self, channel: u8, block_index: u32) -> Result<&[u8], Error> {
+        self.audio().try_get_data_block(channel, block_index)
     }
 
     pub fn get_data_block_with_samplecount(&self, channel: u8, block_index: u32) -> (&[u8], u32) {
-        let (data_offset, block_size, block_samples) = if block_index
-            == self.info.info.total_blocks - 1
-        {
-            (
-                block_index as usize
-                    * self.info.info.num_channels as usize
-                    * self.info.info.blocks_size as usize
-                    + channel as usize * self.info.info.final_block_size_padded as usize,
-                self.info.info.final_block_size_padded,
-                self.info.info.final_block_samples,
-            )
-        } else {
-            (
-                (block_index as usize * self.info.info.num_channels as usize + channel as usize)
-                    * self.info.info.blocks_size as usize,
-                self.info.info.blocks_size,
-                self.info.info.blocks_samples,
-            )
-        };
         (
-            &self.data_bytes[data_offset..][..block_size as usize],
-            block_samples,
+            self.get_data_block(channel, block_index),
+            self.info.info.block_samples(block_index),
         )
     }
 
+    /// panics if the channel doesn't exist or the data doesn't fit the block layout,
+    /// see [`Self::try_get_pcm`]
     pub fn get_pcm(&self, channel: u8) -> Vec<i16> {
+        self.try_get_pcm(channel).unwrap()
+    }
+
+    pub fn try_get_pcm(&self, channel: u8) -> Result<Vec<i16>, Error> {
+        self.try_get_pcm_range(channel, 0, u32::MAX)
+    }
+
+    /// decodes only the samples `start..end` of the channel, decoding starts at the block
+    /// containing `start` using the history from the ADPC section, `end` is capped to the
+    /// total sample count. Panics like [`Self::get_pcm`]
+    pub fn get_pcm_range(&self, channel: u8, start: u32, end: u32) -> Vec<i16> {
+        self.try_get_pcm_range(channel, start, end).unwrap()
+    }
+
+    pub fn try_get_pcm_range(&self, channel: u8, start: u32, end: u32) -> Result<Vec<i16>, Error> {
+        self.audio().try_get_pcm_range(channel, start, end)
+    }
+
+    /// [`Self::get_pcm_range`] for all channels
+    pub fn get_pcm_range_all(&self, start: u32, end: u32) -> Vec<Vec<i16>> {
+        (0..self.info.channel_count())
+            .map(|channel| self.get_pcm_range(channel, start, end))
+            .collect()
+    }
+}
+
+/// the information together with the ADPC and DATA payload, the accessors of
+/// [`BrstmInfoWithData`] and [`crate::view::BrstmView`] are implemented here
+#[derive(Clone, Copy)]
+pub(crate) struct AudioData<'i, 'a> {
+    pub(crate) info: &'i BrstmInformation,
+    pub(crate) adpcm_bytes: &'a [u8],
+    pub(crate) data_bytes: &'a [u8],
+}
+
+impl<'a> AudioData<'_, 'a> {
+    pub(crate) fn check_layout(self) -> Result<(), Error> {
+        let head1 = &self.info.info;
+        if head1.num_channels as usize != self.info.channels.len() {
+            return Err(Error::ChannelCountMismatch {
+                expected: head1.num_channels,
+                found: self.info.channels.len(),
+            });
+        }
+        head1.validate().map_err(Error::InconsistentData)?;
+        if self.data_bytes.len() < head1.data_len() {
+            return Err(Error::InconsistentData(format!(
+                "Audio data is {} bytes, but the block layout needs {}",
+                self.data_bytes.len(),
+                head1.data_len()
+            )));
+        }
+        if head1.codec == Codec::Adpcm {
+            let needed = head1.total_blocks as usize * head1.num_channels as usize * 4;
+            if head1.adpc_bytes_per_entry != 4 || self.adpcm_bytes.len() < needed {
+                return Err(Error::InconsistentData(format!(
+                    "ADPC table is {} bytes with {} bytes per entry, but needs {needed} with 4",
+                    self.adpcm_bytes.len(),
+                    head1.adpc_bytes_per_entry
+                )));
+            }
+        }
+        Ok(())
+    }
+
+    fn check_index(self, channel: u8, block_index: u32) -> Result<(), Error> {
+        if channel as usize >= self.info.channels.len() {
+            return Err(Error::ChannelOutOfRange {
+                channel,
+                channel_count: self.info.channels.len(),
+            });
+        }
+        if block_index >= self.info.info.total_blocks {
+            return Err(Error::BlockOutOfRange {
+                block: block_index,
+                total_blocks: self.info.info.total_blocks,
+            });
+        }
+        Ok(())
+    }
+
+    fn adpc_offset(self, channel: u8, block_index: u32) -> usize {
+        block_index as usize * 4 * self.info.info.num_channels as usize + channel as usize * 4
+    }
+
+    pub(crate) fn get_adpc_bytes(self, channel: u8, block_index: u32) -> &'a [u8; 4] {
+        let adpc_offset = self.adpc_offset(channel, block_index);
+
+        self.adpcm_bytes[adpc_offset..][..4].try_into().unwrap()
+    }
+
+    pub(crate) fn try_get_adpc_bytes(
+        self,
+        channel: u8,
+        block_index: u32,
+    ) -> Result<&'a [u8; 4], Error> {
+        self.check_index(channel, block_index)?;
+        let adpc_offset = self.adpc_offset(channel, block_index);
+        self.adpcm_bytes
+            .get(adpc_offset..adpc_offset + 4)
+            .and_then(|bytes| bytes.try_into().ok())
+            .ok_or_else(|| {
+                Error::InconsistentData(format!(
+                    "ADPC table is {} bytes, the entry of channel {channel} in block {block_index} is at {adpc_offset}",
+                    self.adpcm_bytes.len()
+                ))
+            })
+    }
+
+    pub(crate) fn get_adpc_values(self, channel: u8, block_index: u32) -> (i16, i16) {
+        let bytes = self.get_adpc_bytes(channel, block_index);
+
+        (
+            i16::from_be_bytes(bytes[..2].try_into().unwrap()),
+            i16::from_be_bytes(bytes[2..4].try_into().unwrap()),
+        )
+    }
+
+    pub(crate) fn try_get_adpc_values(
+        self,
+        channel: u8,
+        block_index: u32,
+    ) -> Result<(i16, i16), Error> {
+        let bytes = self.try_get_adpc_bytes(channel, block_index)?;
+        Ok((
+            i16::from_be_bytes([bytes[0], bytes[1]]),
+            i16::from_be_bytes([bytes[2], bytes[3]]),
+        ))
+    }
+
+    pub(crate) fn get_data_block(self, channel: u8, block_index: u32) -> &'a [u8] {
+        let head1 = &self.info.info;
+        &self.data_bytes[head1.block_data_offset(channel, block_index)..]
+            [..head1.block_byte_len(block_index) as usize]
+    }
+
+    pub(crate) fn try_get_data_block(
+        self,
+        channel: u8,
+        block_index: u32,
+    ) -> Result<&'a [u8], Error> {
+        self.check_index(channel, block_index)?;
+        let head1 = &self.info.info;
+        let offset = head1.block_data_offset(channel, block_index);
+        let len = head1.block_byte_len(block_index) as usize;
+        self.data_bytes.get(offset..offset + len).ok_or_else(|| {
+            Error::InconsistentData(format!(
+                "Audio data is {} bytes, block {block_index} of channel {channel} is at {offset} with {len} bytes",
+                self.data_bytes.len()
+            ))
+        })
+    }
+
+    pub(crate) fn try_get_pcm_range(
+        self,
+        channel: u8,
+        start: u32,
+        end: u32,
+    ) -> Result<Vec<i16>, Error> {
+        let head1 = &self.info.info;
+        head1.validate().map_err(Error::InconsistentData)?;
+        if channel as usize >= self.info.channels.len() {
+            return Err(Error::ChannelOutOfRange {
+                channel,
+                channel_count: self.info.channels.len(),
+            });
+        }
+        let end = end.min(head1.total_samples);
+        if start >= end {
+            return Ok(Vec::new());
+        }
+        let first_block = start / head1.blocks_samples;
+        let last_block = (end - 1) / head1.blocks_samples;
+        // the header can claim far more samples than there is data, so the blocks only reserve
+        // their samples once their data is known to exist
         let mut result = Vec::new();
+        self.decode_blocks(channel, first_block..last_block + 1, &mut result)?;
+        result.truncate((end - first_block * head1.blocks_samples) as usize);
+        result.drain(..(start - first_block * head1.blocks_samples) as usize);
+        Ok(result)
+    }
+
+    fn decode_blocks(
+        self,
+        channel: u8,
+        blocks: Range<u32>,
+        out: &mut Vec<i16>,
+    ) -> Result<(), Error> {
+        let head1 = &self.info.info;
         let coeffs = &self.info.channels[channel as usize].adpcm_coefficients;
-        assert_eq!(4, self.info.info.adpc_bytes_per_entry);
-        for block_index in 0..self.info.info.total_blocks {
-            // decode single block
-            let (yn1, yn2) = self.get_adpc_values(channel, block_index);
-            let (data, sample_count) = self.get_data_block_with_samplecount(channel, block_index);
-            do_decode(data, sample_count, yn1, yn2, coeffs, &mut result);
+        if head1.codec == Codec::Adpcm && head1.adpc_bytes_per_entry != 4 {
+            return Err(Error::InconsistentData(format!(
+                "ADPC entries have to be 4 bytes, not {}",
+                head1.adpc_bytes_per_entry
+            )));
         }
-
-        result
+        for block_index in blocks {
+            // PCM doesn't need any history
+            let (yn1, yn2) = if head1.codec == Codec::Adpcm {
+                self.try_get_adpc_values(channel, block_index)?
+            } else {
+                (0, 0)
+            };
+            let data = self.try_get_data_block(channel, block_index)?;
+            let sample_count = head1.block_samples(block_index);
+            if (data.len() as u64) < u64::from(head1.codec.bytes_for_samples(sample_count)) {
+                return Err(Error::InconsistentData(format!(
+                    "Block {block_index} has {} bytes, which can't hold {sample_count} samples",
+                    data.len()
+                )));
+            }
+            out.reserve(sample_count as usize);
+            decode_block(head1.codec, data, sample_count, yn1, yn2, coeffs, out);
+        }
+        Ok(())
     }
 }
 
-fn do_decode(
+/// decodes a single block of a channel in any codec, the history and coefficients are only
+/// used for ADPCM
+pub(crate) fn decode_block(
+    codec: Codec,
     data: &[u8],
     sample_count: u32,
     yn1: i16,
@@ -418,36 +956,387 @@ fn do_decode(
     coeffs: &[i16; 16],
     out_buf: &mut Vec<i16>,
 ) {
-    // see https://github.com/libertyernie/brawltools/blob/master/BrawlLib/Wii/Audio/ADPCMState.cs
-    let mut data_offset = 0;
-    let mut cps = 0;
-    let mut cyn1 = yn1;
-    let mut cyn2 = yn2;
-    for sample_idx in 0..sample_count {
-        if sample_idx % 14 == 0 {
-            cps = data[data_offset];
-            data_offset += 1;
-        }
-        let mut out_sample = if sample_idx % 2 == 0 {
-            (data[data_offset] >> 4) as i32
-        } else {
-            let tmp = data[data_offset] & 0xF;
-            data_offset += 1;
-            tmp as i32
+    match codec {
+        Codec::Pcm8 => out_buf.extend(
+            data[..sample_count as usize]
+                .iter()
+                .map(|b| i16::from(*b as i8) << 8),
+        ),
+        Codec::Pcm16 => out_buf.extend(
+            data[..sample_count as usize * 2]
+                .chunks_exact(2)
+                .map(|b| i16::from_be_bytes([b[0], b[1]])),
+        ),
+        Codec::Adpcm => decode(
+            data,
+            sample_count,
+            coeffs,
+            &mut History::new(yn1, yn2),
+            out_buf,
+        ),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::Cursor;
+
+    use crate::{
+        decoder::{BrstmStreamDecoder, LoopPlayback},
+        encoder::encode_brstm,
+        structs::Channels,
+        BrstmInfoWithData, BrstmInformation, Error,
+    };
+
+    #[test]
+    pub fn write_derives_block_layout() {
+        let channels = vec![
+            (0..30_000)
+                .map(|i| (i % 300) as i16 * 50)
+                .collect::<Vec<_>>();
+            2
+        ];
+        let mut brstm = encode_brstm(&channels, 32000, Some(10)).unwrap();
+        let expected = brstm.info.info.clone();
+        let expected_pcm = brstm.get_pcm(1);
+        brstm.info.info.total_blocks = 7;
+        brstm.info.info.final_block_samples = 1;
+        brstm.info.info.final_block_size_padded = 0;
+        brstm.info.info.audio_offset = 0;
+        // trailing bytes are not part of any block
+        brstm.data_bytes.extend_from_slice(&[0; 0x40]);
+
+        let mut written = Cursor::new(Vec::new());
+        brstm.write_brstm(&mut written).unwrap();
+        written.set_position(0);
+        let read = BrstmInformation::from_reader(&mut written)
+            .unwrap()
+            .into_with_data(&mut written)
+            .unwrap();
+        let head1 = &read.info.info;
+        assert_eq!(head1.total_blocks, expected.total_blocks);
+        assert_eq!(head1.final_block_samples, expected.final_block_samples);
+        assert_eq!(head1.final_block_size, expected.final_block_size);
+        assert_eq!(
+            head1.final_block_size_padded,
+            expected.final_block_size_padded
+        );
+        assert_eq!(head1.audio_offset, read.info.data_offset);
+        assert_eq!(read.data_bytes.len(), head1.data_len());
+        assert_eq!(read.get_pcm(1), expected_pcm);
+
+        // too little data for the sample count
+        brstm.info.info.total_samples += 100_000;
+        assert!(brstm.write_brstm(&mut Cursor::new(Vec::new())).is_err());
+    }
+
+    #[test]
+    pub fn write_moves_final_blocks() {
+        let channels: Vec<Vec<i16>> = (0..2)
+            .map(|c| {
+                (0..20_000)
+                    .map(|i| ((i * (c + 3)) % 400) as i16 * 40)
+                    .collect()
+            })
+            .collect();
+        let brstm = encode_brstm(&channels, 32000, None).unwrap();
+        let head1 = brstm.info.info.clone();
+        let final_start = head1.block_data_offset(0, head1.total_blocks - 1);
+        let padded = head1.final_block_size_padded as usize;
+        // more padding, and none at all, which the header allows as long as the samples fit
+        for stride in [padded + 0x40, head1.final_block_size as usize] {
+            let mut moved = BrstmInfoWithData {
+                info: brstm.info.clone(),
+                adpcm_bytes: brstm.adpcm_bytes.clone(),
+                data_bytes: brstm.data_bytes[..final_start].to_vec(),
+            };
+            for block in brstm.data_bytes[final_start..].chunks_exact(padded) {
+                moved
+                    .data_bytes
+                    .extend_from_slice(&block[..padded.min(stride)]);
+                moved
+                    .data_bytes
+                    .resize(moved.data_bytes.len() + stride.saturating_sub(padded), 0);
+            }
+            moved.info.info.final_block_size_padded = stride as u32;
+            moved.check_layout().unwrap();
+
+            let mut written = Cursor::new(Vec::new());
+            moved.write_brstm(&mut written).unwrap();
+            written.set_position(0);
+            let read = BrstmInformation::from_reader(&mut written)
+                .unwrap()
+                .into_with_data(&mut written)
+                .unwrap();
+            assert_eq!(read.info.info.final_block_size_padded, padded as u32);
+            assert_eq!(read.data_bytes, brstm.data_bytes);
+            assert_eq!(read.get_pcm(1), brstm.get_pcm(1));
+        }
+    }
+
+    #[test]
+    pub fn lossless_roundtrip() {
+        let channels = vec![
+            (0..20_000)
+                .map(|i| (i % 300) as i16 * 50)
+                .collect::<Vec<_>>();
+            2
+        ];
+        let brstm = encode_brstm(&channels, 32000, Some(10)).unwrap();
+        let mut written = Cursor::new(Vec::new());
+        brstm.write_brstm(&mut written).unwrap();
+        let mut file = written.into_inner();
+        // things the normal writer doesn't reproduce: garbage in the header padding,
+        // a bigger ADPC section and trailing bytes
+        file[0x28..0x40].fill(0xAB);
+        let adpc_size = u32::from_be_bytes(file[0x1C..0x20].try_into().unwrap());
+        file[0x1C..0x20].copy_from_slice(&(adpc_size - 4).to_be_bytes());
+        file.extend_from_slice(b"trailing");
+
+        let read = |file: &[u8]| {
+            let mut cursor = Cursor::new(file);
+            BrstmInformation::from_reader_lossless(&mut cursor)
+                .unwrap()
+                .into_with_data(&mut cursor)
+                .unwrap()
         };
-        if out_sample >= 8 {
-            out_sample -= 16;
-        }
-        let scale = 1 << (cps & 0xF);
-        let c_index = (cps >> 4) << 1;
-        out_sample = (0x400
-            + ((scale * out_sample) << 11)
-            + coeffs[c_index.clamp(0, 15) as usize] as i32 * cyn1 as i32
-            + coeffs[(c_index + 1).clamp(0, 15) as usize] as i32 * cyn2 as i32)
-            >> 11;
-
-        cyn2 = cyn1;
-        cyn1 = out_sample.clamp(i16::MIN as i32, i16::MAX as i32) as i16;
-        out_buf.push(cyn1);
+        // only kept when asked for
+        let plain = BrstmInformation::from_reader(&mut Cursor::new(&file)).unwrap();
+        assert!(plain.original.is_none());
+        assert!(!plain.is_unmodified());
+
+        let mut parsed = read(&file);
+        assert!(parsed.info.is_unmodified());
+        let mut lossless = Cursor::new(Vec::new());
+        parsed.write_brstm_lossless(&mut lossless).unwrap();
+        assert_eq!(lossless.get_ref(), &file);
+        let mut normalized = Cursor::new(Vec::new());
+        parsed.write_brstm(&mut normalized).unwrap();
+        assert_ne!(normalized.get_ref(), &file);
+
+        // modified audio data of the same length keeps the layout
+        parsed.data_bytes[100] ^= 0xFF;
+        let mut lossless = Cursor::new(Vec::new());
+        parsed.write_brstm_lossless(&mut lossless).unwrap();
+        assert_eq!(lossless.get_ref().len(), file.len());
+        assert_eq!(read(lossless.get_ref()).data_bytes, parsed.data_bytes);
+
+        // modified information falls back to the normal writer
+        parsed.info.info.sample_rate = 48000;
+        assert!(!parsed.info.is_unmodified());
+        let mut lossless = Cursor::new(Vec::new());
+        parsed.write_brstm_lossless(&mut lossless).unwrap();
+        assert_eq!(read(lossless.get_ref()).info.info.sample_rate, 48000);
+        assert_eq!(&lossless.get_ref()[0x28..0x40], &[0; 0x18]);
+    }
+
+    #[test]
+    pub fn error_kinds() {
+        let channels = vec![vec![0; 20_000]; 2];
+        let mut brstm = encode_brstm(&channels, 32000, None).unwrap();
+        let mut written = Cursor::new(Vec::new());
+        brstm.write_brstm(&mut written).unwrap();
+        let file = written.into_inner();
+        let read_u32 = |off: usize| u32::from_be_bytes(file[off..][..4].try_into().unwrap());
+        let head_offset = read_u32(0x10) as usize;
+        let head1_off = head_offset + 8 + read_u32(head_offset + 0xC) as usize;
+        let read = |file: &[u8]| BrstmInformation::from_reader(&mut Cursor::new(file));
+
+        let mut bad_magic = file.clone();
+        bad_magic[..4].copy_from_slice(b"CSTM");
+        assert!(matches!(read(&bad_magic), Err(Error::BadMagic { pos: 0 })));
+
+        let mut bad_version = file.clone();
+        bad_version[6] = 2;
+        assert!(matches!(
+            read(&bad_version),
+            Err(Error::UnsupportedVersion {
+                pos: 6,
+                version: 0x0200
+            })
+        ));
+
+        let mut bad_codec = file.clone();
+        bad_codec[head1_off] = 5;
+        assert!(matches!(
+            read(&bad_codec),
+            Err(Error::UnsupportedCodec { pos, codec: 5 }) if pos == head1_off as u64
+        ));
+
+        let mut bad_layout = file.clone();
+        // blocks_samples in Head1
+        bad_layout[head1_off + 0x1C..][..4].copy_from_slice(&1u32.to_be_bytes());
+        assert!(matches!(
+            read(&bad_layout),
+            Err(Error::InvalidLayout { pos, .. }) if pos == head1_off as u64
+        ));
+        // still loads without the check
+        let unchecked =
+            BrstmInformation::from_reader_unchecked(&mut Cursor::new(&bad_layout)).unwrap();
+        assert_eq!(unchecked.info.blocks_samples, 1);
+
+        assert!(matches!(read(&file[..0x30]), Err(Error::Io(_))));
+
+        brstm.info.tracks[0].channels = Channels::Stereo(0, 2);
+        assert!(matches!(
+            brstm.write_brstm(&mut Cursor::new(Vec::new())),
+            Err(Error::InvalidChannelReference {
+                track: 0,
+                channel: 2,
+                channel_count: 2
+            })
+        ));
+    }
+
+    #[test]
+    pub fn untrusted_input_never_panics() {
+        let channels = vec![
+            (0..20_000)
+                .map(|i| (i * 37 % 2000) as i16)
+                .collect::<Vec<_>>();
+            2
+        ];
+        let brstm = encode_brstm(&channels, 32000, Some(100)).unwrap();
+        let mut written = Cursor::new(Vec::new());
+        brstm.write_brstm(&mut written).unwrap();
+        let file = written.into_inner();
+
+        let parse = |file: &[u8]| {
+            // the validator is meant for broken files as well
+            crate::validate::validate(file);
+            if let Ok(mut decoder) = BrstmStreamDecoder::new(Cursor::new(file)) {
+                while let Ok(Some(_)) = decoder.next_block() {}
+                let _ = decoder.seek_sample(decoder.info().info.total_samples / 2);
+            }
+            let mut cursor = Cursor::new(file);
+            if let Ok(mut decoder) = BrstmInformation::from_reader_unchecked(&mut cursor)
+                .and_then(|i| BrstmStreamDecoder::from_info(i, Cursor::new(file)))
+            {
+                let _ = decoder.seek_sample(1);
+                while let Ok(Some(_)) = decoder.next_block() {}
+            }
+            let mut cursor = Cursor::new(file);
+            if let Ok(brstm) = BrstmInformation::from_reader_unchecked(&mut cursor)
+                .and_then(|i| i.into_with_data_unchecked(&mut cursor))
+            {
+                for channel in 0..brstm.info.channel_count() {
+                    let _ = brstm.try_get_pcm(channel);
+                }
+                if let Ok(playback) = LoopPlayback::new(&brstm, Some(1)) {
+                    playback.for_each(drop);
+                }
+            }
+            let mut cursor = Cursor::new(file);
+            let Ok(brstm) = BrstmInformation::from_reader(&mut cursor)
+                .and_then(|i| i.into_with_data(&mut cursor))
+            else {
+                return;
+            };
+            for channel in 0..brstm.info.channel_count() {
+                brstm.get_pcm(channel);
+            }
+        };
+
+        // every truncation of the headers, then some of the audio data
+        for len in (0..0x200).chain((0x200..file.len()).step_by(97)) {
+            parse(&file[..len]);
+        }
+        // random bytes changed, mostly in the headers where they matter
+        let mut state = 0x2545_F491_4F6C_DD1Du64;
+        let mut next = move || {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state
+        };
+        for _ in 0..2000 {
+            let mut mutated = file.clone();
+            for _ in 0..next() % 4 + 1 {
+                let range = if next() % 8 == 0 { file.len() } else { 0x180 };
+                let pos = (next() % range as u64) as usize;
+                mutated[pos] = next() as u8;
+            }
+            parse(&mutated);
+        }
+        // channel counts that don't match the channel table, with sections anywhere
+        let head1_off =
+            0x40 + 8 + u32::from_be_bytes(file[0x4C..0x50].try_into().unwrap()) as usize;
+        for num_channels in [0u8, 1, 3, 0xFF] {
+            for offset in [0, 0x20, file.len() as u32 - 0x10, 0x7FFF_0000, u32::MAX] {
+                // ADPC offset, DATA offset and the audio offset in HEAD
+                for offset_pos in [0x18, 0x20, head1_off + 0x10] {
+                    let mut mutated = file.clone();
+                    mutated[head1_off + 0x02] = num_channels;
+                    mutated[offset_pos..][..4].copy_from_slice(&offset.to_be_bytes());
+                    parse(&mutated);
+                }
+            }
+        }
+        // every field of the block layout
+        for field in (0x0C..0x30).step_by(4) {
+            for value in [0, 1, 0x20, u32::MAX] {
+                let mut mutated = file.clone();
+                mutated[head1_off + field..][..4].copy_from_slice(&value.to_be_bytes());
+                parse(&mutated);
+            }
+        }
+        // garbage after a valid magic
+        for _ in 0..200 {
+            let mut garbage: Vec<u8> = (0..0x400).map(|_| next() as u8).collect();
+            garbage[..8].copy_from_slice(&file[..8]);
+            parse(&garbage);
+        }
+    }
+
+    #[test]
+    pub fn checked_accessors() {
+        let channels = vec![vec![100; 20_000]; 2];
+        let mut brstm = encode_brstm(&channels, 32000, None).unwrap();
+        let total_blocks = brstm.info.info.total_blocks;
+        assert!(brstm.check_layout().is_ok());
+        assert_eq!(brstm.try_get_pcm(1).unwrap(), brstm.get_pcm(1));
+        assert_eq!(
+            brstm.try_get_adpc_values(1, 1).unwrap(),
+            brstm.get_adpc_values(1, 1)
+        );
+        assert!(matches!(
+            brstm.try_get_pcm(2),
+            Err(Error::ChannelOutOfRange {
+                channel: 2,
+                channel_count: 2
+            })
+        ));
+        assert!(matches!(
+            brstm.try_get_data_block(0, total_blocks),
+            Err(Error::BlockOutOfRange { block, .. }) if block == total_blocks
+        ));
+
+        brstm.data_bytes.truncate(100);
+        assert!(matches!(
+            brstm.check_layout(),
+            Err(Error::InconsistentData(_))
+        ));
+        assert!(brstm.try_get_data_block(1, 0).is_err());
+        assert!(brstm.try_get_pcm(0).is_err());
+        brstm.adpcm_bytes.clear();
+        assert!(brstm.try_get_adpc_bytes(0, 0).is_err());
+    }
+
+    #[test]
+    pub fn sequential_write() {
+        let channels = vec![(0..30_000).map(|i| (i % 200) as i16 * 30).collect(); 4];
+        let brstm = encode_brstm(&channels, 44100, Some(1000)).unwrap();
+        // a Vec can't seek
+        let mut sequential = Vec::new();
+        brstm.write_brstm_sequential(&mut sequential).unwrap();
+        // leftover bytes in the gaps have to be overwritten
+        let mut seekable = Cursor::new(vec![0xFF; sequential.len()]);
+        brstm.write_brstm(&mut seekable).unwrap();
+        assert_eq!(seekable.into_inner(), sequential);
+        let reread = BrstmInformation::from_reader(&mut Cursor::new(&sequential))
+            .unwrap()
+            .into_with_data(&mut Cursor::new(&sequential))
+            .unwrap();
+        assert_eq!(reread.get_pcm(3), brstm.get_pcm(3));
     }
 }
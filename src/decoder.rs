@@ -0,0 +1,502 @@
+use std::io::{self, Read, Seek, SeekFrom};
+
+use crate::{
+    adpcm::decode_adpcm_samples, brstm::decode_block, structs::Codec, BrstmInfoWithData,
+    BrstmInformation, Error,
+};
+
+/// Decodes a BRSTM block by block straight from a reader, instead of reading all the data into
+/// memory first like [`BrstmInformation::into_with_data`].
+/// Only the current block of every channel is kept in memory, the start of each block is
+/// decoded using the history from the ADPC section
+pub struct BrstmStreamDecoder<R> {
+    reader: R,
+    info: BrstmInformation,
+    next_block: u32,
+    // raw bytes of the current block of all channels
+    block_bytes: Vec<u8>,
+    adpc_bytes: Vec<u8>,
+    // decoded samples of the current block, per channel
+    samples: Vec<Vec<i16>>,
+    // position in the decoded samples, for reading interleaved frames
+    sample_pos: usize,
+}
+
+impl<R: Read + Seek> BrstmStreamDecoder<R> {
+    pub fn new(mut reader: R) -> Result<Self, Error> {
+        let info = BrstmInformation::from_reader(&mut reader)?;
+        Self::from_info(info, reader)
+    }
+
+    /// uses already read information, the reader has to be for the same file
+    pub fn from_info(info: BrstmInformation, reader: R) -> Result<Self, Error> {
+        let head1 = &info.info;
+        if head1.num_channels as usize != info.channels.len() {
+            return Err(Error::ChannelCountMismatch {
+                expected: head1.num_channels,
+                found: info.channels.len(),
+            });
+        }
+        // unchecked information can have any block layout
+        head1.validate().map_err(Error::InconsistentData)?;
+        if head1.codec == Codec::Adpcm {
+            let needed = head1.total_blocks as u64 * head1.num_channels as u64 * 4;
+            if head1.adpc_bytes_per_entry != 4 || (info.adpcm_size as u64) < needed {
+                return Err(Error::InvalidLayout {
+                    pos: info.adpcm_offset.into(),
+                    message: format!(
+                        "ADPC section too small, {} bytes but needs {needed}",
+                        info.adpcm_size
+                    ),
+                });
+            }
+        }
+        let samples = vec![Vec::new(); info.channels.len()];
+        Ok(BrstmStreamDecoder {
+            reader,
+            info,
+            next_block: 0,
+            block_bytes: Vec::new(),
+            adpc_bytes: Vec::new(),
+            samples,
+            sample_pos: 0,
+        })
+    }
+
+    pub fn info(&self) -> &BrstmInformation {
+        &self.info
+    }
+
+    pub fn into_inner(self) -> R {
+        self.reader
+    }
+
+    /// the index of the block that is decoded with the next call to [`Self::next_block`]
+    pub fn next_block_index(&self) -> u32 {
+        self.next_block
+    }
+
+    /// the next call to [`Self::next_block`] decodes the specified block, any samples of the
+    /// current block that haven't been read yet are dropped
+    pub fn seek_block(&mut self, block_index: u32) {
+        self.next_block = block_index.min(self.info.info.total_blocks);
+        for channel in self.samples.iter_mut() {
+            channel.clear();
+        }
+        self.sample_pos = 0;
+    }
+
+    /// continues decoding at the specified sample, the containing block is decoded right away
+    /// and interleaved reads start at the sample
+    pub fn seek_sample(&mut self, sample: u32) -> io::Result<()> {
+        let head1 = &self.info.info;
+        if sample >= head1.total_samples {
+            self.seek_block(head1.total_blocks);
+            return Ok(());
+        }
+        let block_index = sample / head1.blocks_samples;
+        let block_start = block_index * head1.blocks_samples;
+        self.seek_block(block_index);
+        self.next_block()?;
+        self.sample_pos = (sample - block_start) as usize;
+        Ok(())
+    }
+
+    /// decodes the next block, returns the samples of each channel
+    /// or None if all blocks have been decoded
+    pub fn next_block(&mut self) -> io::Result<Option<&[Vec<i16>]>> {
+        let head1 = &self.info.info;
+        let block_index = self.next_block;
+        if block_index >= head1.total_blocks {
+            return Ok(None);
+        }
+        let channel_count = self.info.channels.len();
+        if head1.codec == Codec::Adpcm {
+            // history of all channels for this block
+            let adpc_offset =
+                self.info.adpcm_offset as u64 + block_index as u64 * channel_count as u64 * 4;
+            self.adpc_bytes.resize(channel_count * 4, 0);
+            self.reader.seek(SeekFrom::Start(adpc_offset))?;
+            self.reader.read_exact(&mut self.adpc_bytes)?;
+        }
+        // all channels of a block are right after each other
+        let block_len = head1.block_byte_len(block_index) as usize;
+        self.reader.seek(SeekFrom::Start(
+            self.info.data_offset as u64 + head1.block_data_offset(0, block_index) as u64,
+        ))?;
+        // the block size comes from the header, so only allocate as much as the file has
+        let needed = block_len * channel_count;
+        self.block_bytes.clear();
+        (&mut self.reader)
+            .take(needed as u64)
+            .read_to_end(&mut self.block_bytes)?;
+        if self.block_bytes.len() < needed {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                format!("Block {block_index} is cut off"),
+            ));
+        }
+
+        let sample_count = head1.block_samples(block_index);
+        for (channel, out) in self.samples.iter_mut().enumerate() {
+            out.clear();
+            let (yn1, yn2) = match self.adpc_bytes.get(channel * 4..channel * 4 + 4) {
+                Some(b) if head1.codec == Codec::Adpcm => (
+                    i16::from_be_bytes([b[0], b[1]]),
+                    i16::from_be_bytes([b[2], b[3]]),
+                ),
+                _ => (0, 0),
+            };
+            decode_block(
+                head1.codec,
+                &self.block_bytes[channel * block_len..][..block_len],
+                sample_count,
+                yn1,
+                yn2,
+                &self.info.channels[channel].adpcm_coefficients,
+                out,
+            );
+        }
+        self.next_block += 1;
+        self.sample_pos = 0;
+        Ok(Some(&self.samples))
+    }
+
+    /// fills the buffer with interleaved samples of all channels, decoding new blocks as needed,
+    /// returns the number of complete frames (one sample per channel) that were written,
+    /// which is only 0 at the end of the stream
+    pub fn read_interleaved(&mut self, out: &mut [i16]) -> io::Result<usize> {
+        let channel_count = self.samples.len();
+        if channel_count == 0 {
+            return Ok(0);
+        }
+        let frame_capacity = out.len() / channel_count;
+        let mut frames_written = 0;
+        while frames_written < frame_capacity {
+            let available = self.samples[0].len() - self.sample_pos;
+            if available == 0 {
+                if self.next_block()?.is_none() {
+                    break;
+                }
+                continue;
+            }
+            let count = available.min(frame_capacity - frames_written);
+            let out_frames = &mut out[frames_written * channel_count..][..count * channel_count];
+            for (channel_idx, channel) in self.samples.iter().enumerate() {
+                for (frame, sample) in out_frames
+                    .chunks_exact_mut(channel_count)
+                    .zip(&channel[self.sample_pos..][..count])
+                {
+                    frame[channel_idx] = *sample;
+                }
+            }
+            self.sample_pos += count;
+            frames_written += count;
+        }
+        Ok(frames_written)
+    }
+}
+
+/// a chunk of samples of all channels, as returned by [`LoopPlayback`]
+#[derive(Debug, Clone)]
+pub struct PlaybackChunk {
+    /// position of the first sample of the chunk in the stream
+    pub start: u32,
+    /// if this is the first chunk after jumping back to the loop start
+    pub after_loop: bool,
+    pub samples: Vec<Vec<i16>>,
+}
+
+/// Decodes a BRSTM the way the game plays it: the channels are decoded continuously from the
+/// start using the initial context, at the end playback jumps back to the loop start and continues
+/// with the loop context (`loop_predictor`, `loop_history_sample1/2`), instead of using the history
+/// from the ADPC section. Each chunk is at most one block long
+pub struct LoopPlayback<'a> {
+    brstm: &'a BrstmInfoWithData,
+    // predictor/scale and history of each channel
+    states: Vec<(u8, i16, i16)>,
+    position: u32,
+    after_loop: bool,
+    // None loops forever
+    loops_left: Option<u32>,
+}
+
+impl<'a> LoopPlayback<'a> {
+    /// plays the stream and then loops `loops` times, or forever if it's None.
+    /// Streams that don't loop end after the first playthrough. Fails if the block layout
+    /// doesn't fit the data, see [`BrstmInfoWithData::check_layout`]
+    pub fn new(brstm: &'a BrstmInfoWithData, loops: Option<u32>) -> Result<Self, Error> {
+        brstm.check_layout()?;
+        let states = brstm
+            .info
+            .channels
+            .iter()
+            .map(|c| {
+                (
+                    c.initial_predictor as u8,
+                    c.history_sample1,
+                    c.history_sample2,
+                )
+            })
+            .collect();
+        Ok(LoopPlayback {
+            brstm,
+            states,
+            position: 0,
+            after_loop: false,
+            loops_left: loops,
+        })
+    }
+}
+
+impl Iterator for LoopPlayback<'_> {
+    type Item = PlaybackChunk;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let info = &self.brstm.info;
+        let head1 = &info.info;
+        self.after_loop = false;
+        if self.position >= head1.total_samples {
+            if !head1.is_looping() || head1.loop_start >= head1.total_samples {
+                return None;
+            }
+            match &mut self.loops_left {
+                Some(0) => return None,
+                Some(loops_left) => *loops_left -= 1,
+                None => (),
+            }
+            self.position = head1.loop_start;
+            self.after_loop = true;
+            for (state, channel) in self.states.iter_mut().zip(&info.channels) {
+                *state = (
+                    channel.loop_predictor as u8,
+                    channel.loop_history_sample1,
+                    channel.loop_history_sample2,
+                );
+            }
+        }
+        let start = self.position;
+        let block_index = start / head1.blocks_samples;
+        let block_start = block_index * head1.blocks_samples;
+        let block_samples = head1.block_samples(block_index);
+        let mut samples = Vec::with_capacity(self.states.len());
+        for (channel, state) in self.states.iter_mut().enumerate() {
+            let data = self.brstm.get_data_block(channel as u8, block_index);
+            let mut out = Vec::with_capacity((block_samples + block_start - start) as usize);
+            let range = start - block_start..block_samples;
+            if head1.codec == Codec::Adpcm {
+                let (ps, yn1, yn2) = *state;
+                *state = decode_adpcm_samples(
+                    data,
+                    range,
+                    ps,
+                    yn1,
+                    yn2,
+                    &info.channels[channel].adpcm_coefficients,
+                    &mut out,
+                );
+            } else {
+                decode_block(head1.codec, data, block_samples, 0, 0, &[0; 16], &mut out);
+                out.drain(..range.start as usize);
+            }
+            samples.push(out);
+        }
+        self.position = block_start + block_samples;
+        Some(PlaybackChunk {
+            start,
+            after_loop: self.after_loop,
+            samples,
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::Cursor;
+
+    use crate::{encoder::encode_brstm, BrstmInfoWithData, BrstmInformation, Error};
+
+    use super::{BrstmStreamDecoder, LoopPlayback, PlaybackChunk};
+
+    #[test]
+    pub fn stream_matches_full_decode() {
+        let channels: Vec<Vec<i16>> = (0..4)
+            .map(|c| {
+                (0..40_000)
+                    .map(|i| ((i as f32 / (10.0 + c as f32)).sin() * 10_000.0) as i16)
+                    .collect()
+            })
+            .collect();
+        let brstm = encode_brstm(&channels, 32000, Some(500)).unwrap();
+        let mut file = Cursor::new(Vec::new());
+        brstm.write_brstm(&mut file).unwrap();
+        file.set_position(0);
+        let expected: Vec<Vec<i16>> = (0..4).map(|c| brstm.get_pcm(c)).collect();
+
+        let mut decoder = BrstmStreamDecoder::new(file.clone()).unwrap();
+        let mut per_channel = vec![Vec::new(); 4];
+        while let Some(block) = decoder.next_block().unwrap() {
+            for (out, samples) in per_channel.iter_mut().zip(block) {
+                out.extend_from_slice(samples);
+            }
+        }
+        assert_eq!(expected, per_channel);
+
+        // odd buffer size so that reads cross block boundaries
+        let mut decoder = BrstmStreamDecoder::new(file).unwrap();
+        let mut buf = vec![0; 4 * 1001 + 3];
+        let mut interleaved = Vec::new();
+        loop {
+            let frames = decoder.read_interleaved(&mut buf).unwrap();
+            if frames == 0 {
+                break;
+            }
+            interleaved.extend_from_slice(&buf[..frames * 4]);
+        }
+        assert_eq!(interleaved.len(), 4 * 40_000);
+        for (i, frame) in interleaved.chunks_exact(4).enumerate() {
+            for c in 0..4 {
+                assert_eq!(frame[c], expected[c][i]);
+            }
+        }
+
+        // seeking to the last block continues with the right history
+        decoder.seek_block(decoder.info().info.total_blocks - 1);
+        let last = decoder.next_block().unwrap().unwrap();
+        let last_len = last[1].len();
+        assert_eq!(last[1][..], expected[1][40_000 - last_len..]);
+    }
+
+    #[test]
+    pub fn sample_ranges() {
+        let channels: Vec<Vec<i16>> = (0..2)
+            .map(|c| {
+                (0..30_000)
+                    .map(|i| ((i as f32 / (7.0 + c as f32)).sin() * 12_000.0) as i16)
+                    .collect()
+            })
+            .collect();
+        let brstm = encode_brstm(&channels, 32000, None).unwrap();
+        let full: Vec<Vec<i16>> = (0..2).map(|c| brstm.get_pcm(c)).collect();
+        let blocks_samples = brstm.info.info.blocks_samples;
+        for (start, end) in [
+            (0, 1),
+            (5, 100),
+            (blocks_samples - 3, blocks_samples + 3),
+            (blocks_samples, 2 * blocks_samples),
+            (100, 29_990),
+            (29_000, 40_000),
+        ] {
+            let range = brstm.get_pcm_range_all(start, end);
+            let end = end.min(30_000) as usize;
+            assert_eq!(range[0], full[0][start as usize..end]);
+            assert_eq!(range[1], full[1][start as usize..end]);
+        }
+        assert!(brstm.get_pcm_range(0, 100, 100).is_empty());
+        assert!(brstm.get_pcm_range(0, 30_000, 30_001).is_empty());
+
+        let mut file = Cursor::new(Vec::new());
+        brstm.write_brstm(&mut file).unwrap();
+        file.set_position(0);
+        let mut decoder = BrstmStreamDecoder::new(file).unwrap();
+        let start = blocks_samples + 1234;
+        decoder.seek_sample(start).unwrap();
+        let mut buf = [0; 2 * 10];
+        assert_eq!(decoder.read_interleaved(&mut buf).unwrap(), 10);
+        for i in 0..10 {
+            assert_eq!(buf[i * 2], full[0][start as usize + i]);
+            assert_eq!(buf[i * 2 + 1], full[1][start as usize + i]);
+        }
+
+        // a broken block layout is an error instead of a division by zero when seeking
+        let mut file = decoder.into_inner();
+        file.set_position(0);
+        let mut info = BrstmInformation::from_reader(&mut file).unwrap();
+        info.info.blocks_samples = 0;
+        assert!(matches!(
+            BrstmStreamDecoder::from_info(info, file),
+            Err(Error::InconsistentData(_))
+        ));
+    }
+
+    #[test]
+    pub fn loop_playback() {
+        let channels: Vec<Vec<i16>> = (0..2)
+            .map(|c| {
+                (0..35_000)
+                    .map(|i| ((i as f32 / (9.0 + c as f32)).sin() * 9_000.0) as i16)
+                    .collect()
+            })
+            .collect();
+        // not on a frame boundary
+        let loop_start = 20_000 + 5;
+        let brstm = encode_brstm(&channels, 32000, Some(loop_start)).unwrap();
+
+        // continuous decoding of the first playthrough
+        let first_pass: Vec<PlaybackChunk> = LoopPlayback::new(&brstm, Some(0)).unwrap().collect();
+        assert!(first_pass.iter().all(|c| !c.after_loop));
+        let continuous: Vec<Vec<i16>> = (0..2)
+            .map(|c| {
+                first_pass
+                    .iter()
+                    .flat_map(|chunk| chunk.samples[c].iter().copied())
+                    .collect()
+            })
+            .collect();
+        assert_eq!(continuous[0].len(), 35_000);
+
+        // the ADPC section and the loop context agree with the continuous decoding
+        let head1 = &brstm.info.info;
+        let block = loop_start / head1.blocks_samples;
+        let frame = (loop_start - block * head1.blocks_samples) as usize / 14;
+        for (c, samples) in continuous.iter().enumerate() {
+            let channel = &brstm.info.channels[c];
+            let header = brstm.get_data_block(c as u8, block)[frame * 8];
+            assert_eq!(channel.loop_predictor, header.into());
+            assert_eq!(
+                channel.loop_history_sample1,
+                samples[loop_start as usize - 1]
+            );
+            assert_eq!(
+                channel.loop_history_sample2,
+                samples[loop_start as usize - 2]
+            );
+        }
+        assert_eq!(brstm.get_pcm(0), continuous[0]);
+        assert_eq!(brstm.get_pcm(1), continuous[1]);
+
+        let played: Vec<PlaybackChunk> = LoopPlayback::new(&brstm, Some(2)).unwrap().collect();
+        assert_eq!(played.iter().filter(|c| c.after_loop).count(), 2);
+        for chunk in played.iter().filter(|c| c.after_loop) {
+            assert_eq!(chunk.start, loop_start);
+        }
+        for (c, samples) in continuous.iter().enumerate() {
+            let played: Vec<i16> = played
+                .iter()
+                .flat_map(|chunk| chunk.samples[c].iter().copied())
+                .collect();
+            let loop_len = 35_000 - loop_start as usize;
+            assert_eq!(played.len(), 35_000 + 2 * loop_len);
+            // the seam is exactly the loop start
+            assert_eq!(played[35_000..][..loop_len], samples[loop_start as usize..]);
+            assert_eq!(played[35_000 + loop_len..], samples[loop_start as usize..]);
+        }
+
+        // looping forever
+        assert_eq!(
+            LoopPlayback::new(&brstm, None).unwrap().take(100).count(),
+            100
+        );
+
+        // truncated data fails up front instead of in the middle of playback
+        let truncated = BrstmInfoWithData {
+            info: brstm.info.clone(),
+            adpcm_bytes: brstm.adpcm_bytes.clone(),
+            data_bytes: brstm.data_bytes[..0x100].to_vec(),
+        };
+        assert!(matches!(
+            LoopPlayback::new(&truncated, None),
+            Err(Error::InconsistentData(_))
+        ));
+    }
+}
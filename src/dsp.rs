@@ -0,0 +1,401 @@
+use std::io::{self, Read, Seek, Write};
+
+use binrw::{binrw, BinReaderExt, BinWriterExt};
+use thiserror::Error;
+
+use crate::{
+    adpcm::{decode, History, PACKET_BYTES, PACKET_SAMPLES},
+    brstm::align_next_32,
+    structs::{AdpcmChannelInformation, Codec, Head1},
+    BrstmInfoWithData, BrstmInformation,
+};
+
+// note: standard mono DSP file as written by DSPADPCM, a 0x60 byte header followed by the ADPCM frames.
+// Positions in the header are nibble addresses, where every 8 byte frame has 16 nibbles,
+// the first two of them are the frame header.
+
+const BLOCK_SIZE: u32 = 8192;
+const BLOCK_SAMPLES: u32 = BLOCK_SIZE / PACKET_BYTES as u32 * PACKET_SAMPLES as u32;
+
+#[binrw]
+#[brw(big)]
+#[derive(Debug, Default, Clone)]
+pub struct DspHeader {
+    pub num_samples: u32,
+    pub num_nibbles: u32,
+    pub sample_rate: u32,
+    pub loop_flag: u16,
+    // 0 for ADPCM
+    pub format: u16,
+    pub loop_start_offset: u32,
+    pub loop_end_offset: u32,
+    pub current_address: u32,
+    pub adpcm_coefficients: [i16; 16],
+    pub gain: i16,
+    pub initial_predictor: i16,
+    pub history_sample1: i16,
+    pub history_sample2: i16,
+    pub loop_predictor: i16,
+    pub loop_history_sample1: i16,
+    #[brw(pad_after = 0x16)]
+    pub loop_history_sample2: i16,
+}
+
+impl DspHeader {
+    pub fn byte_len() -> u32 {
+        0x60
+    }
+}
+
+/// converts a sample index into the nibble address used by the DSP header
+pub fn sample_to_nibble(sample: u32) -> u32 {
+    let frame = sample / PACKET_SAMPLES as u32;
+    let in_frame = sample % PACKET_SAMPLES as u32;
+    frame * 16 + in_frame + 2
+}
+
+/// number of nibbles needed to store the given amount of samples, including frame headers
+pub fn nibble_count(samples: u32) -> u32 {
+    let frames = samples / PACKET_SAMPLES as u32;
+    let rem = samples % PACKET_SAMPLES as u32;
+    frames * 16 + if rem > 0 { rem + 2 } else { 0 }
+}
+
+#[derive(Error, Debug)]
+#[non_exhaustive]
+pub enum DspError {
+    #[error("Only DSP-ADPCM streams can be split, got codec {0:?}")]
+    UnsupportedCodec(Codec),
+    #[error("Only the ADPCM format is supported, got {0}")]
+    UnsupportedFormat(u16),
+    #[error("No DSP files given")]
+    EmptyChannels,
+    #[error("Too many channels: {0}, only 16 are supported")]
+    TooHighChannelCount(usize),
+    #[error("Sample rate {0} is too high for a BRSTM")]
+    SampleRateTooHigh(u32),
+    #[error("DSP file {index} doesn't match the first one: {field} differs")]
+    MismatchedChannel { index: usize, field: &'static str },
+    #[error("DSP file {index} has {len} bytes of data, but {needed} are needed")]
+    DataTooShort {
+        index: usize,
+        len: usize,
+        needed: usize,
+    },
+    #[error("Loop start {loop_start} is past the end of the {sample_count} samples")]
+    LoopStartOutOfRange { loop_start: u32, sample_count: u32 },
+}
+
+#[derive(Debug, Clone)]
+pub struct DspFile {
+    pub header: DspHeader,
+    pub data: Vec<u8>,
+}
+
+impl DspFile {
+    pub fn from_reader<RS: Read + Seek>(f: &mut RS) -> Result<Self, crate::Error> {
+        let header: DspHeader = f.read_be()?;
+        let needed = Codec::Adpcm.bytes_for_samples(header.num_samples) as usize;
+        // the sample count can't be trusted, only allocate for the data that exists
+        let mut data = Vec::new();
+        f.take(needed as u64).read_to_end(&mut data)?;
+        if data.len() < needed {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                format!("Only {} of {needed} bytes of data", data.len()),
+            )
+            .into());
+        }
+        Ok(DspFile { header, data })
+    }
+
+    pub fn write_dsp<WS: Write + Seek>(&self, ws: &mut WS) -> Result<(), crate::Error> {
+        ws.write_be(&self.header)?;
+        ws.write_all(&self.data)?;
+        ws.flush()?;
+        Ok(())
+    }
+
+    pub fn loop_start(&self) -> Option<u32> {
+        if self.header.loop_flag == 0 {
+            return None;
+        }
+        // undo the nibble addressing
+        let nibble = self.header.loop_start_offset;
+        Some(nibble / 16 * PACKET_SAMPLES as u32 + (nibble % 16).saturating_sub(2))
+    }
+
+    pub fn channel_info(&self) -> AdpcmChannelInformation {
+        AdpcmChannelInformation {
+            adpcm_coefficients: self.header.adpcm_coefficients,
+            gain: self.header.gain,
+            initial_predictor: self.header.initial_predictor,
+            history_sample1: self.header.history_sample1,
+            history_sample2: self.header.history_sample2,
+            loop_predictor: self.header.loop_predictor,
+            loop_history_sample1: self.header.loop_history_sample1,
+            loop_history_sample2: self.header.loop_history_sample2,
+            ..Default::default()
+        }
+    }
+}
+
+/// splits a BRSTM into one DSP file per channel, the ADPCM frames are copied as is
+pub fn split_brstm(brstm: &BrstmInfoWithData) -> Result<Vec<DspFile>, DspError> {
+    let head1 = &brstm.info.info;
+    if head1.codec != Codec::Adpcm {
+        return Err(DspError::UnsupportedCodec(head1.codec));
+    }
+    let needed_bytes = Codec::Adpcm.bytes_for_samples(head1.total_samples) as usize;
+    let mut files = Vec::with_capacity(brstm.info.channels.len());
+    for (channel, channel_info) in brstm.info.channels.iter().enumerate() {
+        let mut data = Vec::with_capacity(needed_bytes);
+        for block_index in 0..head1.total_blocks {
+            data.extend_from_slice(brstm.get_data_block(channel as u8, block_index));
+        }
+        // drop the padding of the final block
+        data.truncate(needed_bytes);
+        let loop_flag = head1.loop_flag != 0;
+        files.push(DspFile {
+            header: DspHeader {
+                num_samples: head1.total_samples,
+                num_nibbles: nibble_count(head1.total_samples),
+                sample_rate: head1.sample_rate.into(),
+                loop_flag: loop_flag.into(),
+                format: 0,
+                loop_start_offset: if loop_flag {
+                    sample_to_nibble(head1.loop_start)
+                } else {
+                    0
+                },
+                loop_end_offset: sample_to_nibble(head1.total_samples.saturating_sub(1)),
+                current_address: sample_to_nibble(0),
+                adpcm_coefficients: channel_info.adpcm_coefficients,
+                gain: channel_info.gain,
+                initial_predictor: channel_info.initial_predictor,
+                history_sample1: channel_info.history_sample1,
+                history_sample2: channel_info.history_sample2,
+                loop_predictor: channel_info.loop_predictor,
+                loop_history_sample1: channel_info.loop_history_sample1,
+                loop_history_sample2: channel_info.loop_history_sample2,
+            },
+            data,
+        });
+    }
+    Ok(files)
+}
+
+/// builds a BRSTM out of mono DSP files, each file becomes one channel.
+/// The ADPCM frames are only re-blocked, decoding is only done to fill in the ADPC history
+pub fn build_brstm(dsps: &[DspFile]) -> Result<BrstmInfoWithData, DspError> {
+    let first = dsps.first().ok_or(DspError::EmptyChannels)?;
+    if dsps.len() > 16 {
+        return Err(DspError::TooHighChannelCount(dsps.len()));
+    }
+    let sample_count = first.header.num_samples;
+    let needed_bytes = Codec::Adpcm.bytes_for_samples(sample_count) as usize;
+    for (index, dsp) in dsps.iter().enumerate() {
+        if dsp.header.format != 0 {
+            return Err(DspError::UnsupportedFormat(dsp.header.format));
+        }
+        let mismatched_field = if dsp.header.num_samples != sample_count {
+            Some("sample count")
+        } else if dsp.header.sample_rate != first.header.sample_rate {
+            Some("sample rate")
+        } else if dsp.loop_start() != first.loop_start() {
+            Some("loop")
+        } else {
+            None
+        };
+        if let Some(field) = mismatched_field {
+            return Err(DspError::MismatchedChannel { index, field });
+        }
+        if dsp.data.len() < needed_bytes {
+            return Err(DspError::DataTooShort {
+                index,
+                len: dsp.data.len(),
+                needed: needed_bytes,
+            });
+        }
+    }
+    if let Some(loop_start) = first.loop_start().filter(|&start| start >= sample_count) {
+        return Err(DspError::LoopStartOutOfRange {
+            loop_start,
+            sample_count,
+        });
+    }
+    let sample_rate = first
+        .header
+        .sample_rate
+        .try_into()
+        .map_err(|_| DspError::SampleRateTooHigh(first.header.sample_rate))?;
+
+    let total_blocks = sample_count.div_ceil(BLOCK_SAMPLES).max(1);
+    let final_block_samples = sample_count - (total_blocks - 1) * BLOCK_SAMPLES;
+    let final_block_size = Codec::Adpcm.bytes_for_samples(final_block_samples);
+    let final_block_size_padded = align_next_32(final_block_size);
+
+    // the history at the start of each block can only be known by decoding
+    let channel_pcm: Vec<Vec<i16>> = dsps
+        .iter()
+        .map(|dsp| {
+            let mut pcm = Vec::with_capacity(sample_count as usize);
+            decode(
+                &dsp.data,
+                sample_count,
+                &dsp.header.adpcm_coefficients,
+                &mut History::new(dsp.header.history_sample1, dsp.header.history_sample2),
+                &mut pcm,
+            );
+            pcm
+        })
+        .collect();
+
+    let mut adpcm_bytes = Vec::with_capacity(total_blocks as usize * dsps.len() * 4);
+    let mut data_bytes = Vec::new();
+    for block_index in 0..total_blocks {
+        let is_final = block_index == total_blocks - 1;
+        let block_start = (block_index * BLOCK_SIZE) as usize;
+        for (dsp, pcm) in dsps.iter().zip(channel_pcm.iter()) {
+            let (yn1, yn2) = if block_index == 0 {
+                (dsp.header.history_sample1, dsp.header.history_sample2)
+            } else {
+                let sample = (block_index * BLOCK_SAMPLES) as usize;
+                (pcm[sample - 1], pcm[sample - 2])
+            };
+            adpcm_bytes.extend_from_slice(&yn1.to_be_bytes());
+            adpcm_bytes.extend_from_slice(&yn2.to_be_bytes());
+            if is_final {
+                data_bytes.extend_from_slice(&dsp.data[block_start..needed_bytes]);
+                data_bytes.resize(
+                    data_bytes.len() + (final_block_size_padded - final_block_size) as usize,
+                    0,
+                );
+            } else {
+                data_bytes.extend_from_slice(&dsp.data[block_start..][..BLOCK_SIZE as usize]);
+            }
+        }
+    }
+
+    let loop_start = first.loop_start();
+    let mut info = BrstmInformation {
+        info: Head1 {
+            codec: Codec::Adpcm,
+            loop_flag: loop_start.is_some().into(),
+            num_channels: dsps.len() as u8,
+            sample_rate,
+            loop_start: loop_start.unwrap_or(0),
+            total_samples: sample_count,
+            total_blocks,
+            blocks_size: BLOCK_SIZE,
+            blocks_samples: BLOCK_SAMPLES,
+            final_block_size,
+            final_block_samples,
+            final_block_size_padded,
+            adpc_samples_per_entry: BLOCK_SAMPLES,
+            adpc_bytes_per_entry: 4,
+            // filled in later
+            audio_offset: 0,
+        },
+        tracks: Vec::new(),
+        channels: dsps.iter().map(DspFile::channel_info).collect(),
+        // filled in later
+        adpcm_offset: 0,
+        adpcm_size: 0,
+        data_offset: 0,
+        data_size: 0,
+        original: None,
+    };
+    // guesses stereo or mono tracks
+    info.fix_tracks();
+    Ok(BrstmInfoWithData {
+        info,
+        adpcm_bytes,
+        data_bytes,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::Cursor;
+
+    use crate::{
+        adpcm::{decode, History},
+        encoder::encode_brstm,
+        structs::Channels,
+    };
+
+    use super::{build_brstm, sample_to_nibble, split_brstm, DspError, DspFile, DspHeader};
+
+    #[test]
+    pub fn split_and_build() {
+        let channels: Vec<Vec<i16>> = (0..2)
+            .map(|c| {
+                (0..50_000)
+                    .map(|i| ((i as f64 * 0.005 * (c + 1) as f64).sin() * 12_000.0) as i16)
+                    .collect()
+            })
+            .collect();
+        let brstm = encode_brstm(&channels, 32000, Some(20_000)).unwrap();
+
+        let dsps: Vec<DspFile> = split_brstm(&brstm)
+            .unwrap()
+            .into_iter()
+            .map(|dsp| {
+                let mut buf = Vec::new();
+                dsp.write_dsp(&mut Cursor::new(&mut buf)).unwrap();
+                assert_eq!(
+                    buf.len(),
+                    DspHeader::byte_len() as usize + 50_000usize.div_ceil(14) * 8
+                );
+                DspFile::from_reader(&mut Cursor::new(&buf)).unwrap()
+            })
+            .collect();
+        assert_eq!(dsps[0].loop_start(), Some(20_000));
+
+        let rebuilt = build_brstm(&dsps).unwrap();
+        assert_eq!(brstm.data_bytes, rebuilt.data_bytes);
+        assert!(matches!(
+            rebuilt.info.tracks[0].channels,
+            Channels::Stereo(0, 1)
+        ));
+        for (channel, dsp) in dsps.iter().enumerate() {
+            let mut expected = Vec::new();
+            decode(
+                &dsp.data,
+                dsp.header.num_samples,
+                &dsp.header.adpcm_coefficients,
+                &mut History::default(),
+                &mut expected,
+            );
+            assert_eq!(expected, rebuilt.get_pcm(channel as u8));
+        }
+    }
+    #[test]
+    pub fn untrusted_header() {
+        let brstm = encode_brstm(&[vec![0; 1000]], 32000, Some(500)).unwrap();
+        let mut dsp = split_brstm(&brstm).unwrap().remove(0);
+
+        // claims way more samples than the file has
+        let mut buf = Vec::new();
+        DspFile {
+            header: DspHeader {
+                num_samples: u32::MAX,
+                ..dsp.header.clone()
+            },
+            data: dsp.data.clone(),
+        }
+        .write_dsp(&mut Cursor::new(&mut buf))
+        .unwrap();
+        assert!(DspFile::from_reader(&mut Cursor::new(&buf)).is_err());
+
+        dsp.header.loop_start_offset = sample_to_nibble(1000);
+        assert!(matches!(
+            build_brstm(&[dsp]),
+            Err(DspError::LoopStartOutOfRange {
+                loop_start: 1000,
+                sample_count: 1000
+            })
+        ));
+    }
+}
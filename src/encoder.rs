@@ -1,12 +1,18 @@
-use std::iter::repeat;
+use std::io::{self, Seek, SeekFrom, Write};
 
+pub use crate::adpcm::Quality;
 use crate::{
-    gc_dspadpcm::{dsp_correlate_coefs, dsp_encode_frame, PACKET_BYTES, PACKET_SAMPLES},
-    structs::{AdpcmChannelInformation, Channels, Head1, TrackDescription},
+    adpcm::{flatten_coefs, pair_coefs},
+    gc_dspadpcm::{
+        dsp_correlate_coefs, dsp_encode_frame, dsp_encode_frame_search, CorrelateState,
+        PACKET_BYTES, PACKET_SAMPLES,
+    },
+    structs::{AdpcmChannelInformation, Channels, Codec, Head1, TrackDescription},
     BrstmInfoWithData, BrstmInformation,
 };
 
-const BLOCK_SIZE: usize = 8192;
+/// block size of the files from the games
+pub const DEFAULT_BLOCK_SIZE: u32 = 8192;
 
 // TODO: use from std when it's stable
 pub const fn div_ceil(lhs: usize, rhs: usize) -> usize {
@@ -19,133 +25,102 @@ pub const fn div_ceil(lhs: usize, rhs: usize) -> usize {
     }
 }
 
-struct BrstmStreamEncoder<'a> {
-    // input
-    samples: &'a [i16],
-    samples_until_loop_point: Option<usize>,
+/// encodes the blocks of a single ADPCM channel, one after another. Like the reference encoder
+/// the whole channel is one continuous stream of frames, each frame is encoded with the decoded
+/// samples of the previous one as history, which is also what goes into the ADPC table and the
+/// loop context
+struct ChannelEncoder {
+    coefs: [[i16; 2]; 8],
+    loop_point: usize,
+    quality: Quality,
     // work
-    prev_samples: [i16; 2],
-    is_first: bool,
+    // the last 2 decoded samples, older one first
+    history: [i16; 2],
+    // samples encoded so far
+    position: usize,
 
     // output
-    coefs: [[i16; 2]; 8],
     initial_predictor: u8,
     loop_predictor: u8,
+    // yn1 and yn2 before the loop point
     loop_history_samples: [i16; 2],
 }
 
-impl<'a> BrstmStreamEncoder<'a> {
-    pub fn init(samples: &'a [i16], loop_point: u32) -> Self {
-        let loop_point: usize = loop_point.try_into().unwrap();
-
-        let coefs = dsp_correlate_coefs(samples);
-
-        // gracefully handle the case when the loop point is 0
-        let loop_history_samples = [
-            samples
-                .get(loop_point.wrapping_sub(2))
-                .copied()
-                .unwrap_or(0),
-            samples
-                .get(loop_point.wrapping_sub(1))
-                .copied()
-                .unwrap_or(0),
-        ];
-
+impl ChannelEncoder {
+    fn new(coefs: [[i16; 2]; 8], loop_point: u32, quality: Quality) -> Self {
         Self {
-            samples,
             coefs,
-            prev_samples: [0; 2],
-            loop_history_samples,
-            samples_until_loop_point: Some(loop_point),
-            loop_predictor: 0,
-            is_first: false,
+            loop_point: loop_point.try_into().unwrap(),
+            quality,
+            history: [0; 2],
+            position: 0,
             initial_predictor: 0,
+            loop_predictor: 0,
+            loop_history_samples: [0; 2],
         }
     }
 
-    /// returns number of writes without padding when done (it writes padding)
-    pub fn pull_chunk(
+    /// encodes the samples of the next block, which has to be full unless it's the final one.
+    /// Adds the ADPC entry of the block and the frames without any padding
+    fn encode_block(
         &mut self,
+        samples: &[i16],
         adpcm_bytes: &mut Vec<u8>,
         data_bytes: &mut Vec<u8>,
-    ) -> Option<(usize, usize)> {
-        // we have 8192 bytes to fill
-
+    ) {
         let mut conv_samps = [0i16; 16];
-        conv_samps[0] = self.prev_samples[0];
-        conv_samps[1] = self.prev_samples[1];
-        
-        adpcm_bytes.extend_from_slice(&self.prev_samples[0].to_be_bytes());
-        adpcm_bytes.extend_from_slice(&self.prev_samples[1].to_be_bytes());
-        self.prev_samples[0] = self.samples.get(BLOCK_SIZE / PACKET_BYTES * PACKET_SAMPLES - 2).copied().unwrap_or(0);
-        self.prev_samples[1] = self.samples.get(BLOCK_SIZE / PACKET_BYTES * PACKET_SAMPLES - 1).copied().unwrap_or(0);
+        conv_samps[..2].copy_from_slice(&self.history);
+
+        // yn1, yn2
+        adpcm_bytes.extend_from_slice(&self.history[1].to_be_bytes());
+        adpcm_bytes.extend_from_slice(&self.history[0].to_be_bytes());
+
         // each packet is 8 bytes
-        for p in 0..BLOCK_SIZE / PACKET_BYTES {
-            // let num_samples = self.samples.len().min(PACKET_SAMPLES);
+        for (p, packet) in samples.chunks(PACKET_SAMPLES).enumerate() {
             // the first 2 samples are from the previous packet, if the stream ends first the rest is zero filled
-            for (conv_samp, samp) in conv_samps
-                .iter_mut()
-                .skip(2)
-                .zip(self.samples.iter().chain(repeat(&0)))
-            {
-                *conv_samp = *samp;
-            }
+            conv_samps[2..][..packet.len()].copy_from_slice(packet);
+            conv_samps[2 + packet.len()..].fill(0);
 
-            let block = dsp_encode_frame(&mut conv_samps, PACKET_SAMPLES, &self.coefs);
+            // replaces the samples with the decoded ones
+            let block = match self.quality {
+                Quality::Standard => dsp_encode_frame(&mut conv_samps, packet.len(), &self.coefs),
+                Quality::High => {
+                    dsp_encode_frame_search(&mut conv_samps, packet.len(), &self.coefs)
+                }
+            };
             data_bytes.extend_from_slice(&block);
 
-            if self.is_first {
-                self.is_first = false;
+            let packet_start = self.position + p * PACKET_SAMPLES;
+            if packet_start == 0 {
                 self.initial_predictor = block[0];
             }
-
-            if let Some(loop_point) = self.samples_until_loop_point {
-                if loop_point < PACKET_SAMPLES {
-                    self.samples_until_loop_point = None;
-                    self.loop_predictor = block[0]
-                } else {
-                    self.samples_until_loop_point = Some(loop_point - PACKET_SAMPLES);
+            let packet_samples = packet_start..packet_start + packet.len();
+            if packet_samples.contains(&self.loop_point) {
+                self.loop_predictor = block[0];
+            }
+            // gracefully handle the case when the loop point is 0
+            for (history, offset) in self.loop_history_samples.iter_mut().zip([1, 2]) {
+                if let Some(sample) = self.loop_point.checked_sub(offset) {
+                    if packet_samples.contains(&sample) {
+                        *history = conv_samps[2 + sample - packet_start];
+                    }
                 }
             }
 
             conv_samps[0] = conv_samps[14];
             conv_samps[1] = conv_samps[15];
-
-            if self.samples.len() > PACKET_SAMPLES {
-                self.samples = &self.samples[PACKET_SAMPLES..];
-            } else {
-                // there aren't enough samples to fill this block, so encoding this stream is finished
-                let bytes_written = (p + 1) * PACKET_BYTES;
-                let samples = p * PACKET_SAMPLES + self.samples.len();
-                // pad to 32 bytes
-                let rem = bytes_written % 32;
-                if rem != 0 {
-                    for _ in 0..(32 - rem) {
-                        data_bytes.push(0);
-                    }
-                }
-                return Some((bytes_written, samples));
-            }
         }
-        None
+        self.history.copy_from_slice(&conv_samps[..2]);
+        self.position += samples.len();
     }
 
-    pub fn get_adpcm_channel_info(&self) -> AdpcmChannelInformation {
-        let mut adpcm_coefficients = [0; 16];
-        for (dst, src) in adpcm_coefficients
-            .iter_mut()
-            .zip(self.coefs.iter().flatten())
-        {
-            *dst = *src;
-        }
+    fn get_adpcm_channel_info(&self) -> AdpcmChannelInformation {
         AdpcmChannelInformation {
-            // TODO: remove unsafe here
-            adpcm_coefficients,
+            adpcm_coefficients: flatten_coefs(&self.coefs),
             gain: 0,
             history_sample1: 0,
             history_sample2: 0,
-            initial_predictor: self.initial_predictor.into(), // TODO
+            initial_predictor: self.initial_predictor.into(),
             loop_history_sample1: self.loop_history_samples[0],
             loop_history_sample2: self.loop_history_samples[1],
             loop_predictor: self.loop_predictor.into(),
@@ -154,6 +129,30 @@ impl<'a> BrstmStreamEncoder<'a> {
     }
 }
 
+/// finds the ADPCM coefficients of a channel while its samples come in, to use them with a
+/// [`StreamEncoder`]. The result is the same as encoding all samples at once. The analysis keeps
+/// 24 bytes for every 14 samples, but at most 24 MiB per channel: beyond about 14.7 million
+/// samples only an evenly spread part of them refines the coefficients, so they can differ
+/// slightly from the reference encoder
+#[derive(Debug, Clone, Default)]
+pub struct CoefficientAnalyzer {
+    state: CorrelateState,
+}
+
+impl CoefficientAnalyzer {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn push(&mut self, samples: &[i16]) {
+        self.state.push(samples);
+    }
+
+    pub fn finish(self) -> [i16; 16] {
+        flatten_coefs(&self.state.finish())
+    }
+}
+
 #[derive(thiserror::Error, Debug)]
 #[non_exhaustive]
 pub enum EncodingError {
@@ -163,102 +162,304 @@ pub enum EncodingError {
     UnevenChannelCount(usize),
     #[error("Too many channels: {0}, only 16 are supported")]
     TooHighChannelCount(usize),
-    #[error("Loop point {loop_point} is greater than the amount of samples {size}")]
+    #[error("Loop point {loop_point} isn't before the end of the {size} samples")]
     LoopOutOfBounds { loop_point: usize, size: usize },
     #[error("All Channels must have the same length, got {0:?}")]
     MissmatchedLengths(Vec<usize>),
+    #[error("Expected samples for {expected} channels, got {got}")]
+    ChannelCountMismatch { expected: usize, got: usize },
+    #[error("Expected {expected} samples, got {got}")]
+    SampleCountMismatch { expected: u32, got: u64 },
+    #[error("IO error: {0}")]
+    Io(#[from] io::Error),
+    #[error("Block size {0} isn't a multiple of 32")]
+    InvalidBlockSize(u32),
+    #[error("Only ADPCM can be encoded as a stream, not {0:?}")]
+    UnsupportedStreamCodec(Codec),
+    #[error("Failed to write: {0}")]
+    Write(#[from] crate::Error),
 }
 
-pub fn encode_brstm(
+#[derive(Debug, Clone)]
+pub struct EncodeOptions {
+    /// PCM is lossless but a lot bigger than ADPCM
+    pub codec: Codec,
+    /// bytes of a block of a single channel, has to be a multiple of 32. Only the final block
+    /// is shorter, it's padded to the next 32 bytes
+    pub block_size: u32,
+    /// only used for ADPCM
+    pub quality: Quality,
+}
+
+impl Default for EncodeOptions {
+    fn default() -> Self {
+        Self {
+            codec: Codec::Adpcm,
+            block_size: DEFAULT_BLOCK_SIZE,
+            quality: Quality::Standard,
+        }
+    }
+}
+
+/// result of encoding all channels into blocks
+struct EncodedBlocks {
+    channel_infos: Vec<AdpcmChannelInformation>,
+    adpcm_bytes: Vec<u8>,
+    data_bytes: Vec<u8>,
+    blocks_samples: u32,
+    total_blocks: u32,
+    final_block_size: u32,
+    final_block_samples: u32,
+}
+
+/// ADPC entries and padded blocks of a single channel
+struct EncodedChannel {
+    info: AdpcmChannelInformation,
+    adpcm_bytes: Vec<u8>,
+    data_bytes: Vec<u8>,
+}
+
+fn encode_adpcm_channel(
+    channel: &[i16],
+    loop_point: Option<u32>,
+    blocks_samples: usize,
+    quality: Quality,
+) -> EncodedChannel {
+    let mut encoder = ChannelEncoder::new(
+        dsp_correlate_coefs(channel),
+        loop_point.unwrap_or(0),
+        quality,
+    );
+    let mut adpcm_bytes = Vec::new();
+    let mut data_bytes = Vec::with_capacity(channel.len() / PACKET_SAMPLES * PACKET_BYTES + 32);
+    // even a stream without samples has one (empty) block
+    for block in channel
+        .chunks(blocks_samples)
+        .chain(channel.is_empty().then_some(&[][..]))
+    {
+        encoder.encode_block(block, &mut adpcm_bytes, &mut data_bytes);
+    }
+    // pad the final block to 32 bytes, the other ones are always full
+    data_bytes.resize((data_bytes.len() + 31) & !31, 0);
+    EncodedChannel {
+        info: encoder.get_adpcm_channel_info(),
+        adpcm_bytes,
+        data_bytes,
+    }
+}
+
+/// runs `f` for every channel, on its own thread if the `parallel` feature is enabled
+fn map_channels<T: Send>(channels: &[Vec<i16>], f: impl Fn(&[i16]) -> T + Sync) -> Vec<T> {
+    #[cfg(feature = "parallel")]
+    {
+        let f = &f;
+        std::thread::scope(|scope| {
+            let handles: Vec<_> = channels
+                .iter()
+                .map(|channel| scope.spawn(move || f(channel)))
+                .collect();
+            handles
+                .into_iter()
+                .map(|handle| handle.join().unwrap())
+                .collect()
+        })
+    }
+    #[cfg(not(feature = "parallel"))]
+    {
+        channels.iter().map(|channel| f(channel)).collect()
+    }
+}
+
+fn encode_adpcm_blocks(
     channels: &[Vec<i16>],
-    sampling_rate: u16,
     loop_point: Option<u32>,
-) -> Result<BrstmInfoWithData, EncodingError> {
-    // make sure all channels have the same length
-    let mut lengths_iter = channels.iter().map(|c| c.len());
-    let sample_count = lengths_iter.next().ok_or(EncodingError::EmptyChannels)?;
-    if !lengths_iter.all(|len| len == sample_count) {
-        return Err(EncodingError::MissmatchedLengths(
-            channels.iter().map(|c| c.len()).collect(),
-        ));
+    block_size: usize,
+    quality: Quality,
+) -> EncodedBlocks {
+    let sample_count = channels[0].len();
+    let blocks_samples = block_size / PACKET_BYTES * PACKET_SAMPLES;
+    let total_blocks = div_ceil(sample_count, blocks_samples).max(1);
+    let final_block_samples = sample_count - (total_blocks - 1) * blocks_samples;
+    let final_block_size = Codec::Adpcm.bytes_for_samples(final_block_samples as u32);
+
+    // the channels are independent, their blocks are interleaved afterwards
+    let encoded = map_channels(channels, |channel| {
+        encode_adpcm_channel(channel, loop_point, blocks_samples, quality)
+    });
+    let mut adpcm_bytes = Vec::with_capacity(total_blocks * channels.len() * 4);
+    let mut data_bytes = Vec::with_capacity(encoded.iter().map(|c| c.data_bytes.len()).sum());
+    for block_index in 0..total_blocks {
+        for channel in encoded.iter() {
+            adpcm_bytes.extend_from_slice(&channel.adpcm_bytes[block_index * 4..][..4]);
+            let block_start = block_index * block_size;
+            let block_end = (block_start + block_size).min(channel.data_bytes.len());
+            data_bytes.extend_from_slice(&channel.data_bytes[block_start..block_end]);
+        }
+    }
+
+    EncodedBlocks {
+        channel_infos: encoded.into_iter().map(|c| c.info).collect(),
+        adpcm_bytes,
+        data_bytes,
+        blocks_samples: blocks_samples as u32,
+        total_blocks: total_blocks as u32,
+        final_block_size,
+        final_block_samples: final_block_samples as u32,
+    }
+}
+
+/// PCM16 is stored big endian, PCM8 only keeps the upper byte of each sample
+fn encode_pcm_blocks(
+    channels: &[Vec<i16>],
+    bytes_per_sample: usize,
+    block_size: usize,
+) -> EncodedBlocks {
+    let sample_count = channels[0].len();
+    let blocks_samples = block_size / bytes_per_sample;
+    let total_blocks = div_ceil(sample_count, blocks_samples).max(1);
+    let final_block_samples = sample_count - (total_blocks - 1) * blocks_samples;
+    let final_block_size = final_block_samples * bytes_per_sample;
+    let final_block_size_padded = (final_block_size + 31) & !31;
+
+    let mut data_bytes = Vec::with_capacity(
+        channels.len() * ((total_blocks - 1) * block_size + final_block_size_padded),
+    );
+    for block_index in 0..total_blocks {
+        for channel in channels.iter() {
+            let samples = channel
+                .chunks(blocks_samples)
+                .nth(block_index)
+                .unwrap_or(&[]);
+            for sample in samples {
+                if bytes_per_sample == 1 {
+                    data_bytes.push((sample >> 8) as u8);
+                } else {
+                    data_bytes.extend_from_slice(&sample.to_be_bytes());
+                }
+            }
+            if block_index == total_blocks - 1 {
+                data_bytes.resize(
+                    data_bytes.len() + final_block_size_padded - final_block_size,
+                    0,
+                );
+            }
+        }
     }
-    if channels.len() % 2 != 0 && channels.len() != 1 {
-        return Err(EncodingError::UnevenChannelCount(channels.len()));
+
+    EncodedBlocks {
+        // PCM doesn't have any coefficients or history
+        channel_infos: vec![AdpcmChannelInformation::default(); channels.len()],
+        adpcm_bytes: vec![0; total_blocks * channels.len() * 4],
+        data_bytes,
+        blocks_samples: blocks_samples as u32,
+        total_blocks: total_blocks as u32,
+        final_block_size: final_block_size as u32,
+        final_block_samples: final_block_samples as u32,
     }
-    if channels.len() > 16 {
-        return Err(EncodingError::TooHighChannelCount(channels.len()));
+}
+
+fn check_layout(
+    channel_count: usize,
+    sample_count: usize,
+    loop_point: Option<u32>,
+    block_size: u32,
+) -> Result<(), EncodingError> {
+    if block_size == 0 || !block_size.is_multiple_of(32) {
+        return Err(EncodingError::InvalidBlockSize(block_size));
+    }
+    if channel_count == 0 {
+        return Err(EncodingError::EmptyChannels);
+    }
+    if !channel_count.is_multiple_of(2) && channel_count != 1 {
+        return Err(EncodingError::UnevenChannelCount(channel_count));
+    }
+    if channel_count > 16 {
+        return Err(EncodingError::TooHighChannelCount(channel_count));
     }
     if let Some(loop_point) = loop_point {
-        if loop_point as usize > sample_count {
+        if loop_point as usize >= sample_count {
             return Err(EncodingError::LoopOutOfBounds {
                 loop_point: loop_point as _,
                 size: sample_count,
             });
         }
     }
+    Ok(())
+}
 
-    let mut data_bytes = Vec::new();
-    let mut adpcm_bytes = Vec::new();
-    let mut channel_encoders: Vec<_> = channels
-        .iter()
-        .map(|channel| BrstmStreamEncoder::init(channel, loop_point.unwrap_or(0)))
-        .collect();
-
-    let mut block_count = 0;
-    let (final_block_size, final_block_samples) = loop {
-        let mut final_chunk_unpadded = None;
-        for encoder in &mut channel_encoders {
-            // not sure if there is a better way to write this, but we have to stop once all
-            // streams reach the final block, which
-            let ret = encoder.pull_chunk(&mut adpcm_bytes, &mut data_bytes);
-            // just debug to make sure all have the same length
-            // if let (Some(a), Some(b)) = (&final_chunk_unpadded, &ret) {
-            //     assert_eq!(a, b);
-            // }
-            final_chunk_unpadded = ret;
-        }
-        block_count += 1;
-        if let Some(size) = final_chunk_unpadded {
-            break size;
-        }
-    };
-
-    // after the encoding is done, grab the channel info, because encoding fills in the predictors
-    let channel_infos: Vec<_> = channel_encoders
-        .iter()
-        .map(BrstmStreamEncoder::get_adpcm_channel_info)
-        .collect();
-
-    let tracks = if channels.len() == 1 {
-        vec![TrackDescription{
+/// a single track for mono, otherwise a stereo track for every pair of channels
+fn default_tracks(channel_count: usize) -> Vec<TrackDescription> {
+    if channel_count == 1 {
+        vec![TrackDescription {
             channels: Channels::Mono(0),
             ..Default::default()
         }]
-    } else { (0..(channels.len() as u8 / 2))
-        .map(|t| TrackDescription {
-            channels: Channels::Stereo(t * 2, t * 2 + 1),
-            ..Default::default()
-        })
-        .collect()};
+    } else {
+        (0..(channel_count as u8 / 2))
+            .map(|t| TrackDescription {
+                channels: Channels::Stereo(t * 2, t * 2 + 1),
+                ..Default::default()
+            })
+            .collect()
+    }
+}
+
+pub fn encode_brstm(
+    channels: &[Vec<i16>],
+    sampling_rate: u16,
+    loop_point: Option<u32>,
+) -> Result<BrstmInfoWithData, EncodingError> {
+    encode_brstm_with_options(
+        channels,
+        sampling_rate,
+        loop_point,
+        &EncodeOptions::default(),
+    )
+}
+
+pub fn encode_brstm_with_options(
+    channels: &[Vec<i16>],
+    sampling_rate: u16,
+    loop_point: Option<u32>,
+    options: &EncodeOptions,
+) -> Result<BrstmInfoWithData, EncodingError> {
+    // make sure all channels have the same length
+    let mut lengths_iter = channels.iter().map(|c| c.len());
+    let sample_count = lengths_iter.next().ok_or(EncodingError::EmptyChannels)?;
+    if !lengths_iter.all(|len| len == sample_count) {
+        return Err(EncodingError::MissmatchedLengths(
+            channels.iter().map(|c| c.len()).collect(),
+        ));
+    }
+    check_layout(channels.len(), sample_count, loop_point, options.block_size)?;
+
+    let block_size = options.block_size as usize;
+    let encoded = match options.codec {
+        Codec::Pcm8 => encode_pcm_blocks(channels, 1, block_size),
+        Codec::Pcm16 => encode_pcm_blocks(channels, 2, block_size),
+        Codec::Adpcm => encode_adpcm_blocks(channels, loop_point, block_size, options.quality),
+    };
+
+    let tracks = default_tracks(channels.len());
 
     let out_brstm = BrstmInformation {
-        channels: channel_infos,
+        channels: encoded.channel_infos,
         tracks,
         info: Head1 {
-            codec: 2, // ADPCM
+            codec: options.codec,
             sample_rate: sampling_rate,
             loop_flag: loop_point.is_some().into(),
             num_channels: channels.len() as u8,
             loop_start: loop_point.unwrap_or(0),
             total_samples: sample_count as u32,
             adpc_bytes_per_entry: 4,
-            adpc_samples_per_entry: 14336, // 8192 / 8 * 14
-            blocks_samples: 14336,
-            blocks_size: 8192,
-            final_block_samples: final_block_samples.try_into().unwrap(),
-            final_block_size: final_block_size.try_into().unwrap(),
-            final_block_size_padded: ((final_block_size + 31) & !31).try_into().unwrap(),
-            total_blocks: block_count as u32,
+            adpc_samples_per_entry: encoded.blocks_samples,
+            blocks_samples: encoded.blocks_samples,
+            blocks_size: options.block_size,
+            final_block_samples: encoded.final_block_samples,
+            final_block_size: encoded.final_block_size,
+            final_block_size_padded: (encoded.final_block_size + 31) & !31,
+            total_blocks: encoded.total_blocks,
             // filled in later
             audio_offset: 0,
         },
@@ -267,10 +468,601 @@ pub fn encode_brstm(
         adpcm_size: 0,
         data_offset: 0,
         data_size: 0,
+        original: None,
     };
     Ok(BrstmInfoWithData {
-        adpcm_bytes,
-        data_bytes,
+        adpcm_bytes: encoded.adpcm_bytes,
+        data_bytes: encoded.data_bytes,
         info: out_brstm,
     })
 }
+
+/// encodes ADPCM while the samples come in, every block is written out as soon as it's full,
+/// so only the current block of each channel is kept in memory. The sample count and the
+/// coefficients (see [`CoefficientAnalyzer`]) have to be known up front. The headers are
+/// written first and written again with the final channel information by [`Self::finish`]
+pub struct StreamEncoder<W> {
+    writer: W,
+    info: BrstmInformation,
+    // where the file starts in the writer
+    start: u64,
+    encoders: Vec<ChannelEncoder>,
+    // samples of the current block, per channel
+    pending: Vec<Vec<i16>>,
+    adpcm_bytes: Vec<u8>,
+    block_bytes: Vec<u8>,
+    block_index: u32,
+    samples_pushed: u64,
+}
+
+impl<W: Write + Seek> StreamEncoder<W> {
+    /// starts the file at the current position of the writer, with one set of coefficients
+    /// per channel
+    pub fn new(
+        writer: W,
+        coefficients: &[[i16; 16]],
+        sampling_rate: u16,
+        total_samples: u32,
+        loop_point: Option<u32>,
+    ) -> Result<Self, EncodingError> {
+        Self::with_options(
+            writer,
+            coefficients,
+            sampling_rate,
+            total_samples,
+            loop_point,
+            &EncodeOptions::default(),
+        )
+    }
+
+    /// like [`Self::new`], the codec of the options has to be ADPCM
+    pub fn with_options(
+        mut writer: W,
+        coefficients: &[[i16; 16]],
+        sampling_rate: u16,
+        total_samples: u32,
+        loop_point: Option<u32>,
+        options: &EncodeOptions,
+    ) -> Result<Self, EncodingError> {
+        if options.codec != Codec::Adpcm {
+            return Err(EncodingError::UnsupportedStreamCodec(options.codec));
+        }
+        let channel_count = coefficients.len();
+        check_layout(
+            channel_count,
+            total_samples as usize,
+            loop_point,
+            options.block_size,
+        )?;
+        let mut info = BrstmInformation {
+            channels: coefficients
+                .iter()
+                .map(|coefs| AdpcmChannelInformation {
+                    adpcm_coefficients: *coefs,
+                    ..Default::default()
+                })
+                .collect(),
+            tracks: default_tracks(channel_count),
+            info: Head1 {
+                codec: Codec::Adpcm,
+                sample_rate: sampling_rate,
+                loop_flag: loop_point.is_some().into(),
+                num_channels: channel_count as u8,
+                loop_start: loop_point.unwrap_or(0),
+                total_samples,
+                blocks_size: options.block_size,
+                ..Default::default()
+            },
+            // only needed for reading
+            adpcm_offset: 0,
+            adpcm_size: 0,
+            data_offset: 0,
+            data_size: 0,
+            original: None,
+        };
+        info.info = info.normalized_head1()?;
+        let head1 = &info.info;
+        let adpcm_len = head1.total_blocks as usize * channel_count * 4;
+        let start = writer.stream_position()?;
+        // the real ADPC table and channel information are only known at the end
+        info.write_head_sections(
+            &mut writer,
+            head1.clone(),
+            &vec![0; adpcm_len],
+            head1.data_len(),
+        )?;
+        let block_samples = head1.blocks_samples as usize;
+        let encoders = coefficients
+            .iter()
+            .map(|coefs| {
+                ChannelEncoder::new(pair_coefs(coefs), loop_point.unwrap_or(0), options.quality)
+            })
+            .collect();
+        Ok(StreamEncoder {
+            writer,
+            start,
+            encoders,
+            pending: vec![Vec::with_capacity(block_samples); channel_count],
+            adpcm_bytes: Vec::with_capacity(adpcm_len),
+            block_bytes: Vec::with_capacity(options.block_size as usize * channel_count),
+            block_index: 0,
+            samples_pushed: 0,
+            info,
+        })
+    }
+
+    /// adds the next samples of all channels, every channel needs the same amount
+    pub fn push(&mut self, channels: &[&[i16]]) -> Result<(), EncodingError> {
+        if channels.len() != self.encoders.len() {
+            return Err(EncodingError::ChannelCountMismatch {
+                expected: self.encoders.len(),
+                got: channels.len(),
+            });
+        }
+        let len = channels[0].len();
+        if channels.iter().any(|channel| channel.len() != len) {
+            return Err(EncodingError::MissmatchedLengths(
+                channels.iter().map(|c| c.len()).collect(),
+            ));
+        }
+        let total_samples = self.info.info.total_samples;
+        if self.samples_pushed + len as u64 > total_samples.into() {
+            return Err(EncodingError::SampleCountMismatch {
+                expected: total_samples,
+                got: self.samples_pushed + len as u64,
+            });
+        }
+        let block_samples = self.info.info.blocks_samples as usize;
+        let mut offset = 0;
+        while offset < len {
+            let count = (block_samples - self.pending[0].len()).min(len - offset);
+            for (pending, channel) in self.pending.iter_mut().zip(channels) {
+                pending.extend_from_slice(&channel[offset..][..count]);
+            }
+            offset += count;
+            self.samples_pushed += count as u64;
+            if self.pending[0].len() == block_samples {
+                self.write_block()?;
+            }
+        }
+        Ok(())
+    }
+
+    /// adds samples with all channels interleaved, like [`Self::push`]
+    pub fn push_interleaved(&mut self, samples: &[i16]) -> Result<(), EncodingError> {
+        let channel_count = self.encoders.len();
+        if !samples.len().is_multiple_of(channel_count) {
+            return Err(EncodingError::MissmatchedLengths(vec![samples.len()]));
+        }
+        let channels: Vec<Vec<i16>> = (0..channel_count)
+            .map(|channel| {
+                samples
+                    .iter()
+                    .skip(channel)
+                    .step_by(channel_count)
+                    .copied()
+                    .collect()
+            })
+            .collect();
+        let channels: Vec<&[i16]> = channels.iter().map(Vec::as_slice).collect();
+        self.push(&channels)
+    }
+
+    fn write_block(&mut self) -> Result<(), EncodingError> {
+        let block_len = self.info.info.block_byte_len(self.block_index) as usize;
+        self.block_bytes.clear();
+        for (encoder, pending) in self.encoders.iter_mut().zip(self.pending.iter_mut()) {
+            let block_start = self.block_bytes.len();
+            encoder.encode_block(pending, &mut self.adpcm_bytes, &mut self.block_bytes);
+            // only the final block can need padding
+            self.block_bytes.resize(block_start + block_len, 0);
+            pending.clear();
+        }
+        self.writer.write_all(&self.block_bytes)?;
+        self.block_index += 1;
+        Ok(())
+    }
+
+    /// writes the final block and the headers, all samples have to be pushed
+    pub fn finish(mut self) -> Result<W, EncodingError> {
+        let total_samples = self.info.info.total_samples;
+        if self.samples_pushed != total_samples.into() {
+            return Err(EncodingError::SampleCountMismatch {
+                expected: total_samples,
+                got: self.samples_pushed,
+            });
+        }
+        if self.block_index < self.info.info.total_blocks {
+            self.write_block()?;
+        }
+        for (channel, encoder) in self.info.channels.iter_mut().zip(&self.encoders) {
+            *channel = encoder.get_adpcm_channel_info();
+        }
+        let end = self.writer.stream_position()?;
+        self.writer.seek(SeekFrom::Start(self.start))?;
+        let head1 = self.info.info.clone();
+        let data_len = head1.data_len();
+        self.info
+            .write_head_sections(&mut self.writer, head1, &self.adpcm_bytes, data_len)?;
+        self.writer.seek(SeekFrom::Start(end))?;
+        self.writer.flush()?;
+        Ok(self.writer)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::Cursor;
+
+    use crate::{
+        reshaper::{reshape, ReshapeSrc, ReshapeTrackDef},
+        structs::Codec,
+        BrstmInformation,
+    };
+
+    use super::{
+        encode_brstm, encode_brstm_with_options, flatten_coefs, CoefficientAnalyzer, EncodeOptions,
+        EncodingError, Quality, StreamEncoder,
+    };
+    use crate::{gc_dspadpcm::dsp_correlate_coefs, validate::validate};
+
+    #[test]
+    pub fn pcm_roundtrip() {
+        let channels: Vec<Vec<i16>> = (0..2)
+            .map(|c| (0..10_000).map(|i| (i * 7 + c * 1000) as i16).collect())
+            .collect();
+        for codec in [Codec::Pcm8, Codec::Pcm16] {
+            let options = EncodeOptions {
+                codec,
+                ..Default::default()
+            };
+            let encoded = encode_brstm_with_options(&channels, 44100, Some(10), &options).unwrap();
+            let mut buf = Vec::new();
+            encoded.write_brstm(&mut Cursor::new(&mut buf)).unwrap();
+            let mut cursor = Cursor::new(&buf);
+            let mut read = BrstmInformation::from_reader(&mut cursor)
+                .unwrap()
+                .into_with_data(&mut cursor)
+                .unwrap();
+            assert_eq!(read.info.info.codec, codec);
+            let expected: Vec<Vec<i16>> = channels
+                .iter()
+                .map(|channel| {
+                    if codec == Codec::Pcm8 {
+                        channel.iter().map(|s| s & !0xFF).collect()
+                    } else {
+                        channel.clone()
+                    }
+                })
+                .collect();
+            assert_eq!(expected[0], read.get_pcm(0));
+            assert_eq!(expected[1], read.get_pcm(1));
+
+            // swap the channels
+            reshape(
+                &mut read,
+                &[ReshapeTrackDef::Stereo {
+                    left: ReshapeSrc::Channel(1),
+                    right: ReshapeSrc::Channel(0),
+                }],
+            )
+            .unwrap();
+            assert_eq!(expected[1], read.get_pcm(0));
+            assert_eq!(expected[0], read.get_pcm(1));
+        }
+    }
+
+    #[test]
+    pub fn stream_matches_encode_brstm() {
+        for (channel_count, len, loop_point) in [
+            (2, 40_000, Some(14_500)),
+            (1, 14_336 * 3, None),
+            (2, 100, Some(0)),
+            (4, 0, None),
+        ] {
+            let channels: Vec<Vec<i16>> = (0..channel_count)
+                .map(|c| {
+                    (0..len)
+                        .map(|i| (((i * (c + 3)) % 700) as i16 - 350) * 60)
+                        .collect()
+                })
+                .collect();
+            let mut expected = Cursor::new(Vec::new());
+            encode_brstm(&channels, 32000, loop_point)
+                .unwrap()
+                .write_brstm(&mut expected)
+                .unwrap();
+
+            let coefficients: Vec<[i16; 16]> = channels
+                .iter()
+                .map(|channel| {
+                    let mut analyzer = CoefficientAnalyzer::new();
+                    for chunk in channel.chunks(1000) {
+                        analyzer.push(chunk);
+                    }
+                    let coefs = analyzer.finish();
+                    assert_eq!(coefs, flatten_coefs(&dsp_correlate_coefs(channel)));
+                    coefs
+                })
+                .collect();
+            let mut encoder = StreamEncoder::new(
+                Cursor::new(Vec::new()),
+                &coefficients,
+                32000,
+                len as u32,
+                loop_point,
+            )
+            .unwrap();
+            // uneven chunks so they don't line up with the blocks
+            let mut pos = 0;
+            while pos < len {
+                let end = (pos + 3333).min(len);
+                let chunk: Vec<&[i16]> = channels.iter().map(|c| &c[pos..end]).collect();
+                encoder.push(&chunk).unwrap();
+                pos = end;
+            }
+            let streamed = encoder.finish().unwrap().into_inner();
+            assert_eq!(streamed, expected.into_inner());
+        }
+    }
+
+    #[test]
+    pub fn stream_sample_count_mismatch() {
+        let mut encoder =
+            StreamEncoder::new(Cursor::new(Vec::new()), &[[0; 16]; 2], 32000, 10, None).unwrap();
+        encoder.push_interleaved(&[1; 8]).unwrap();
+        assert!(encoder.push(&[&[0; 2], &[0; 1]]).is_err());
+        assert!(encoder.push_interleaved(&[1; 14]).is_err());
+        assert!(encoder.finish().is_err());
+    }
+
+    #[test]
+    pub fn block_sizes() {
+        let channels: Vec<Vec<i16>> = (0..2)
+            .map(|c| {
+                (0..5000)
+                    .map(|i| ((i * (c + 2)) % 900) as i16 * 30)
+                    .collect()
+            })
+            .collect();
+        for codec in [Codec::Adpcm, Codec::Pcm16, Codec::Pcm8] {
+            for block_size in [32, 1024, 0x2760, 0x10000] {
+                let options = EncodeOptions {
+                    codec,
+                    block_size,
+                    ..Default::default()
+                };
+                let encoded =
+                    encode_brstm_with_options(&channels, 32000, Some(1234), &options).unwrap();
+                let mut buf = Cursor::new(Vec::new());
+                encoded.write_brstm(&mut buf).unwrap();
+                let file = buf.into_inner();
+                let report = validate(&file);
+                assert!(report.is_valid(), "{codec:?} {block_size}: {report:?}");
+
+                let mut cursor = Cursor::new(&file);
+                let read = BrstmInformation::from_reader(&mut cursor)
+                    .unwrap()
+                    .into_with_data(&mut cursor)
+                    .unwrap();
+                let head1 = &read.info.info;
+                assert_eq!(head1.blocks_size, block_size);
+                assert_eq!(head1.adpc_samples_per_entry, head1.blocks_samples);
+                // one ADPC entry per block
+                assert!(read.try_get_adpc_bytes(1, head1.total_blocks - 1).is_ok());
+                assert!(read.try_get_adpc_bytes(1, head1.total_blocks).is_err());
+                if codec == Codec::Pcm16 {
+                    assert_eq!(read.get_pcm(1), channels[1]);
+                }
+                if codec == Codec::Adpcm {
+                    let coefficients: Vec<[i16; 16]> = read
+                        .info
+                        .channels
+                        .iter()
+                        .map(|c| c.adpcm_coefficients)
+                        .collect();
+                    let mut encoder = StreamEncoder::with_options(
+                        Cursor::new(Vec::new()),
+                        &coefficients,
+                        32000,
+                        5000,
+                        Some(1234),
+                        &options,
+                    )
+                    .unwrap();
+                    encoder.push(&[&channels[0], &channels[1]]).unwrap();
+                    assert_eq!(encoder.finish().unwrap().into_inner(), file);
+                }
+            }
+        }
+        for block_size in [0, 16, 8200] {
+            let options = EncodeOptions {
+                block_size,
+                ..Default::default()
+            };
+            assert!(matches!(
+                encode_brstm_with_options(&channels, 32000, None, &options),
+                Err(EncodingError::InvalidBlockSize(_))
+            ));
+        }
+    }
+
+    #[test]
+    pub fn channels_are_independent() {
+        // with the parallel feature every channel is encoded on its own thread
+        let channels: Vec<Vec<i16>> = (0..6).map(|c| golden_input(c, 40_000)).collect();
+        let encoded = encode_brstm(&channels, 32000, Some(20_000)).unwrap();
+        for (c, channel) in channels.iter().enumerate() {
+            let mono = encode_brstm(std::slice::from_ref(channel), 32000, Some(20_000)).unwrap();
+            assert_eq!(encoded.info.channels[c], mono.info.channels[0]);
+            for block in 0..mono.info.info.total_blocks {
+                assert_eq!(
+                    encoded.get_adpc_bytes(c as u8, block),
+                    mono.get_adpc_bytes(0, block)
+                );
+                assert_eq!(
+                    encoded.get_data_block(c as u8, block),
+                    mono.get_data_block(0, block)
+                );
+            }
+        }
+    }
+
+    #[test]
+    pub fn high_quality() {
+        // quiet like the soft tracks where the hiss is noticeable
+        let channels: Vec<Vec<i16>> = (0..2)
+            .map(|c| golden_input(c, 20_000).iter().map(|s| s / 64).collect())
+            .collect();
+        let squared_error = |quality| {
+            let options = EncodeOptions {
+                quality,
+                ..Default::default()
+            };
+            let encoded =
+                encode_brstm_with_options(&channels, 32000, Some(5000), &options).unwrap();
+            let mut written = Cursor::new(Vec::new());
+            encoded.write_brstm(&mut written).unwrap();
+            let report = validate(written.get_ref());
+            assert!(report.findings.is_empty(), "{:?}", report.findings);
+            (0..2)
+                .flat_map(|c| {
+                    let decoded = encoded.get_pcm(c);
+                    channels[c as usize]
+                        .iter()
+                        .zip(decoded)
+                        .map(|(&a, b)| (a as i64 - b as i64).pow(2))
+                        .collect::<Vec<_>>()
+                })
+                .sum::<i64>()
+        };
+        let standard = squared_error(Quality::Standard);
+        let high = squared_error(Quality::High);
+        assert!(high < standard * 9 / 10, "{high} {standard}");
+    }
+
+    /// deterministic test signal without floats: low passed noise, a sawtooth and some silence
+    fn golden_input(channel: u32, len: usize) -> Vec<i16> {
+        let mut state = 0x1234_5678u32 ^ channel;
+        let mut filtered = 0i32;
+        (0..len)
+            .map(|i| {
+                state ^= state << 13;
+                state ^= state >> 17;
+                state ^= state << 5;
+                filtered += ((state >> 16) as i32 - 0x8000 - filtered) / 8;
+                let saw = (i as i32 * (60 + channel as i32 * 17)) % 16000 - 8000;
+                if (i / 3000) % 4 == 3 {
+                    0
+                } else {
+                    (filtered / 2 + saw) as i16
+                }
+            })
+            .collect()
+    }
+
+    /// a channel of the inputs in test_data/encoder
+    fn golden_channel(name: &str, channel: u32) -> Vec<i16> {
+        std::fs::read(format!("test_data/encoder/{name}_{channel}.pcm"))
+            .unwrap()
+            .chunks_exact(2)
+            .map(|sample| i16::from_le_bytes([sample[0], sample[1]]))
+            .collect()
+    }
+
+    /// name, channels, loop point and block size of the inputs in test_data/encoder
+    const GOLDEN_CASES: [(&str, u32, u32, u32); 2] = [
+        ("mono_loop", 1, 10_007, 8192),
+        ("stereo_small_blocks", 2, 1_800, 1024),
+    ];
+
+    /// the BRSTM files in test_data/encoder, set BRSTM_WRITE_GOLDEN to write them again
+    #[test]
+    pub fn golden_vectors() {
+        for (name, channel_count, loop_point, block_size) in GOLDEN_CASES {
+            let channels: Vec<Vec<i16>> = (0..channel_count)
+                .map(|channel| golden_channel(name, channel))
+                .collect();
+            let options = EncodeOptions {
+                block_size,
+                ..Default::default()
+            };
+            let mut written = Cursor::new(Vec::new());
+            encode_brstm_with_options(&channels, 32000, Some(loop_point), &options)
+                .unwrap()
+                .write_brstm(&mut written)
+                .unwrap();
+            let written = written.into_inner();
+            let path = format!("test_data/encoder/{name}.brstm");
+            if std::env::var_os("BRSTM_WRITE_GOLDEN").is_some() {
+                std::fs::write(path, &written).unwrap();
+                continue;
+            }
+            // contexts and history agree with the decoded audio
+            let report = validate(&written);
+            assert!(report.findings.is_empty(), "{name}: {:?}", report.findings);
+            assert!(
+                written == std::fs::read(path).unwrap(),
+                "{name} doesn't match"
+            );
+        }
+    }
+
+    /// the same inputs against the output of the reference routines, written by
+    /// test_data/encoder/reference.c
+    #[test]
+    pub fn reference_vectors() {
+        for (name, channel_count, loop_point, block_size) in GOLDEN_CASES {
+            let channels: Vec<Vec<i16>> = (0..channel_count)
+                .map(|channel| golden_channel(name, channel))
+                .collect();
+            let options = EncodeOptions {
+                block_size,
+                ..Default::default()
+            };
+            let brstm =
+                encode_brstm_with_options(&channels, 32000, Some(loop_point), &options).unwrap();
+            let head1 = &brstm.info.info;
+            for channel in 0..channel_count {
+                let prefix = format!("test_data/encoder/{name}_{channel}");
+                let summary = std::fs::read_to_string(format!("{prefix}.txt")).unwrap();
+                let mut adpc_entries = Vec::new();
+                let info = &brstm.info.channels[channel as usize];
+                for line in summary.lines() {
+                    let mut words = line.split_whitespace();
+                    let key = words.next().unwrap();
+                    let values: Vec<i16> = words.map(|word| word.parse().unwrap()).collect();
+                    match key {
+                        "coefs" => assert_eq!(info.adpcm_coefficients[..], values, "{prefix}"),
+                        "initial_ps" => {
+                            assert_eq!(info.initial_predictor, values[0], "{prefix}")
+                        }
+                        "loop" => assert_eq!(
+                            (
+                                info.loop_predictor,
+                                info.loop_history_sample1,
+                                info.loop_history_sample2
+                            ),
+                            (values[0], values[1], values[2]),
+                            "{prefix}"
+                        ),
+                        "adpc" => adpc_entries.push((values[0], values[1])),
+                        _ => panic!("{prefix}: unknown line {line}"),
+                    }
+                }
+                let table: Vec<(i16, i16)> = (0..head1.total_blocks)
+                    .map(|block| brstm.get_adpc_values(channel as u8, block))
+                    .collect();
+                assert_eq!(table, adpc_entries, "{prefix}");
+
+                // every frame, including its ps byte, the final block is padded
+                let frames = std::fs::read(format!("{prefix}.adpcm")).unwrap();
+                let data: Vec<u8> = (0..head1.total_blocks)
+                    .flat_map(|block| brstm.get_data_block(channel as u8, block).to_vec())
+                    .collect();
+                assert!(data[..frames.len()] == frames, "{prefix}");
+                assert!(data[frames.len()..].iter().all(|b| *b == 0), "{prefix}");
+            }
+        }
+    }
+}
@@ -0,0 +1,79 @@
+use std::io;
+
+use thiserror::Error;
+
+/// error type of the crate, positions are byte offsets in the file
+#[derive(Error, Debug)]
+#[non_exhaustive]
+pub enum Error {
+    #[error("IO error: {0}")]
+    Io(#[from] io::Error),
+    #[error("Bad magic at {pos:#X}")]
+    BadMagic { pos: u64 },
+    #[error("Unsupported version {version:#06X} at {pos:#X}")]
+    UnsupportedVersion { pos: u64, version: u16 },
+    #[error("Unsupported codec {codec} at {pos:#X}")]
+    UnsupportedCodec { pos: u64, codec: u8 },
+    /// the file structure doesn't make sense, like offsets or sizes that don't add up
+    #[error("Invalid layout at {pos:#X}: {message}")]
+    InvalidLayout { pos: u64, message: String },
+    /// the in memory data can't be written, for example because the audio data is too short
+    #[error("Inconsistent data: {0}")]
+    InconsistentData(String),
+    #[error("Track {track} at {pos:#X} has description type {found}, expected {expected}")]
+    TrackTypeMismatch {
+        pos: u64,
+        track: usize,
+        found: u8,
+        expected: u8,
+    },
+    #[error("Header says {expected} channels, but there are {found}")]
+    ChannelCountMismatch { expected: u8, found: usize },
+    #[error("Track {track} references channel {channel}, but there are only {channel_count}")]
+    InvalidChannelReference {
+        track: usize,
+        channel: u8,
+        channel_count: usize,
+    },
+    #[error("Channel {channel} doesn't exist, there are only {channel_count}")]
+    ChannelOutOfRange { channel: u8, channel_count: usize },
+    #[error("Track {track} doesn't exist, there are only {track_count}")]
+    TrackOutOfRange { track: usize, track_count: usize },
+    #[error("Block {block} doesn't exist, there are only {total_blocks}")]
+    BlockOutOfRange { block: u32, total_blocks: u32 },
+    /// any other parsing failure
+    #[error("Failed to parse at {pos:#X}: {message}")]
+    Parse { pos: u64, message: String },
+}
+
+pub type Result<T, E = Error> = std::result::Result<T, E>;
+
+fn binrw_error_pos(err: &binrw::Error) -> u64 {
+    match err {
+        binrw::Error::BadMagic { pos, .. }
+        | binrw::Error::AssertFail { pos, .. }
+        | binrw::Error::Custom { pos, .. }
+        | binrw::Error::NoVariantMatch { pos }
+        | binrw::Error::EnumErrors { pos, .. } => *pos,
+        _ => 0,
+    }
+}
+
+impl From<binrw::Error> for Error {
+    fn from(err: binrw::Error) -> Self {
+        match err {
+            binrw::Error::Io(e) => Self::Io(e),
+            binrw::Error::BadMagic { pos, .. } => Self::BadMagic { pos },
+            binrw::Error::AssertFail { pos, message } => Self::Parse { pos, message },
+            // errors of nested structs are wrapped with context
+            err => match err.root_cause() {
+                binrw::Error::Io(e) => Self::Io(io::Error::new(e.kind(), err.to_string())),
+                binrw::Error::BadMagic { pos, .. } => Self::BadMagic { pos: *pos },
+                root => Self::Parse {
+                    pos: binrw_error_pos(root),
+                    message: err.to_string(),
+                },
+            },
+        }
+    }
+}
@@ -1,4 +1,6 @@
 // translated from https://github.com/jackoalan/gc-dspadpcm-encode/blob/039712baa1291fbd77a1390e0496757122efd81b/grok.c
+// the index based loops are kept to stay close to the original source
+#![allow(clippy::needless_range_loop)]
 
 pub const PACKET_SAMPLES: usize = 14;
 pub const PACKET_BYTES: usize = 8;
@@ -296,132 +298,456 @@ fn filter_records(vec_best: &mut [[f64; 3]; 8], exp: usize, records: &[[f64; 3]]
     }
 }
 
-pub fn dsp_correlate_coefs(mut source: &[i16]) -> [[i16; 2]; 8] {
-    let num_frames = source.len().div_ceil(14);
-    let mut frame_samples;
-
-    // let mut block_buffer = vec![0i16; 0x3800];
-    let mut pcm_hist_buffer = [0i16; 2 * 14];
+pub fn dsp_correlate_coefs(source: &[i16]) -> [[i16; 2]; 8] {
+    let mut analyzer = CorrelateState::new();
+    analyzer.push(source);
+    analyzer.finish()
+}
 
-    let mut vec1 = [0f64; 3];
-    let mut vec_idxs = [0; 3];
+/// records kept to find the coefficients, 24 MiB or about 14.7 million samples of a channel.
+/// Longer inputs keep every 2nd, 4th, ... record, spread evenly over the whole input
+const MAX_RECORDS: usize = 1 << 20;
+
+/// calculates the coefficients of [`dsp_correlate_coefs`] incrementally, the samples can be
+/// passed in chunks of any size. Every frame of 14 samples adds a record of 3 values
+/// that is needed to find the coefficients at the end. Up to [`MAX_RECORDS`] that's exactly the
+/// reference encoder, after that the records are thinned out so the memory stays bounded
+#[derive(Debug, Clone)]
+pub struct CorrelateState {
+    pcm_hist_buffer: [i16; 2 * 14],
+    // samples of an incomplete frame
+    frame_len: usize,
+    records: Vec<[f64; 3]>,
+    max_records: usize,
+    // only every `record_stride`th record is kept
+    record_stride: u64,
+    // the average of all records, including the ones that weren't kept
+    filtered_sum: [f64; 3],
+    record_count: u64,
+}
 
-    let mut records: Vec<[f64; 3]> = Vec::with_capacity(num_frames * 2);
-    let mut vec_best = [[0f64; 3]; 8];
+impl Default for CorrelateState {
+    fn default() -> Self {
+        Self::new()
+    }
+}
 
-    // iterate through 1024-block frames
-    let mut x = source.len();
-    while x > 0 {
-        // full 1024-block frame
-        if x > 0x3800 {
-            frame_samples = 0x3800;
-            x -= 0x3800;
-        } else {
-            // partial frame
-            frame_samples = x;
-            x = 0;
+impl CorrelateState {
+    pub fn new() -> Self {
+        Self {
+            pcm_hist_buffer: [0; 2 * 14],
+            frame_len: 0,
+            records: Vec::new(),
+            max_records: MAX_RECORDS,
+            record_stride: 1,
+            filtered_sum: [0f64; 3],
+            record_count: 0,
         }
+    }
 
-        // block buffer copy, shouldn't be needed
-        let block_buffer = source;
-        source = &source[frame_samples..];
-
-        let mut i = 0;
-        while i < frame_samples {
-            for z in 0..14 {
-                pcm_hist_buffer[z] = pcm_hist_buffer[z + 14];
-            }
-            for z in 0..14 {
-                pcm_hist_buffer[z + 14] = block_buffer.get(i).copied().unwrap_or(0);
-                i += 1;
-            }
-
-            // usually this would be a buffer of 2 14 sample arrays, but inner_product_merge would read 2 elements
-            // into the previous buffer in that case
-            let fixed_hist_buffer: [i16; 16] = pcm_hist_buffer[12..][..16].try_into().unwrap();
-
-            // 14 - 2, to prevent OoB reads
-            let mut vec1 = inner_product_merge(&fixed_hist_buffer);
-            if vec1[0].abs() > 10f64 {
-                let mut mtx = outer_product_merge(&fixed_hist_buffer);
-                if !analyze_ranges(&mut mtx, &mut vec_idxs) {
-                    bidirectional_filter(&mut mtx, &vec_idxs, &mut vec1);
-                    if !quadratic_merge(&mut vec1) {
-                        let mut out_vec = [0f64; 3];
-                        finish_record(&mut vec1, &mut out_vec);
-                        records.push(out_vec);
-                    }
+    pub fn push(&mut self, samples: &[i16]) {
+        for sample in samples {
+            if self.frame_len == 0 {
+                for z in 0..14 {
+                    self.pcm_hist_buffer[z] = self.pcm_hist_buffer[z + 14];
                 }
             }
+            self.pcm_hist_buffer[14 + self.frame_len] = *sample;
+            self.frame_len += 1;
+            if self.frame_len == 14 {
+                self.analyze_frame();
+                self.frame_len = 0;
+            }
         }
     }
 
-    vec1[0] = 1f64;
-    vec1[1] = 0f64;
-    vec1[2] = 0f64;
+    fn analyze_frame(&mut self) {
+        let mut vec_idxs = [0; 3];
+        // usually this would be a buffer of 2 14 sample arrays, but inner_product_merge would read 2 elements
+        // into the previous buffer in that case
+        let fixed_hist_buffer: [i16; 16] = self.pcm_hist_buffer[12..][..16].try_into().unwrap();
+
+        // 14 - 2, to prevent OoB reads
+        let mut vec1 = inner_product_merge(&fixed_hist_buffer);
+        if vec1[0].abs() > 10f64 {
+            let mut mtx = outer_product_merge(&fixed_hist_buffer);
+            if !analyze_ranges(&mut mtx, &mut vec_idxs) {
+                bidirectional_filter(&mut mtx, &vec_idxs, &mut vec1);
+                if !quadratic_merge(&mut vec1) {
+                    let mut out_vec = [0f64; 3];
+                    finish_record(&mut vec1, &mut out_vec);
+                    self.add_record(out_vec);
+                }
+            }
+        }
+    }
 
-    for record in &records {
-        matrix_filter(record, &mut vec_best[0]);
+    fn add_record(&mut self, record: [f64; 3]) {
+        let mut filtered = [0f64; 3];
+        matrix_filter(&record, &mut filtered);
         for y in 1..=2 {
-            vec1[y] += vec_best[0][y];
+            self.filtered_sum[y] += filtered[y];
         }
-    }
-    for y in 1..=2 {
-        vec1[y] /= records.len() as f64;
-    }
-    merge_finish_record(&mut vec1, &mut vec_best[0]);
-    let mut exp = 1;
-    for w in 0..3 {
-        let vec2 = [0f64, -1f64, 0f64];
-        for i in 0..exp {
-            for y in 0..=2 {
-                vec_best[exp + i][y] = (0.01 * vec2[y]) + vec_best[i][y];
+        let index = self.record_count;
+        self.record_count += 1;
+        if !index.is_multiple_of(self.record_stride) {
+            return;
+        }
+        if self.records.len() == self.max_records {
+            // keeps the records with an index that is a multiple of the doubled stride
+            let mut keep = false;
+            self.records.retain(|_| {
+                keep = !keep;
+                keep
+            });
+            self.record_stride *= 2;
+            if !index.is_multiple_of(self.record_stride) {
+                return;
             }
         }
-        exp = 1 << (w + 1);
-        filter_records(&mut vec_best, exp, &records);
+        self.records.push(record);
     }
 
-    let mut coefs_out = [[0; 2]; 8];
+    pub fn finish(mut self) -> [[i16; 2]; 8] {
+        // the last frame is filled with zeros
+        if self.frame_len > 0 {
+            self.pcm_hist_buffer[14 + self.frame_len..].fill(0);
+            self.analyze_frame();
+        }
+        let records = &self.records;
+        let mut vec1 = [0f64; 3];
+        let mut vec_best = [[0f64; 3]; 8];
 
-    for z in 0..8 {
-        let d = -vec_best[z][1] * 2048f64;
-        if d > 0f64 {
-            coefs_out[z][0] = if d > 32767f64 {
-                32767
-            } else {
-                d.round() as i16
-            };
-        } else {
-            coefs_out[z][0] = if d < -32768f64 {
-                -32768
-            } else {
-                d.round() as i16
-            };
+        vec1[0] = 1f64;
+        vec1[1] = 0f64;
+        vec1[2] = 0f64;
+
+        for y in 1..=2 {
+            vec1[y] += self.filtered_sum[y];
+            vec1[y] /= self.record_count as f64;
+        }
+        merge_finish_record(&mut vec1, &mut vec_best[0]);
+        let mut exp = 1;
+        for w in 0..3 {
+            let vec2 = [0f64, -1f64, 0f64];
+            for i in 0..exp {
+                for y in 0..=2 {
+                    vec_best[exp + i][y] = (0.01 * vec2[y]) + vec_best[i][y];
+                }
+            }
+            exp = 1 << (w + 1);
+            filter_records(&mut vec_best, exp, records);
         }
-        let d = -vec_best[z][2] * 2048f64;
-        if d > 0f64 {
-            coefs_out[z][1] = if d > 32767f64 {
-                32767
+
+        let mut coefs_out = [[0; 2]; 8];
+
+        for z in 0..8 {
+            let d = -vec_best[z][1] * 2048f64;
+            if d > 0f64 {
+                coefs_out[z][0] = if d > 32767f64 {
+                    32767
+                } else {
+                    d.round() as i16
+                };
             } else {
-                d.round() as i16
-            };
-        } else {
-            coefs_out[z][1] = if d < -32768f64 {
-                -32768
+                coefs_out[z][0] = if d < -32768f64 {
+                    -32768
+                } else {
+                    d.round() as i16
+                };
+            }
+            let d = -vec_best[z][2] * 2048f64;
+            if d > 0f64 {
+                coefs_out[z][1] = if d > 32767f64 {
+                    32767
+                } else {
+                    d.round() as i16
+                };
             } else {
-                d.round() as i16
-            };
+                coefs_out[z][1] = if d < -32768f64 {
+                    -32768
+                } else {
+                    d.round() as i16
+                };
+            }
         }
+
+        coefs_out
     }
+}
 
-    coefs_out
+/// divides by `2^scale` and rounds to the nearest integer, halves are rounded towards zero.
+/// Gives the same result as the `v / 2^scale ± 0.4999999` in floating point of the reference
+/// encoder: the fraction is a multiple of 1/4096, so it only rounds away if it's above one half
+#[inline]
+fn round_scaled(v: i64, scale: i64) -> i64 {
+    let half_down = ((1 << scale) - 1) >> 1;
+    let rounded = (v.abs() + half_down) >> scale;
+    if v > 0 {
+        rounded
+    } else {
+        -rounded
+    }
 }
 
+/// encodes one frame of up to 14 samples, `pcm_in_out[..2]` is the history (older one first),
+/// the samples start at index 2. Tries every coefficient set and keeps the one with the smallest
+/// error, the samples are replaced with the decoded ones, which are the history of the next
+/// frame. The same as the reference encoder, but only with integers and all 8 sets are encoded
+/// side by side, so their (otherwise sequential) decoding can overlap. The only difference are
+/// frames that don't fit even the largest scale (only possible with coefficients beyond
+/// ±16384), the reference encoder retries them forever, here they are encoded with scale 12
 pub fn dsp_encode_frame(
     pcm_in_out: &mut [i16; 16],
     sample_count: usize,
     coefs_in: &[[i16; 2]; 8],
+) -> [u8; 8] {
+    let pcm = pcm_in_out.map(i64::from);
+    let coef1 = coefs_in.map(|coefs| i64::from(coefs[0]));
+    let coef2 = coefs_in.map(|coefs| i64::from(coefs[1]));
+
+    /* the largest error of the prediction from the original samples gives the initial scale */
+    let mut scale = [0i64; 8];
+    for i in 0..8 {
+        let mut distance = 0i64;
+        for s in 0..sample_count {
+            let v1 = (pcm[s] * coef2[i] + pcm[s + 1] * coef1[i]) / 2048;
+            let v3 = (pcm[s + 2] - v1).clamp(i16::MIN.into(), i16::MAX.into());
+            if v3.abs() > distance.abs() {
+                distance = v3;
+            }
+        }
+        while scale[i] <= 12 && !(-8..=7).contains(&distance) {
+            scale[i] += 1;
+            distance /= 2;
+        }
+        scale[i] = if scale[i] <= 1 { -1 } else { scale[i] - 2 };
+    }
+
+    // the decoded samples and nibbles of each coefficient set, the sets that are done with
+    // retrying keep their result
+    let mut samples = [[0i64; 8]; 16];
+    samples[0] = [pcm[0]; 8];
+    samples[1] = [pcm[1]; 8];
+    let mut nibbles = [[0i64; 8]; 14];
+    let mut dist_accum = [0i64; 8];
+    let mut active = [true; 8];
+    while active.contains(&true) {
+        for i in 0..8 {
+            if active[i] {
+                scale[i] += 1;
+            }
+        }
+        let mut index = [0i64; 8];
+        let mut distance = [0i64; 8];
+        for s in 0..sample_count {
+            let [prev2, prev1, current] = [samples[s], samples[s + 1], samples[s + 2]];
+            let mut decoded_samples = current;
+            for i in 0..8 {
+                /* Multiply previous */
+                let v1 = prev2[i] * coef2[i] + prev1[i] * coef1[i];
+                /* Evaluate from real sample */
+                let v2 = ((pcm[s + 2] << 11) - v1) / 2048;
+                /* Round to nearest sample, clamp and set index */
+                let v3 = round_scaled(v2, scale[i]);
+                index[i] = index[i].max(-8 - v3).max(v3 - 7);
+                let v3 = v3.clamp(-8, 7);
+                /* Round and expand */
+                let decoded = ((v1 + ((v3 << scale[i]) << 11) + 1024) >> 11)
+                    .clamp(i16::MIN.into(), i16::MAX.into());
+                /* Accumulate distance */
+                let error = pcm[s + 2] - decoded;
+                distance[i] += error * error;
+                if active[i] {
+                    nibbles[s][i] = v3;
+                    decoded_samples[i] = decoded;
+                }
+            }
+            samples[s + 2] = decoded_samples;
+        }
+
+        for i in 0..8 {
+            if !active[i] {
+                continue;
+            }
+            dist_accum[i] = distance[i];
+            let frame_scale = scale[i];
+            let mut x = index[i] + 8;
+            while x > 256 {
+                scale[i] += 1;
+                if scale[i] >= 12 {
+                    scale[i] = 11;
+                }
+                x >>= 1;
+            }
+            // there is no larger scale to retry with
+            if frame_scale >= 12 {
+                scale[i] = frame_scale;
+                active[i] = false;
+            }
+            if !((scale[i] < 12) && (index[i] > 1)) {
+                active[i] = false;
+            }
+        }
+    }
+
+    let mut best_index = 0;
+    for i in 1..8 {
+        if dist_accum[i] < dist_accum[best_index] {
+            best_index = i;
+        }
+    }
+
+    /* Write converted samples */
+    for s in 2..(sample_count + 2) {
+        pcm_in_out[s] = samples[s][best_index] as i16;
+    }
+
+    let mut adpcm_out = [0; 8];
+
+    /* Write ps */
+    adpcm_out[0] = ((best_index << 4) | (scale[best_index] as usize & 0xF)) as u8;
+
+    /* Write output samples, the ones after the sample count are zero */
+    for y in 0..7 {
+        adpcm_out[y + 1] =
+            ((nibbles[y * 2][best_index] << 4) | (nibbles[y * 2 + 1][best_index] & 0xF)) as u8;
+    }
+
+    adpcm_out
+}
+
+/// partial frames kept by [`dsp_encode_frame_search`] after every sample
+const BEAM_WIDTH: usize = 16;
+
+/// a partial frame of the search
+#[derive(Clone, Copy)]
+struct Candidate {
+    /// squared error so far
+    error: i64,
+    /// the last 2 decoded samples, older one first
+    history: [i64; 2],
+    nibbles: [i64; 14],
+}
+
+/// the best nibbles for a coefficient pair and a scale with an error below `bound`, if any
+fn search_nibbles(
+    pcm: &[i64; 16],
+    sample_count: usize,
+    [coef1, coef2]: [i64; 2],
+    scale: i64,
+    bound: i64,
+) -> Option<Candidate> {
+    let mut beam = vec![Candidate {
+        error: 0,
+        history: [pcm[0], pcm[1]],
+        nibbles: [0; 14],
+    }];
+    let mut next = Vec::with_capacity(BEAM_WIDTH * 2);
+    for s in 0..sample_count {
+        next.clear();
+        for candidate in beam.iter() {
+            let v1 = candidate.history[0] * coef2 + candidate.history[1] * coef1;
+            // the decoded sample grows with the nibble, so the best ones are around the ideal one
+            let ideal = ((pcm[s + 2] << 11) - v1) >> (scale + 11);
+            let low = ideal.clamp(-8, 7);
+            let high = (ideal + 1).clamp(-8, 7);
+            for nibble in [low, high]
+                .into_iter()
+                .take(if low == high { 1 } else { 2 })
+            {
+                let decoded = ((v1 + ((nibble << scale) << 11) + 1024) >> 11)
+                    .clamp(i16::MIN.into(), i16::MAX.into());
+                let error = candidate.error + (pcm[s + 2] - decoded).pow(2);
+                // the error only grows, this can't beat what was already found
+                if error >= bound {
+                    continue;
+                }
+                let mut nibbles = candidate.nibbles;
+                nibbles[s] = nibble;
+                next.push(Candidate {
+                    error,
+                    history: [candidate.history[1], decoded],
+                    nibbles,
+                });
+            }
+        }
+        // candidates with the same history continue the same way, only the best one is needed
+        next.sort_by_key(|candidate| candidate.error);
+        beam.clear();
+        for candidate in next.iter() {
+            if beam.len() == BEAM_WIDTH {
+                break;
+            }
+            if !beam.iter().any(|kept| kept.history == candidate.history) {
+                beam.push(*candidate);
+            }
+        }
+        if beam.is_empty() {
+            return None;
+        }
+    }
+    beam.first().filter(|best| best.error < bound).copied()
+}
+
+/// like [`dsp_encode_frame`], but the nibbles aren't rounded one after another, instead every
+/// coefficient set and scale is searched for the nibbles with the smallest squared error of the
+/// whole frame. Never worse than [`dsp_encode_frame`], but a lot slower
+pub fn dsp_encode_frame_search(
+    pcm_in_out: &mut [i16; 16],
+    sample_count: usize,
+    coefs_in: &[[i16; 2]; 8],
+) -> [u8; 8] {
+    let pcm = pcm_in_out.map(i64::from);
+    let greedy = dsp_encode_frame(pcm_in_out, sample_count, coefs_in);
+    let mut best_error: i64 = (2..sample_count + 2)
+        .map(|s| (pcm[s] - i64::from(pcm_in_out[s])).pow(2))
+        .sum();
+
+    let mut best = None;
+    for (index, coefs) in coefs_in.iter().enumerate() {
+        // a set that was already tried gives the same result
+        if coefs_in[..index].contains(coefs) {
+            continue;
+        }
+        for scale in 0..=12 {
+            if let Some(found) =
+                search_nibbles(&pcm, sample_count, coefs.map(i64::from), scale, best_error)
+            {
+                best_error = found.error;
+                best = Some((index, scale, found));
+            }
+        }
+    }
+    let Some((index, scale, found)) = best else {
+        return greedy;
+    };
+
+    /* Write converted samples */
+    let [coef1, coef2] = coefs_in[index].map(i64::from);
+    let mut history = [pcm[0], pcm[1]];
+    for s in 0..sample_count {
+        let v1 = history[0] * coef2 + history[1] * coef1;
+        let decoded = ((v1 + ((found.nibbles[s] << scale) << 11) + 1024) >> 11)
+            .clamp(i16::MIN.into(), i16::MAX.into());
+        pcm_in_out[s + 2] = decoded as i16;
+        history = [history[1], decoded];
+    }
+
+    let mut adpcm_out = [0; 8];
+    adpcm_out[0] = ((index << 4) | scale as usize) as u8;
+    for y in 0..7 {
+        adpcm_out[y + 1] = ((found.nibbles[y * 2] << 4) | (found.nibbles[y * 2 + 1] & 0xF)) as u8;
+    }
+    adpcm_out
+}
+
+/// the original floating point version of [`dsp_encode_frame`], ported from the reference
+/// encoder. Gives the same frames, but a lot slower, only kept to compare against
+pub fn dsp_encode_frame_reference(
+    pcm_in_out: &mut [i16; 16],
+    sample_count: usize,
+    coefs_in: &[[i16; 2]; 8],
 ) -> [u8; 8] {
     let mut in_samples = [[0; 16]; 8];
     let mut out_samples = [[0; 14]; 8];
@@ -557,3 +883,153 @@ pub fn dsp_encode_frame(
 
     adpcm_out
 }
+
+#[cfg(test)]
+mod test {
+    use super::{
+        dsp_correlate_coefs, dsp_encode_frame, dsp_encode_frame_reference, dsp_encode_frame_search,
+        CorrelateState,
+    };
+
+    #[test]
+    pub fn integer_encoder_matches_reference() {
+        let mut state = 0x9E37_79B9u32;
+        let mut next = move || {
+            state ^= state << 13;
+            state ^= state >> 17;
+            state ^= state << 5;
+            state
+        };
+        // coefficients of real signals and random ones, including duplicates and the largest
+        // ones the reference encoder doesn't get stuck on
+        let signal: Vec<i16> = (0..20_000)
+            .map(|i| ((i * 37) % 3000) as i16 * 8 + (next() >> 22) as i16)
+            .collect();
+        let mut coef_sets = vec![dsp_correlate_coefs(&signal), [[0; 2]; 8]];
+        for _ in 0..50 {
+            let mut coefs = [[0i16; 2]; 8];
+            for pair in coefs.iter_mut() {
+                *pair = match next() % 4 {
+                    0 => [0x3FFF, -0x4000],
+                    1 => [(next() % 4096) as i16, -((next() % 2048) as i16)],
+                    _ => [next() as i16 / 2, next() as i16 / 2],
+                };
+            }
+            coef_sets.push(coefs);
+        }
+        for coefs in &coef_sets {
+            for _ in 0..2000 {
+                let sample_count = (next() % 15) as usize;
+                let amplitude = [1, 16, 2048, 65536][next() as usize % 4];
+                let mut frame = [0i16; 16];
+                for sample in frame[..sample_count + 2].iter_mut() {
+                    *sample = ((next() % amplitude) as i64 - amplitude as i64 / 2) as i16;
+                }
+                let mut reference_frame = frame;
+                let expected =
+                    dsp_encode_frame_reference(&mut reference_frame, sample_count, coefs);
+                assert_eq!(dsp_encode_frame(&mut frame, sample_count, coefs), expected);
+                assert_eq!(frame, reference_frame);
+            }
+        }
+    }
+
+    #[test]
+    pub fn extreme_coefficients() {
+        // the reference encoder never finishes these frames
+        let coefs = [[i16::MAX, i16::MIN]; 8];
+        let mut frame = [
+            i16::MIN,
+            i16::MAX,
+            i16::MIN,
+            i16::MAX,
+            0,
+            0,
+            0,
+            0,
+            0,
+            0,
+            0,
+            0,
+            0,
+            0,
+            0,
+            0,
+        ];
+        let encoded = dsp_encode_frame(&mut frame, 14, &coefs);
+        assert_eq!(encoded[0] & 0xF, 12);
+    }
+
+    #[test]
+    pub fn search_never_worse() {
+        let mut state = 0x2545_F491u32;
+        let mut next = move || {
+            state ^= state << 13;
+            state ^= state >> 17;
+            state ^= state << 5;
+            state
+        };
+        let signal: Vec<i16> = (0..20_000)
+            .map(|i| ((i * 37) % 3000) as i16 * 8 + (next() >> 22) as i16)
+            .collect();
+        let coefs = dsp_correlate_coefs(&signal);
+        let squared_error = |original: &[i16; 16], decoded: &[i16; 16]| -> i64 {
+            (2..16)
+                .map(|s| (original[s] as i64 - decoded[s] as i64).pow(2))
+                .sum()
+        };
+        let mut improved = 0;
+        for frame in signal.windows(16).step_by(14) {
+            let original: [i16; 16] = frame.try_into().unwrap();
+            let mut greedy = original;
+            dsp_encode_frame(&mut greedy, 14, &coefs);
+            let mut searched = original;
+            let encoded = dsp_encode_frame_search(&mut searched, 14, &coefs);
+            assert!(squared_error(&original, &searched) <= squared_error(&original, &greedy));
+            if squared_error(&original, &searched) < squared_error(&original, &greedy) {
+                improved += 1;
+            }
+            // the decoded samples belong to the frame
+            let mut decoded = Vec::new();
+            crate::adpcm::decode_adpcm_samples(
+                &encoded,
+                0..14,
+                0,
+                original[1],
+                original[0],
+                &coefs.concat().try_into().unwrap(),
+                &mut decoded,
+            );
+            assert_eq!(decoded, searched[2..]);
+        }
+        assert!(improved > 0);
+    }
+
+    #[test]
+    pub fn bounded_records() {
+        let signal: Vec<i16> = (0..14 * 1000)
+            .map(|i| ((i as f64 * 0.03).sin() * 8000.0 + ((i * 7) % 300) as f64) as i16)
+            .collect();
+        let mut full = CorrelateState::new();
+        full.push(&signal);
+        let mut bounded = CorrelateState {
+            max_records: 64,
+            ..CorrelateState::new()
+        };
+        for chunk in signal.chunks(333) {
+            bounded.push(chunk);
+        }
+        assert_eq!(full.record_count, bounded.record_count);
+        assert!(full.records.len() > 64);
+        assert!(bounded.records.len() <= 64);
+        assert!(bounded.record_stride > 1);
+        // evenly spread over the whole signal
+        let sampled: Vec<_> = full
+            .records
+            .iter()
+            .copied()
+            .step_by(bounded.record_stride as usize)
+            .collect();
+        assert_eq!(bounded.records, sampled);
+    }
+}
@@ -1,9 +1,20 @@
+pub mod adpcm;
+pub mod bcstm;
+pub mod bfstm;
+pub mod decoder;
+pub mod dsp;
 mod brstm;
 pub use brstm::*;
 pub mod encoder;
+mod error;
+pub use error::{Error, Result};
 mod gc_dspadpcm;
+pub mod repair;
 pub mod reshaper;
 pub mod structs;
+pub mod validate;
+pub mod view;
+pub mod wav;
 
 #[cfg(test)]
 mod tests {}
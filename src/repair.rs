@@ -0,0 +1,503 @@
+use std::{
+    fmt,
+    io::{self, Read, Seek, SeekFrom},
+};
+
+use thiserror::Error;
+
+use crate::{
+    adpcm::decode_adpcm_samples,
+    structs::{Codec, Head1},
+    BrstmInfoWithData, BrstmInformation,
+};
+
+// note: the repairs only touch what can be derived from the audio data itself,
+// the samples are never changed
+
+#[derive(Error, Debug)]
+#[non_exhaustive]
+pub enum RepairError {
+    #[error("Failed to read: {0}")]
+    Read(#[from] crate::Error),
+    #[error("IO error: {0}")]
+    Io(#[from] io::Error),
+    #[error("Block size {0} can't be used for the codec")]
+    InvalidBlockSize(u32),
+    /// the sample count is more than a block beyond the audio data, so it's most likely broken
+    /// itself, and there is nothing to recover
+    #[error("Header claims {total_samples} samples, but the audio data only has {available}")]
+    MissingData { total_samples: u32, available: u32 },
+}
+
+/// a single change made by [`repair`] or [`repair_file`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum Repair {
+    /// the section extends past the end of the file
+    SectionSize {
+        section: &'static str,
+        old: u32,
+        new: u32,
+    },
+    /// the track table or the channel count were rebuilt, see [`BrstmInformation::fix_tracks`]
+    Tracks,
+    LoopFlag {
+        old: u8,
+        new: u8,
+    },
+    LoopStart {
+        old: u32,
+        new: u32,
+    },
+    /// the sample count was cut to the audio data that's there
+    TotalSamples {
+        old: u32,
+        new: u32,
+    },
+    /// a field of the block layout didn't match the sample count
+    BlockLayout {
+        field: &'static str,
+        old: u32,
+        new: u32,
+    },
+    /// the audio data was padded with silence or cut to the length of the blocks
+    DataLength {
+        old: usize,
+        new: usize,
+    },
+    AdpcTableLength {
+        old: usize,
+        new: usize,
+    },
+    /// ADPC entries that didn't match the decoded samples before the block
+    AdpcHistory {
+        channel: u8,
+        entries: u32,
+    },
+    InitialPredictor {
+        channel: u8,
+        old: i16,
+        new: i16,
+    },
+    /// predictor/scale, history sample 1 and history sample 2 at the loop start
+    LoopContext {
+        channel: u8,
+        old: (i16, i16, i16),
+        new: (i16, i16, i16),
+    },
+}
+
+impl fmt::Display for Repair {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::SectionSize { section, old, new } => {
+                write!(f, "{section} section size {old:#X} -> {new:#X}")
+            }
+            Self::Tracks => write!(f, "rebuilt tracks"),
+            Self::LoopFlag { old, new } => write!(f, "loop flag {old} -> {new}"),
+            Self::LoopStart { old, new } => write!(f, "loop start {old} -> {new}"),
+            Self::TotalSamples { old, new } => write!(f, "total samples {old} -> {new}"),
+            Self::BlockLayout { field, old, new } => write!(f, "{field} {old} -> {new}"),
+            Self::DataLength { old, new } => write!(f, "audio data length {old:#X} -> {new:#X}"),
+            Self::AdpcTableLength { old, new } => {
+                write!(f, "ADPC table length {old:#X} -> {new:#X}")
+            }
+            Self::AdpcHistory { channel, entries } => {
+                write!(f, "channel {channel}: recomputed {entries} ADPC entries")
+            }
+            Self::InitialPredictor { channel, old, new } => {
+                write!(
+                    f,
+                    "channel {channel}: initial predictor {old:#X} -> {new:#X}"
+                )
+            }
+            Self::LoopContext { channel, old, new } => {
+                write!(f, "channel {channel}: loop context {old:?} -> {new:?}")
+            }
+        }
+    }
+}
+
+/// reads a file that might be broken, sections that extend past the end of the file are cut
+/// and the block layout isn't checked, then calls [`repair`]
+pub fn repair_file<RS: Read + Seek>(
+    f: &mut RS,
+) -> Result<(BrstmInfoWithData, Vec<Repair>), RepairError> {
+    f.seek(SeekFrom::Start(0))?;
+    let (mut info, _) = BrstmInformation::read_unchecked(f)?;
+    let file_len = f.seek(SeekFrom::End(0))?;
+    let mut repairs = Vec::new();
+    // the section sizes stored here don't include the section headers
+    let sections = [
+        ("ADPC", info.adpcm_offset, &mut info.adpcm_size, 8),
+        ("DATA", info.data_offset, &mut info.data_size, 0x20),
+    ];
+    for (section, offset, size, header_len) in sections {
+        let available = file_len.saturating_sub(offset.into());
+        if *size as u64 > available {
+            repairs.push(Repair::SectionSize {
+                section,
+                old: *size + header_len,
+                new: available as u32 + header_len,
+            });
+            *size = available as u32;
+        }
+    }
+    let mut brstm = info.into_with_data_unchecked(f)?;
+    repairs.extend(repair(&mut brstm)?);
+    Ok((brstm, repairs))
+}
+
+/// fixes everything that can be derived from the audio data: tracks, the loop, the block layout
+/// (moving the final blocks if their padding changes), the ADPC table, the initial predictor and
+/// the loop context. Returns every change that was made
+pub fn repair(brstm: &mut BrstmInfoWithData) -> Result<Vec<Repair>, RepairError> {
+    let mut repairs = Vec::new();
+    if brstm.info.fix_tracks() {
+        repairs.push(Repair::Tracks);
+    }
+
+    let old = brstm.info.info.clone();
+    let head1 = &mut brstm.info.info;
+    if head1.loop_flag > 1 {
+        head1.loop_flag = 1;
+        repairs.push(Repair::LoopFlag {
+            old: old.loop_flag,
+            new: 1,
+        });
+    }
+    head1
+        .derive_block_layout()
+        .map_err(|_| RepairError::InvalidBlockSize(old.blocks_size))?;
+    // the sample count can be as broken as everything else, so it's only trusted as far as the
+    // audio data goes. A missing final block is cut off, anything more is an error
+    let available = payload_samples(head1, brstm.data_bytes.len());
+    if head1.total_samples > available {
+        if head1.total_samples - available > head1.blocks_samples {
+            return Err(RepairError::MissingData {
+                total_samples: head1.total_samples,
+                available,
+            });
+        }
+        repairs.push(Repair::TotalSamples {
+            old: head1.total_samples,
+            new: available,
+        });
+        head1.total_samples = available;
+        head1
+            .derive_block_layout()
+            .map_err(|_| RepairError::InvalidBlockSize(old.blocks_size))?;
+    }
+    let layout_fields = [
+        ("total_blocks", old.total_blocks, head1.total_blocks),
+        ("blocks_samples", old.blocks_samples, head1.blocks_samples),
+        (
+            "final_block_samples",
+            old.final_block_samples,
+            head1.final_block_samples,
+        ),
+        (
+            "final_block_size",
+            old.final_block_size,
+            head1.final_block_size,
+        ),
+        (
+            "final_block_size_padded",
+            old.final_block_size_padded,
+            head1.final_block_size_padded,
+        ),
+        (
+            "adpc_samples_per_entry",
+            old.adpc_samples_per_entry,
+            head1.adpc_samples_per_entry,
+        ),
+    ];
+    for (field, old, new) in layout_fields {
+        if old != new {
+            repairs.push(Repair::BlockLayout { field, old, new });
+        }
+    }
+    if head1.is_looping() && head1.loop_start >= head1.total_samples {
+        // there is no way to know where it was supposed to loop, so loop the whole song
+        repairs.push(Repair::LoopStart {
+            old: head1.loop_start,
+            new: 0,
+        });
+        head1.loop_start = 0;
+    }
+    let head1 = brstm.info.info.clone();
+
+    // move the final blocks if only their padding was wrong
+    if old.final_block_size_padded != head1.final_block_size_padded
+        && old.total_blocks == head1.total_blocks
+        && brstm.data_bytes.len() == old.data_len()
+    {
+        let final_start = head1.block_data_offset(0, head1.total_blocks - 1);
+        let old_len = old.final_block_size_padded as usize;
+        let new_len = head1.final_block_size_padded as usize;
+        let final_blocks = brstm.data_bytes.split_off(final_start);
+        for channel in 0..head1.num_channels as usize {
+            let block = &final_blocks[channel * old_len..][..old_len.min(new_len)];
+            brstm.data_bytes.extend_from_slice(block);
+            brstm
+                .data_bytes
+                .resize(final_start + (channel + 1) * new_len, 0);
+        }
+    }
+    let data_len = head1.data_len();
+    if brstm.data_bytes.len() != data_len {
+        repairs.push(Repair::DataLength {
+            old: brstm.data_bytes.len(),
+            new: data_len,
+        });
+        brstm.data_bytes.resize(data_len, 0);
+    }
+
+    if head1.codec != Codec::Adpcm {
+        return Ok(repairs);
+    }
+    let adpc_len = head1.total_blocks as usize * head1.num_channels as usize * 4;
+    // the section is padded, so the table can be longer than needed
+    if brstm.adpcm_bytes.len() < adpc_len {
+        repairs.push(Repair::AdpcTableLength {
+            old: brstm.adpcm_bytes.len(),
+            new: adpc_len,
+        });
+        brstm.adpcm_bytes.resize(adpc_len, 0);
+    }
+    for channel in 0..head1.num_channels {
+        repair_channel(brstm, channel, &mut repairs);
+    }
+    Ok(repairs)
+}
+
+/// samples of a single channel that fit into the audio data, with the block size of the header.
+/// The final blocks are assumed to split the remaining bytes evenly, only full frames count
+fn payload_samples(head1: &Head1, data_len: usize) -> u32 {
+    let channels = u64::from(head1.num_channels.max(1));
+    let blocks_len = channels * u64::from(head1.blocks_size);
+    let full_blocks = data_len as u64 / blocks_len;
+    let final_block_len = (data_len as u64 % blocks_len / channels) as u32;
+    let samples = full_blocks * u64::from(head1.blocks_samples)
+        + u64::from(head1.codec.samples_for_bytes(final_block_len));
+    samples.try_into().unwrap_or(u32::MAX)
+}
+
+/// recomputes the history and predictors of a channel by decoding it from the start
+fn repair_channel(brstm: &mut BrstmInfoWithData, channel: u8, repairs: &mut Vec<Repair>) {
+    let head1 = brstm.info.info.clone();
+    let channel_info = brstm.info.channels[channel as usize].clone();
+    let mut samples = Vec::with_capacity(head1.total_samples as usize);
+    let mut changed_entries = 0;
+    for block_index in 0..head1.total_blocks {
+        let history = if block_index == 0 {
+            (channel_info.history_sample1, channel_info.history_sample2)
+        } else {
+            (samples[samples.len() - 1], samples[samples.len() - 2])
+        };
+        if brstm.get_adpc_values(channel, block_index) != history {
+            changed_entries += 1;
+            let offset =
+                (block_index as usize * head1.num_channels as usize + channel as usize) * 4;
+            brstm.adpcm_bytes[offset..][..2].copy_from_slice(&history.0.to_be_bytes());
+            brstm.adpcm_bytes[offset + 2..][..2].copy_from_slice(&history.1.to_be_bytes());
+        }
+        let (data, sample_count) = brstm.get_data_block_with_samplecount(channel, block_index);
+        decode_adpcm_samples(
+            data,
+            0..sample_count,
+            0,
+            history.0,
+            history.1,
+            &channel_info.adpcm_coefficients,
+            &mut samples,
+        );
+    }
+    if changed_entries > 0 {
+        repairs.push(Repair::AdpcHistory {
+            channel,
+            entries: changed_entries,
+        });
+    }
+
+    let channel_info = &mut brstm.info.channels[channel as usize];
+    let frame_header = |sample: u32| {
+        let block_index = sample / head1.blocks_samples;
+        let frame = (sample % head1.blocks_samples) / 14;
+        brstm.data_bytes[head1.block_data_offset(channel, block_index) + frame as usize * 8] as i16
+    };
+    if head1.total_samples > 0 {
+        let initial_predictor = frame_header(0);
+        if channel_info.initial_predictor != initial_predictor {
+            repairs.push(Repair::InitialPredictor {
+                channel,
+                old: channel_info.initial_predictor,
+                new: initial_predictor,
+            });
+            channel_info.initial_predictor = initial_predictor;
+        }
+    }
+    if head1.is_looping() && head1.loop_start < head1.total_samples {
+        let loop_start = head1.loop_start as usize;
+        let history = |offset: usize| {
+            loop_start
+                .checked_sub(offset)
+                .map_or(0, |sample| samples[sample])
+        };
+        let new = (frame_header(head1.loop_start), history(1), history(2));
+        let old = (
+            channel_info.loop_predictor,
+            channel_info.loop_history_sample1,
+            channel_info.loop_history_sample2,
+        );
+        if old != new {
+            repairs.push(Repair::LoopContext { channel, old, new });
+            (
+                channel_info.loop_predictor,
+                channel_info.loop_history_sample1,
+                channel_info.loop_history_sample2,
+            ) = new;
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::Cursor;
+
+    use crate::{encoder::encode_brstm, validate::validate};
+
+    use super::{repair, repair_file, Repair, RepairError};
+
+    #[test]
+    pub fn repair_broken_file() {
+        let channels: Vec<Vec<i16>> = (0..2)
+            .map(|c| {
+                (0..20_000)
+                    .map(|i| ((i as f32 / (8.0 + c as f32)).sin() * 10_000.0) as i16)
+                    .collect()
+            })
+            .collect();
+        let mut brstm = encode_brstm(&channels, 32000, Some(1005)).unwrap();
+        let expected_pcm = brstm.get_pcm(0);
+        // break the history, the initial predictor and the loop context
+        brstm.adpcm_bytes[8..12].copy_from_slice(&[1, 2, 3, 4]);
+        brstm.info.channels[0].initial_predictor ^= 1;
+        brstm.info.channels[1].loop_predictor = 0;
+        brstm.info.channels[1].loop_history_sample1 = 1234;
+        let mut file = Cursor::new(Vec::new());
+        brstm.write_brstm(&mut file).unwrap();
+        let mut file = file.into_inner();
+
+        // break the block count and claim the data section is bigger than the file
+        let head1_off = 0x40 + 8 + u32::from_be_bytes(file[0x4C..0x50].try_into().unwrap());
+        file[head1_off as usize + 0x14..][..4].copy_from_slice(&9u32.to_be_bytes());
+        let data_size = u32::from_be_bytes(file[0x24..0x28].try_into().unwrap());
+        file[0x24..0x28].copy_from_slice(&(data_size + 0x100).to_be_bytes());
+        assert!(!validate(&file).is_valid());
+
+        let (repaired, repairs) = repair_file(&mut Cursor::new(&file)).unwrap();
+        assert!(repairs.contains(&Repair::SectionSize {
+            section: "DATA",
+            old: data_size + 0x100,
+            new: data_size,
+        }));
+        assert!(repairs.contains(&Repair::BlockLayout {
+            field: "total_blocks",
+            old: 9,
+            new: 2,
+        }));
+        assert!(repairs
+            .iter()
+            .any(|r| matches!(r, Repair::LoopContext { channel: 1, .. })));
+        assert!(repairs
+            .iter()
+            .any(|r| matches!(r, Repair::AdpcHistory { channel: 0, .. })));
+        assert!(repairs
+            .iter()
+            .any(|r| matches!(r, Repair::InitialPredictor { channel: 0, .. })));
+
+        // after repairing, nothing is left to complain about and the audio is the same
+        let mut file = Cursor::new(Vec::new());
+        repaired.write_brstm(&mut file).unwrap();
+        let report = validate(file.get_ref());
+        assert!(report.findings.is_empty(), "{:?}", report.findings);
+        let pcm = repaired.get_pcm(0);
+        assert_eq!(pcm.len(), expected_pcm.len());
+
+        // nothing to repair the second time
+        let (_, repairs) = repair_file(&mut file).unwrap();
+        assert_eq!(repairs, Vec::new());
+    }
+
+    #[test]
+    pub fn repair_broken_sample_count() {
+        let channels = vec![vec![0; 20_000]; 2];
+        let brstm = encode_brstm(&channels, 32000, Some(1000)).unwrap();
+        let mut file = Cursor::new(Vec::new());
+        brstm.write_brstm(&mut file).unwrap();
+        let file = file.into_inner();
+        let head1_off = 0x40 + 8 + u32::from_be_bytes(file[0x4C..0x50].try_into().unwrap());
+        let total_samples_off = head1_off as usize + 0x0C;
+        // the padded final blocks have room for 20048 samples
+        let available = 20_048;
+
+        // a single broken byte claims billions of samples, which must not be allocated
+        let mut broken = file.clone();
+        broken[total_samples_off] = 0x7F;
+        assert!(matches!(
+            repair_file(&mut Cursor::new(&broken)),
+            Err(RepairError::MissingData {
+                available: 20_048,
+                ..
+            })
+        ));
+
+        // slightly more than there is gets cut to the audio data
+        let mut broken = file.clone();
+        broken[total_samples_off..][..4].copy_from_slice(&20_100u32.to_be_bytes());
+        let (repaired, repairs) = repair_file(&mut Cursor::new(&broken)).unwrap();
+        assert!(repairs.contains(&Repair::TotalSamples {
+            old: 20_100,
+            new: available,
+        }));
+        assert_eq!(repaired.info.info.total_samples, available);
+        let mut file = Cursor::new(Vec::new());
+        repaired.write_brstm(&mut file).unwrap();
+        let report = validate(file.get_ref());
+        assert!(report.is_valid(), "{:?}", report.findings);
+    }
+
+    #[test]
+    pub fn repair_final_block_padding() {
+        let channels = vec![
+            (0..20_000)
+                .map(|i| (i % 200) as i16 * 100)
+                .collect::<Vec<_>>();
+            2
+        ];
+        let mut brstm = encode_brstm(&channels, 32000, None).unwrap();
+        let expected_data = brstm.data_bytes.clone();
+        let head1 = brstm.info.info.clone();
+        // lay out the final blocks with 0x40 bytes too much padding
+        let final_start = head1.block_data_offset(0, head1.total_blocks - 1);
+        let padded = head1.final_block_size_padded as usize;
+        let final_blocks = brstm.data_bytes.split_off(final_start);
+        for block in final_blocks.chunks_exact(padded) {
+            brstm.data_bytes.extend_from_slice(block);
+            brstm.data_bytes.extend_from_slice(&[0; 0x40]);
+        }
+        brstm.info.info.final_block_size_padded += 0x40;
+
+        let repairs = repair(&mut brstm).unwrap();
+        assert!(repairs.contains(&Repair::BlockLayout {
+            field: "final_block_size_padded",
+            old: padded as u32 + 0x40,
+            new: padded as u32,
+        }));
+        assert!(!repairs
+            .iter()
+            .any(|r| matches!(r, Repair::DataLength { .. })));
+        assert_eq!(brstm.data_bytes, expected_data);
+    }
+}
@@ -178,12 +178,11 @@ pub fn reshape(
                 * brstm.info.info.blocks_size as usize
                 + brstm.info.info.final_block_size_padded as usize),
     );
+    // PCM streams don't need the ADPC history, so it might be missing
+    let has_adpc_table = brstm.adpcm_bytes.len()
+        >= brstm.info.info.total_blocks as usize * brstm.info.channels.len() * 4;
     for block_index in 0..brstm.info.info.total_blocks {
-        let block_size = if block_index == brstm.info.info.total_blocks - 1 {
-            brstm.info.info.final_block_size_padded
-        } else {
-            brstm.info.info.blocks_size
-        };
+        let block_size = brstm.info.info.block_byte_len(block_index);
         for channel in channel_reshape.iter() {
             match channel {
                 ReshapeSrc::Empty => {
@@ -192,7 +191,12 @@ pub fn reshape(
                     data_bytes.resize(data_bytes.len() + block_size as usize, 0);
                 }
                 ReshapeSrc::Channel(channel_ref) => {
-                    adpc_bytes.extend_from_slice(brstm.get_adpc_bytes(*channel_ref, block_index));
+                    if has_adpc_table {
+                        adpc_bytes
+                            .extend_from_slice(brstm.get_adpc_bytes(*channel_ref, block_index));
+                    } else {
+                        adpc_bytes.extend_from_slice(&[0; 4]);
+                    }
                     data_bytes.extend_from_slice(brstm.get_data_block(*channel_ref, block_index));
                 }
             }
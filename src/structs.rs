@@ -69,12 +69,67 @@ impl HeadSectionHeader {
     }
 }
 
+#[binrw]
+#[brw(big, repr = u8)]
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum Codec {
+    Pcm8 = 0,
+    Pcm16 = 1,
+    #[default]
+    Adpcm = 2,
+}
+
+impl Codec {
+    /// bytes of the smallest decodable unit, a single sample for PCM or an 8 byte ADPCM frame
+    pub fn frame_byte_len(self) -> u32 {
+        match self {
+            Self::Pcm8 => 1,
+            Self::Pcm16 => 2,
+            Self::Adpcm => 8,
+        }
+    }
+
+    /// samples in the smallest decodable unit
+    pub fn frame_samples(self) -> u32 {
+        match self {
+            Self::Pcm8 | Self::Pcm16 => 1,
+            Self::Adpcm => 14,
+        }
+    }
+
+    /// bytes needed to store the given amount of samples, rounded up to full frames
+    pub fn bytes_for_samples(self, samples: u32) -> u32 {
+        // saturates for sizes that can't exist in a file
+        samples
+            .div_ceil(self.frame_samples())
+            .saturating_mul(self.frame_byte_len())
+    }
+
+    /// samples that fit into the given amount of bytes, only full frames are counted
+    pub fn samples_for_bytes(self, bytes: u32) -> u32 {
+        (bytes / self.frame_byte_len()).saturating_mul(self.frame_samples())
+    }
+}
+
+impl TryFrom<u8> for Codec {
+    type Error = u8;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            0 => Ok(Self::Pcm8),
+            1 => Ok(Self::Pcm16),
+            2 => Ok(Self::Adpcm),
+            other => Err(other),
+        }
+    }
+}
+
 #[binrw]
 #[brw(big)]
 #[br(assert(adpc_bytes_per_entry == 4))]
-#[derive(Debug, Default, Clone)]
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
 pub struct Head1 {
-    pub codec: u8,
+    pub codec: Codec,
     pub loop_flag: u8,
     pub num_channels: u8,
     #[brw(pad_before = 1)]
@@ -97,6 +152,144 @@ impl Head1 {
     pub fn byte_len() -> u32 {
         52
     }
+
+    pub fn is_looping(&self) -> bool {
+        self.loop_flag != 0
+    }
+
+    pub fn duration_secs(&self) -> f64 {
+        if self.sample_rate == 0 {
+            return 0.0;
+        }
+        self.total_samples as f64 / self.sample_rate as f64
+    }
+
+    /// samples from the loop start to the end, None if the song doesn't loop
+    pub fn loop_length(&self) -> Option<u32> {
+        self.is_looping()
+            .then(|| self.total_samples.saturating_sub(self.loop_start))
+    }
+
+    pub fn is_final_block(&self, block_index: u32) -> bool {
+        block_index + 1 == self.total_blocks
+    }
+
+    /// bytes a block of a single channel takes, including the padding of the final block
+    pub fn block_byte_len(&self, block_index: u32) -> u32 {
+        if self.is_final_block(block_index) {
+            self.final_block_size_padded
+        } else {
+            self.blocks_size
+        }
+    }
+
+    /// samples of a single channel in the block
+    pub fn block_samples(&self, block_index: u32) -> u32 {
+        if self.is_final_block(block_index) {
+            self.final_block_samples
+        } else {
+            self.blocks_samples
+        }
+    }
+
+    /// full codec frames (see [`Codec::frame_byte_len`]) in a non final block
+    pub fn frames_per_block(&self) -> u32 {
+        self.blocks_size / self.codec.frame_byte_len()
+    }
+
+    /// offset of the block of the channel, relative to the start of the audio data
+    pub fn block_data_offset(&self, channel: u8, block_index: u32) -> usize {
+        let channels = self.num_channels as usize;
+        block_index as usize * channels * self.blocks_size as usize
+            + channel as usize * self.block_byte_len(block_index) as usize
+    }
+
+    /// bytes of the audio data of all channels, with the layout described by this header
+    pub fn data_len(&self) -> usize {
+        self.num_channels as usize
+            * (self.total_blocks.saturating_sub(1) as usize * self.blocks_size as usize
+                + self.final_block_size_padded as usize)
+    }
+
+    /// recomputes everything that follows from the codec, the sample count and the block size:
+    /// the samples per block, the block count, the final block and the ADPC entries
+    pub fn derive_block_layout(&mut self) -> Result<(), String> {
+        let frame_byte_len = self.codec.frame_byte_len();
+        if self.blocks_size == 0 || !self.blocks_size.is_multiple_of(frame_byte_len) {
+            return Err(format!(
+                "Block size {} isn't a multiple of the frame size {frame_byte_len}",
+                self.blocks_size
+            ));
+        }
+        self.blocks_samples = self.codec.samples_for_bytes(self.blocks_size);
+        // even a stream without samples has one (empty) block
+        self.total_blocks = self.total_samples.div_ceil(self.blocks_samples).max(1);
+        self.final_block_samples =
+            self.total_samples - (self.total_blocks - 1) * self.blocks_samples;
+        self.final_block_size = self.codec.bytes_for_samples(self.final_block_samples);
+        self.final_block_size_padded = (self.final_block_size + 0x1F) & !0x1F;
+        self.adpc_samples_per_entry = self.blocks_samples;
+        self.adpc_bytes_per_entry = 4;
+        Ok(())
+    }
+
+    /// makes sure the block layout is consistent with the codec and the sample count,
+    /// so that the helpers above can be relied on
+    pub fn validate(&self) -> Result<(), String> {
+        if self.loop_flag > 1 {
+            return Err(format!("Invalid loop flag {}", self.loop_flag));
+        }
+        // the loop needs at least one sample
+        if self.is_looping() && self.loop_start >= self.total_samples {
+            return Err(format!(
+                "Loop start {} isn't before the end {}",
+                self.loop_start, self.total_samples
+            ));
+        }
+        let frame_byte_len = self.codec.frame_byte_len();
+        if self.blocks_size == 0 || !self.blocks_size.is_multiple_of(frame_byte_len) {
+            return Err(format!(
+                "Block size {} isn't a multiple of the frame size {frame_byte_len}",
+                self.blocks_size
+            ));
+        }
+        let expected_block_samples = self.codec.samples_for_bytes(self.blocks_size);
+        if self.blocks_samples != expected_block_samples {
+            return Err(format!(
+                "Block samples {} don't match the block size, expected {expected_block_samples}",
+                self.blocks_samples
+            ));
+        }
+        let expected_blocks = self.total_samples.div_ceil(self.blocks_samples);
+        if self.total_blocks != expected_blocks
+            && !(self.total_samples == 0 && self.total_blocks == 1)
+        {
+            return Err(format!(
+                "Block count {} doesn't match the sample count {}, expected {expected_blocks}",
+                self.total_blocks, self.total_samples
+            ));
+        }
+        if self.total_blocks > 0 {
+            let expected_final_samples =
+                self.total_samples - (self.total_blocks - 1) * self.blocks_samples;
+            if self.final_block_samples != expected_final_samples {
+                return Err(format!(
+                    "Final block samples {} should be {expected_final_samples}",
+                    self.final_block_samples
+                ));
+            }
+            let needed = self.codec.bytes_for_samples(self.final_block_samples);
+            if self.final_block_size > self.final_block_size_padded
+                || self.final_block_size_padded < needed
+            {
+                return Err(format!(
+                    "Final block size {} (padded {}) can't hold {} samples",
+                    self.final_block_size, self.final_block_size_padded, self.final_block_samples
+                ));
+            }
+        }
+        Ok(())
+    }
 }
 
 #[binrw]
@@ -138,7 +331,7 @@ impl TrackInfoOffset {
 
 #[binrw]
 #[brw(big)]
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct TrackDescriptionV1 {
     pub track_volume: u8,
     #[brw(pad_after = 6)]
@@ -157,7 +350,7 @@ impl Default for TrackDescriptionV1 {
 #[binrw]
 #[brw(big)]
 #[br(import(version: u8))]
-#[derive(Debug, Default, Clone)]
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
 pub struct TrackDescription {
     #[br(if(version == 1))]
     pub info_v1: Option<TrackDescriptionV1>,
@@ -192,7 +385,7 @@ impl TrackDescription {
     }
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub enum Channels {
     Mono(u8),
     Stereo(u8, u8),
@@ -270,7 +463,7 @@ impl ChannelInfoOffset {
 
 #[binrw]
 #[brw(big)]
-#[derive(Debug, Default, Clone)]
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
 pub struct AdpcmChannelInformation {
     #[br(temp)]
     #[bw(calc = 0x0100_0000)]
@@ -334,10 +527,10 @@ pub fn read_data_section<R: Read + Seek>(r: &mut R) -> BinResult<Vec<u8>> {
 mod test {
     use std::io::Cursor;
 
-    use binrw::BinWriterExt;
+    use binrw::{BinReaderExt, BinWriterExt};
 
     use crate::structs::{
-        AdpcmChannelInformation, BrstmHeader, ChannelInfoOffset, Head1, Head2, Head3,
+        AdpcmChannelInformation, BrstmHeader, ChannelInfoOffset, Codec, Head1, Head2, Head3,
         HeadSectionHeader, TrackInfoOffset,
     };
 
@@ -387,4 +580,68 @@ mod test {
         Cursor::new(&mut buf).write_be(&channel_info).unwrap();
         assert_eq!(AdpcmChannelInformation::byte_len() as usize, buf.len());
     }
+
+    #[test]
+    pub fn head1_validation() {
+        let mut head1 = Head1 {
+            codec: Codec::Adpcm,
+            loop_flag: 1,
+            num_channels: 2,
+            sample_rate: 32000,
+            loop_start: 1000,
+            total_samples: 20000,
+            total_blocks: 2,
+            blocks_size: 8192,
+            blocks_samples: 14336,
+            final_block_size: 3296,
+            final_block_samples: 5664,
+            final_block_size_padded: 3296,
+            adpc_samples_per_entry: 14336,
+            adpc_bytes_per_entry: 4,
+            audio_offset: 0,
+        };
+        assert_eq!(Ok(()), head1.validate());
+        assert_eq!(Some(19000), head1.loop_length());
+        assert_eq!(1024, head1.frames_per_block());
+        assert_eq!(3296, head1.block_byte_len(1));
+        assert_eq!(2 * 8192 + 3296, head1.block_data_offset(1, 1));
+
+        // the loop needs at least one sample
+        head1.loop_start = 20000;
+        assert!(head1.validate().is_err());
+        head1.loop_start = 1000;
+
+        let mut buf = Vec::new();
+        Cursor::new(&mut buf).write_be(&head1).unwrap();
+        buf[0] = 3;
+        assert!(Cursor::new(&buf).read_be::<Head1>().is_err());
+
+        head1.total_blocks = 3;
+        assert!(head1.validate().is_err());
+
+        head1.final_block_samples = 0;
+        head1.final_block_size_padded = 0;
+        head1.derive_block_layout().unwrap();
+        assert_eq!(Ok(()), head1.validate());
+        assert_eq!(2, head1.total_blocks);
+        assert_eq!(5664, head1.final_block_samples);
+        // 405 frames, padded to 32 bytes
+        assert_eq!(3240, head1.final_block_size);
+        assert_eq!(3264, head1.final_block_size_padded);
+        assert_eq!(2 * (8192 + 3264), head1.data_len());
+
+        head1.total_samples = 0;
+        head1.derive_block_layout().unwrap();
+        assert_eq!(
+            (1, 0, 0),
+            (
+                head1.total_blocks,
+                head1.final_block_samples,
+                head1.data_len()
+            )
+        );
+
+        head1.blocks_size = 8190;
+        assert!(head1.derive_block_layout().is_err());
+    }
 }
@@ -0,0 +1,867 @@
+use std::{fmt, io::Cursor};
+
+use binrw::BinReaderExt;
+
+use crate::{
+    adpcm::decode_adpcm_samples,
+    structs::{
+        AdpcmChannelInformation, BrstmHeader, Codec, Head1, Head2, Head3, HeadSectionHeader,
+        TrackDescription,
+    },
+};
+
+// note: all offsets in the findings are absolute offsets into the file.
+// Errors are things that break playback or parsing, warnings are things that are unusual but
+// still play (e.g. slightly wrong history, which only causes clicks)
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Severity {
+    Warning,
+    Error,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum FindingKind {
+    /// a structure couldn't be parsed at all, no further checks depending on it were done
+    Parse,
+    /// section offsets and sizes, the file length
+    Sections,
+    Alignment,
+    /// block math of the header vs the sample count and the codec
+    BlockLayout,
+    AdpcTable,
+    Loop,
+    Tracks,
+    Coefficients,
+    PredictorScale,
+    /// history samples that don't match the decoded audio
+    History,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Finding {
+    pub severity: Severity,
+    pub kind: FindingKind,
+    pub offset: Option<u64>,
+    pub message: String,
+}
+
+impl fmt::Display for Finding {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?} ({:?})", self.severity, self.kind)?;
+        if let Some(offset) = self.offset {
+            write!(f, " at {offset:#X}")?;
+        }
+        write!(f, ": {}", self.message)
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct ValidationReport {
+    pub findings: Vec<Finding>,
+}
+
+impl ValidationReport {
+    /// no errors were found, there might still be warnings
+    pub fn is_valid(&self) -> bool {
+        self.errors().next().is_none()
+    }
+
+    pub fn errors(&self) -> impl Iterator<Item = &Finding> {
+        self.findings
+            .iter()
+            .filter(|f| f.severity == Severity::Error)
+    }
+
+    pub fn warnings(&self) -> impl Iterator<Item = &Finding> {
+        self.findings
+            .iter()
+            .filter(|f| f.severity == Severity::Warning)
+    }
+
+    fn push(&mut self, severity: Severity, kind: FindingKind, offset: u64, message: String) {
+        self.findings.push(Finding {
+            severity,
+            kind,
+            offset: Some(offset),
+            message,
+        });
+    }
+
+    fn error(&mut self, kind: FindingKind, offset: u64, message: String) {
+        self.push(Severity::Error, kind, offset, message);
+    }
+
+    fn warning(&mut self, kind: FindingKind, offset: u64, message: String) {
+        self.push(Severity::Warning, kind, offset, message);
+    }
+}
+
+// offsets of the fields in Head1
+const HEAD1_LOOP_FLAG: u64 = 0x01;
+const HEAD1_NUM_CHANNELS: u64 = 0x02;
+const HEAD1_SAMPLE_RATE: u64 = 0x04;
+const HEAD1_LOOP_START: u64 = 0x08;
+const HEAD1_AUDIO_OFFSET: u64 = 0x10;
+const HEAD1_TOTAL_BLOCKS: u64 = 0x14;
+const HEAD1_BLOCKS_SIZE: u64 = 0x18;
+const HEAD1_BLOCKS_SAMPLES: u64 = 0x1C;
+const HEAD1_FINAL_BLOCK_SIZE: u64 = 0x20;
+const HEAD1_FINAL_BLOCK_SAMPLES: u64 = 0x24;
+const HEAD1_FINAL_BLOCK_SIZE_PADDED: u64 = 0x28;
+const HEAD1_ADPC_SAMPLES_PER_ENTRY: u64 = 0x2C;
+
+// offsets of the fields in AdpcmChannelInformation
+const CHANNEL_COEFFICIENTS_OFFSET: u64 = 0x04;
+const CHANNEL_COEFFICIENTS: u64 = 0x08;
+const CHANNEL_GAIN: u64 = 0x28;
+const CHANNEL_INITIAL_PREDICTOR: u64 = 0x2A;
+const CHANNEL_HISTORY: u64 = 0x2C;
+const CHANNEL_LOOP_PREDICTOR: u64 = 0x30;
+const CHANNEL_LOOP_HISTORY: u64 = 0x32;
+
+/// checks everything in a BRSTM file that a console relies on: the section layout, the block math,
+/// the ADPC table, tracks, the loop, the coefficients and the ADPCM frames and history.
+/// Parsing is lenient, so this also works on files that [`crate::BrstmInformation::from_reader`]
+/// rejects
+pub fn validate(bytes: &[u8]) -> ValidationReport {
+    let mut report = ValidationReport::default();
+    validate_into(bytes, &mut report);
+    report
+}
+
+fn read_at<T>(bytes: &[u8], offset: u64) -> binrw::BinResult<T>
+where
+    T: binrw::BinRead + binrw::meta::ReadEndian,
+    for<'a> T::Args<'a>: Default,
+{
+    let mut cursor = Cursor::new(bytes);
+    cursor.set_position(offset);
+    cursor.read_be()
+}
+
+fn validate_into(bytes: &[u8], report: &mut ValidationReport) {
+    use FindingKind::*;
+
+    let header: BrstmHeader = match read_at(bytes, 0) {
+        Ok(header) => header,
+        Err(e) => {
+            report.error(Parse, 0, format!("Invalid file header: {e}"));
+            return;
+        }
+    };
+    let file_len = bytes.len() as u64;
+    let header_file_len = header.file_length as u64;
+    if header_file_len > file_len {
+        report.error(
+            Sections,
+            0x08,
+            format!(
+                "File length is {header_file_len:#X}, but the file is only {file_len:#X} bytes"
+            ),
+        );
+    } else if header_file_len < file_len {
+        report.warning(
+            Sections,
+            0x08,
+            format!("File length is {header_file_len:#X}, but the file is {file_len:#X} bytes"),
+        );
+    }
+    let end = header_file_len.min(file_len);
+
+    let sections = [
+        (b"HEAD", 0x10, header.head_offset, header.head_size),
+        (b"ADPC", 0x18, header.adpc_offset, header.adpc_size),
+        (b"DATA", 0x20, header.data_offset, header.data_size),
+    ];
+    let mut sections_ok = [true; 3];
+    for (idx, (magic, field_offset, offset, size)) in sections.iter().enumerate() {
+        let name = std::str::from_utf8(*magic).unwrap();
+        // PCM streams might not have an ADPC section
+        if *magic == b"ADPC" && *offset == 0 && *size == 0 {
+            continue;
+        }
+        let (offset, size) = (*offset as u64, *size as u64);
+        if offset % 0x20 != 0 {
+            report.error(
+                Alignment,
+                *field_offset,
+                format!("{name} section offset {offset:#X} isn't aligned to 0x20"),
+            );
+        }
+        if size % 0x20 != 0 {
+            report.warning(
+                Alignment,
+                field_offset + 4,
+                format!("{name} section size {size:#X} isn't aligned to 0x20"),
+            );
+        }
+        if offset + size > end || size < 8 {
+            report.error(
+                Sections,
+                *field_offset,
+                format!(
+                    "{name} section {offset:#X}..{:#X} is out of bounds",
+                    offset + size
+                ),
+            );
+            sections_ok[idx] = false;
+        } else if &bytes[offset as usize..][..4] != *magic {
+            report.error(
+                Sections,
+                offset,
+                format!("Expected the {name} section at {offset:#X}"),
+            );
+            sections_ok[idx] = false;
+        }
+    }
+    let mut ordered: Vec<_> = sections
+        .iter()
+        .filter(|(_, _, offset, size)| *offset != 0 || *size != 0)
+        .collect();
+    ordered.sort_by_key(|(_, _, offset, _)| *offset);
+    for pair in ordered.windows(2) {
+        let (first, _, first_offset, first_size) = pair[0];
+        let (second, _, second_offset, _) = pair[1];
+        if *first_offset as u64 + *first_size as u64 > *second_offset as u64 {
+            report.error(
+                Sections,
+                *second_offset as u64,
+                format!(
+                    "{} section overlaps the {} section",
+                    std::str::from_utf8(*first).unwrap(),
+                    std::str::from_utf8(*second).unwrap()
+                ),
+            );
+        }
+    }
+    if !sections_ok[0] {
+        return;
+    }
+
+    // HEAD section
+    let head_offset = header.head_offset as u64;
+    let head: HeadSectionHeader = match read_at(bytes, head_offset) {
+        Ok(head) => head,
+        Err(e) => {
+            report.error(Parse, head_offset, format!("Invalid HEAD section: {e}"));
+            return;
+        }
+    };
+    let head_base = head_offset + 8;
+    let mut chunk_offsets = [0; 3];
+    for (idx, chunk) in head.head_chunks.iter().enumerate() {
+        let offset = chunk.head_chunk_offset as u64;
+        if offset + 8 > header.head_size as u64 {
+            report.error(
+                Sections,
+                head_offset + 8 + idx as u64 * 8 + 4,
+                format!(
+                    "HEAD chunk {} at {offset:#X} is outside the section",
+                    idx + 1
+                ),
+            );
+            return;
+        }
+        chunk_offsets[idx] = head_base + offset;
+    }
+
+    let head1_off = chunk_offsets[0];
+    let head1: Head1 = match read_at(bytes, head1_off) {
+        Ok(head1) => head1,
+        Err(e) => {
+            report.error(Parse, head1_off, format!("Invalid HEAD chunk 1: {e}"));
+            return;
+        }
+    };
+    if head1.loop_flag > 1 {
+        report.error(
+            Loop,
+            head1_off + HEAD1_LOOP_FLAG,
+            format!("Invalid loop flag {}", head1.loop_flag),
+        );
+    }
+    if head1.is_looping() && head1.loop_start >= head1.total_samples {
+        report.error(
+            Loop,
+            head1_off + HEAD1_LOOP_START,
+            format!(
+                "Loop start {} isn't before the end {}",
+                head1.loop_start, head1.total_samples
+            ),
+        );
+    }
+    if head1.sample_rate == 0 {
+        report.error(
+            BlockLayout,
+            head1_off + HEAD1_SAMPLE_RATE,
+            "Sample rate is 0".into(),
+        );
+    }
+    let audio_start = u64::from(header.data_offset) + 0x20;
+    if u64::from(head1.audio_offset) != audio_start {
+        report.error(
+            Sections,
+            head1_off + HEAD1_AUDIO_OFFSET,
+            format!(
+                "Audio offset {:#X} should be right after the DATA header at {audio_start:#X}",
+                head1.audio_offset,
+            ),
+        );
+    }
+    let mut layout = head1.clone();
+    let layout_ok = match layout.derive_block_layout() {
+        Ok(()) => {
+            let checks = [
+                (
+                    "Samples per block",
+                    HEAD1_BLOCKS_SAMPLES,
+                    head1.blocks_samples,
+                    layout.blocks_samples,
+                ),
+                (
+                    "Block count",
+                    HEAD1_TOTAL_BLOCKS,
+                    head1.total_blocks,
+                    layout.total_blocks,
+                ),
+                (
+                    "Final block samples",
+                    HEAD1_FINAL_BLOCK_SAMPLES,
+                    head1.final_block_samples,
+                    layout.final_block_samples,
+                ),
+                (
+                    "Padded final block size",
+                    HEAD1_FINAL_BLOCK_SIZE_PADDED,
+                    head1.final_block_size_padded,
+                    layout.final_block_size_padded,
+                ),
+                (
+                    "Samples per ADPC entry",
+                    HEAD1_ADPC_SAMPLES_PER_ENTRY,
+                    head1.adpc_samples_per_entry,
+                    layout.adpc_samples_per_entry,
+                ),
+            ];
+            let mut ok = true;
+            for (name, field_offset, actual, expected) in checks {
+                if actual != expected {
+                    report.error(
+                        BlockLayout,
+                        head1_off + field_offset,
+                        format!(
+                            "{name} is {actual}, but should be {expected} for {} samples",
+                            head1.total_samples
+                        ),
+                    );
+                    ok = false;
+                }
+            }
+            // only the padded size is used for the layout
+            if head1.final_block_size != layout.final_block_size {
+                report.warning(
+                    BlockLayout,
+                    head1_off + HEAD1_FINAL_BLOCK_SIZE,
+                    format!(
+                        "Final block size is {}, but should be {}",
+                        head1.final_block_size, layout.final_block_size
+                    ),
+                );
+            }
+            ok
+        }
+        Err(message) => {
+            report.error(BlockLayout, head1_off + HEAD1_BLOCKS_SIZE, message);
+            false
+        }
+    };
+
+    // tracks and channels
+    let head2_off = chunk_offsets[1];
+    let head2: Head2 = match read_at(bytes, head2_off) {
+        Ok(head2) => head2,
+        Err(e) => {
+            report.error(Parse, head2_off, format!("Invalid HEAD chunk 2: {e}"));
+            return;
+        }
+    };
+    let head3_off = chunk_offsets[2];
+    let head3: Head3 = match read_at(bytes, head3_off) {
+        Ok(head3) => head3,
+        Err(e) => {
+            report.error(Parse, head3_off, format!("Invalid HEAD chunk 3: {e}"));
+            return;
+        }
+    };
+    let mut channels = Vec::with_capacity(head3.info_offsets.len());
+    for (idx, channel_off) in head3.info_offsets.iter().enumerate() {
+        let offset = head_base + channel_off.offset as u64;
+        match read_at::<AdpcmChannelInformation>(bytes, offset) {
+            Ok(channel) => channels.push((offset, channel)),
+            Err(e) => {
+                report.error(
+                    Parse,
+                    offset,
+                    format!("Invalid channel information {idx}: {e}"),
+                );
+                return;
+            }
+        }
+    }
+    if head1.num_channels as usize != channels.len() {
+        report.error(
+            Tracks,
+            head1_off + HEAD1_NUM_CHANNELS,
+            format!(
+                "Header has {} channels, but there are {} channel informations",
+                head1.num_channels,
+                channels.len()
+            ),
+        );
+    }
+    if head2.track_info_offsets.is_empty() {
+        report.warning(Tracks, head2_off, "No tracks".into());
+    }
+    let mut referenced = vec![false; channels.len()];
+    for (idx, track_off) in head2.track_info_offsets.iter().enumerate() {
+        let offset = head_base + track_off.offset as u64;
+        if track_off.track_info_type != head2.track_info_type {
+            report.error(
+                Tracks,
+                head2_off + 4 + idx as u64 * 8 + 1,
+                format!(
+                    "Track {idx} has type {}, but the header says {}",
+                    track_off.track_info_type, head2.track_info_type
+                ),
+            );
+            continue;
+        }
+        let mut cursor = Cursor::new(bytes);
+        cursor.set_position(offset);
+        let track = match cursor.read_be_args::<TrackDescription>((track_off.track_info_type,)) {
+            Ok(track) => track,
+            Err(e) => {
+                report.error(Parse, offset, format!("Invalid track {idx}: {e}"));
+                continue;
+            }
+        };
+        let ids = [
+            track.channels.left_channel_id(),
+            track.channels.right_channel_id(),
+        ];
+        for channel in ids.iter().take(track.channels.channels().into()) {
+            match referenced.get_mut(*channel as usize) {
+                Some(referenced) => *referenced = true,
+                None => report.error(
+                    Tracks,
+                    offset,
+                    format!("Track {idx} references channel {channel} that doesn't exist"),
+                ),
+            }
+        }
+    }
+    for (channel, referenced) in referenced.iter().enumerate() {
+        if !referenced {
+            report.warning(
+                Tracks,
+                channels[channel].0,
+                format!("Channel {channel} isn't used by any track"),
+            );
+        }
+    }
+
+    if head1.codec == Codec::Adpcm {
+        for (idx, (offset, channel)) in channels.iter().enumerate() {
+            check_coefficients(report, idx, *offset, head_base, channel);
+        }
+    }
+
+    // ADPC and DATA sections
+    if !layout_ok {
+        return;
+    }
+    let channel_count = channels.len().min(head1.num_channels.into());
+    let adpc_entries_ok = if head1.codec == Codec::Adpcm {
+        let needed = layout.total_blocks as u64 * head1.num_channels as u64 * 4;
+        let available = if sections_ok[1] {
+            (header.adpc_size as u64).saturating_sub(8)
+        } else {
+            0
+        };
+        if available < needed {
+            report.error(
+                AdpcTable,
+                0x1C,
+                format!(
+                    "ADPC table has {available:#X} bytes, but {} blocks of {} channels need {needed:#X}",
+                    layout.total_blocks, head1.num_channels
+                ),
+            );
+        }
+        available >= needed
+    } else {
+        false
+    };
+    let data_start = header.data_offset as u64 + 0x20;
+    let needed = layout.data_len() as u64;
+    let available = if sections_ok[2] {
+        (header.data_size as u64).saturating_sub(0x20)
+    } else {
+        0
+    };
+    if available < needed {
+        report.error(
+            Sections,
+            0x24,
+            format!(
+                "DATA section has {available:#X} bytes of audio, but the blocks need {needed:#X}"
+            ),
+        );
+        return;
+    } else if available >= needed + 0x20 {
+        report.warning(
+            Sections,
+            0x24,
+            format!(
+                "DATA section has {available:#X} bytes of audio, but the blocks only need {needed:#X}"
+            ),
+        );
+    }
+    // nothing to check without the audio data, which can't be trusted to be in the file
+    // if the section isn't
+    if head1.codec != Codec::Adpcm || !sections_ok[2] {
+        return;
+    }
+    let Some(data) = usize::try_from(data_start)
+        .ok()
+        .and_then(|start| bytes.get(start..)?.get(..needed as usize))
+    else {
+        return;
+    };
+    let adpc = bytes.get((header.adpc_offset as usize).saturating_add(8)..);
+    for (idx, (info_offset, channel)) in channels.iter().enumerate().take(channel_count) {
+        check_frames(
+            report,
+            &layout,
+            idx as u8,
+            data,
+            data_start,
+            *info_offset,
+            channel,
+        );
+        if let (true, Some(adpc)) = (adpc_entries_ok, adpc) {
+            check_history(
+                report,
+                &layout,
+                idx as u8,
+                data,
+                adpc,
+                header.adpc_offset as u64 + 8,
+                *info_offset,
+                channel,
+            );
+        }
+    }
+}
+
+fn check_coefficients(
+    report: &mut ValidationReport,
+    idx: usize,
+    offset: u64,
+    head_base: u64,
+    channel: &AdpcmChannelInformation,
+) {
+    use FindingKind::Coefficients;
+
+    // the coefficients directly follow the offset
+    let expected_offset = offset - head_base + CHANNEL_COEFFICIENTS;
+    if channel.channel_adpcm_coefficients_offset as u64 != expected_offset {
+        report.warning(
+            Coefficients,
+            offset + CHANNEL_COEFFICIENTS_OFFSET,
+            format!(
+                "Channel {idx} coefficient offset is {:#X}, should be {expected_offset:#X}",
+                channel.channel_adpcm_coefficients_offset
+            ),
+        );
+    }
+    if channel.adpcm_coefficients.iter().all(|c| *c == 0) {
+        report.warning(
+            Coefficients,
+            offset + CHANNEL_COEFFICIENTS,
+            format!("Channel {idx} has only zero coefficients"),
+        );
+    }
+    for (pair_idx, pair) in channel.adpcm_coefficients.chunks_exact(2).enumerate() {
+        // the predictor is y = (c1 * y1 + c2 * y2) / 2048, which only stays bounded if
+        // |c2| <= 2048 and |c1| <= 2048 - c2
+        let (c1, c2) = (pair[0] as i32, pair[1] as i32);
+        if c2.abs() > 2048 || c1.abs() > 2048 - c2 {
+            report.warning(
+                Coefficients,
+                offset + CHANNEL_COEFFICIENTS + pair_idx as u64 * 4,
+                format!("Channel {idx} coefficient pair {pair_idx} ({c1}, {c2}) is unstable"),
+            );
+        }
+    }
+    if channel.gain != 0 {
+        report.warning(
+            Coefficients,
+            offset + CHANNEL_GAIN,
+            format!("Channel {idx} has gain {}, should be 0", channel.gain),
+        );
+    }
+}
+
+/// checks the predictor/scale header of every frame
+fn check_frames(
+    report: &mut ValidationReport,
+    layout: &Head1,
+    channel: u8,
+    data: &[u8],
+    data_start: u64,
+    info_offset: u64,
+    channel_info: &AdpcmChannelInformation,
+) {
+    use FindingKind::PredictorScale;
+
+    let mut bad_predictor = (0, None);
+    let mut bad_scale = (0, None);
+    for block_index in 0..layout.total_blocks {
+        let block_offset = layout.block_data_offset(channel, block_index);
+        let frames = layout.block_samples(block_index).div_ceil(14);
+        for frame in 0..frames {
+            let offset = block_offset + frame as usize * 8;
+            let ps = data[offset];
+            if ps >> 4 > 7 {
+                bad_predictor.0 += 1;
+                bad_predictor.1.get_or_insert(offset as u64 + data_start);
+            }
+            if ps & 0xF > 12 {
+                bad_scale.0 += 1;
+                bad_scale.1.get_or_insert(offset as u64 + data_start);
+            }
+        }
+    }
+    if let (count, Some(offset)) = bad_predictor {
+        report.error(
+            PredictorScale,
+            offset,
+            format!("Channel {channel} has {count} frames with a predictor index above 7"),
+        );
+    }
+    if let (count, Some(offset)) = bad_scale {
+        report.warning(
+            PredictorScale,
+            offset,
+            format!("Channel {channel} has {count} frames with a scale above 12"),
+        );
+    }
+
+    if layout.total_samples > 0 {
+        let first = data[layout.block_data_offset(channel, 0)];
+        if channel_info.initial_predictor != first as i16 {
+            report.warning(
+                PredictorScale,
+                info_offset + CHANNEL_INITIAL_PREDICTOR,
+                format!(
+                    "Channel {channel} initial predictor/scale {:#X} doesn't match the first frame {first:#X}",
+                    channel_info.initial_predictor
+                ),
+            );
+        }
+    }
+    if layout.is_looping() && layout.loop_start < layout.total_samples {
+        let block_index = layout.loop_start / layout.blocks_samples;
+        let frame = (layout.loop_start % layout.blocks_samples) / 14;
+        let loop_frame = data[layout.block_data_offset(channel, block_index) + frame as usize * 8];
+        if channel_info.loop_predictor != loop_frame as i16 {
+            report.warning(
+                PredictorScale,
+                info_offset + CHANNEL_LOOP_PREDICTOR,
+                format!(
+                    "Channel {channel} loop predictor/scale {:#X} doesn't match the loop frame {loop_frame:#X}",
+                    channel_info.loop_predictor
+                ),
+            );
+        }
+    }
+}
+
+/// decodes the channel and checks that the history of every block and of the loop
+/// matches the samples before it
+#[allow(clippy::too_many_arguments)]
+fn check_history(
+    report: &mut ValidationReport,
+    layout: &Head1,
+    channel: u8,
+    data: &[u8],
+    adpc: &[u8],
+    adpc_start: u64,
+    info_offset: u64,
+    channel_info: &AdpcmChannelInformation,
+) {
+    use FindingKind::History;
+
+    let entry_offset = |block_index: u32| {
+        (block_index as usize * layout.num_channels as usize + channel as usize) * 4
+    };
+    let entry = |block_index: u32| {
+        let b = &adpc[entry_offset(block_index)..][..4];
+        (
+            i16::from_be_bytes([b[0], b[1]]),
+            i16::from_be_bytes([b[2], b[3]]),
+        )
+    };
+    let coeffs = &channel_info.adpcm_coefficients;
+    let mut samples = Vec::with_capacity(layout.total_samples as usize);
+    let mut mismatches = (0, None);
+    for block_index in 0..layout.total_blocks {
+        let (yn1, yn2) = entry(block_index);
+        let expected = if block_index == 0 {
+            (channel_info.history_sample1, channel_info.history_sample2)
+        } else {
+            (samples[samples.len() - 1], samples[samples.len() - 2])
+        };
+        if (yn1, yn2) != expected {
+            mismatches.0 += 1;
+            mismatches
+                .1
+                .get_or_insert(adpc_start + entry_offset(block_index) as u64);
+        }
+        let block = &data[layout.block_data_offset(channel, block_index)..]
+            [..layout.block_byte_len(block_index) as usize];
+        decode_adpcm_samples(
+            block,
+            0..layout.block_samples(block_index),
+            0,
+            yn1,
+            yn2,
+            coeffs,
+            &mut samples,
+        );
+    }
+    if let (count, Some(offset)) = mismatches {
+        report.warning(
+            History,
+            offset,
+            format!(
+                "Channel {channel} has {count} ADPC entries that don't match the previous samples"
+            ),
+        );
+    }
+    if layout.is_looping() && layout.loop_start >= 2 && layout.loop_start < layout.total_samples {
+        let loop_start = layout.loop_start as usize;
+        let expected = (samples[loop_start - 1], samples[loop_start - 2]);
+        let actual = (
+            channel_info.loop_history_sample1,
+            channel_info.loop_history_sample2,
+        );
+        if actual != expected {
+            report.warning(
+                History,
+                info_offset + CHANNEL_LOOP_HISTORY,
+                format!(
+                    "Channel {channel} loop history {actual:?} doesn't match the samples before the loop {expected:?}"
+                ),
+            );
+        }
+    }
+    if (channel_info.history_sample1, channel_info.history_sample2) != (0, 0) {
+        report.warning(
+            History,
+            info_offset + CHANNEL_HISTORY,
+            format!("Channel {channel} has a nonzero initial history"),
+        );
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::Cursor;
+
+    use crate::encoder::encode_brstm;
+
+    use super::{validate, FindingKind, Severity};
+
+    fn encoded_file() -> Vec<u8> {
+        let channels: Vec<Vec<i16>> = (0..2)
+            .map(|c| {
+                (0..20_000)
+                    .map(|i| ((i as f32 / (8.0 + c as f32)).sin() * 10_000.0) as i16)
+                    .collect()
+            })
+            .collect();
+        let brstm = encode_brstm(&channels, 32000, Some(1000)).unwrap();
+        let mut file = Cursor::new(Vec::new());
+        brstm.write_brstm(&mut file).unwrap();
+        file.into_inner()
+    }
+
+    #[test]
+    pub fn structural_findings() {
+        let file = encoded_file();
+        let report = validate(&file);
+        assert!(report.is_valid(), "{:?}", report.findings);
+
+        // truncated DATA section
+        let report = validate(&file[..file.len() - 0x100]);
+        assert!(!report.is_valid());
+        assert!(report
+            .errors()
+            .any(|f| f.kind == FindingKind::Sections && f.offset == Some(0x08)));
+
+        // broken block count
+        let mut broken = file.clone();
+        let head1_off = 0x40 + 8 + u32::from_be_bytes(broken[0x4C..0x50].try_into().unwrap());
+        let total_blocks_off = head1_off as usize + 0x14;
+        broken[total_blocks_off..][..4].copy_from_slice(&5u32.to_be_bytes());
+        let report = validate(&broken);
+        let finding = report
+            .errors()
+            .find(|f| f.kind == FindingKind::BlockLayout)
+            .unwrap();
+        assert_eq!(finding.offset, Some(total_blocks_off as u64));
+
+        // loop start after and at the end, the encoder doesn't write the latter either
+        for loop_start in [30_000u32, 20_000] {
+            let mut broken = file.clone();
+            broken[head1_off as usize + 0x08..][..4].copy_from_slice(&loop_start.to_be_bytes());
+            assert!(validate(&broken)
+                .errors()
+                .any(|f| f.kind == FindingKind::Loop));
+        }
+        assert!(encode_brstm(&[vec![0; 20_000]], 32000, Some(20_000)).is_err());
+
+        // DATA offset at the very end of the range
+        let mut broken = file.clone();
+        broken[0x20..][..4].copy_from_slice(&u32::MAX.to_be_bytes());
+        let report = validate(&broken);
+        assert!(report
+            .errors()
+            .any(|f| f.kind == FindingKind::Sections && f.offset == Some(0x20)));
+
+        // no channels, so no audio is needed, with the DATA section far outside the file
+        let mut broken = file.clone();
+        broken[head1_off as usize + 0x02] = 0;
+        broken[0x20..][..4].copy_from_slice(&0x7FFF_0000u32.to_be_bytes());
+        assert!(!validate(&broken).is_valid());
+
+        // invalid predictor index in the first frame
+        let mut broken = file.clone();
+        let audio_offset =
+            u32::from_be_bytes(broken[head1_off as usize + 0x10..][..4].try_into().unwrap());
+        broken[audio_offset as usize] = 0x80;
+        let report = validate(&broken);
+        let finding = report
+            .findings
+            .iter()
+            .find(|f| f.kind == FindingKind::PredictorScale && f.severity == Severity::Error)
+            .unwrap();
+        assert_eq!(finding.offset, Some(audio_offset as u64));
+
+        assert!(!validate(b"RSTM").is_valid());
+    }
+}
@@ -0,0 +1,168 @@
+use std::io::Cursor;
+
+use crate::{brstm::AudioData, BrstmInfoWithData, BrstmInformation, Error};
+
+/// a BRSTM file that is already in memory, only the headers are parsed and the ADPC and DATA
+/// payload is borrowed from the bytes instead of copied like [`BrstmInformation::into_with_data`]
+/// does. Offers the same accessors and decoding as [`BrstmInfoWithData`]
+#[derive(Debug, Clone)]
+pub struct BrstmView<'a> {
+    pub info: BrstmInformation,
+    pub adpcm_bytes: &'a [u8],
+    pub data_bytes: &'a [u8],
+}
+
+impl<'a> BrstmView<'a> {
+    /// parses the file, the payload has to fit the block layout like with
+    /// [`BrstmInformation::into_with_data`]
+    pub fn from_bytes(bytes: &'a [u8]) -> Result<Self, Error> {
+        let info = BrstmInformation::from_reader(&mut Cursor::new(bytes))?;
+        let (adpcm_range, data_range) = info.payload_ranges(bytes.len() as u64)?;
+        let view = BrstmView {
+            info,
+            adpcm_bytes: &bytes[adpcm_range],
+            data_bytes: &bytes[data_range],
+        };
+        view.check_layout()?;
+        Ok(view)
+    }
+
+    fn audio(&self) -> AudioData<'_, 'a> {
+        AudioData {
+            info: &self.info,
+            adpcm_bytes: self.adpcm_bytes,
+            data_bytes: self.data_bytes,
+        }
+    }
+
+    /// copies the payload, for modifying or writing the file
+    pub fn to_owned_data(&self) -> BrstmInfoWithData {
+        BrstmInfoWithData {
+            info: self.info.clone(),
+            adpcm_bytes: self.adpcm_bytes.to_vec(),
+            data_bytes: self.data_bytes.to_vec(),
+        }
+    }
+
+    /// see [`BrstmInfoWithData::check_layout`]
+    pub fn check_layout(&self) -> Result<(), Error> {
+        self.audio().check_layout()
+    }
+
+    /// panics if the entry doesn't exist, see [`Self::try_get_adpc_bytes`]
+    pub fn get_adpc_bytes(&self, channel: u8, block_index: u32) -> &'a [u8; 4] {
+        self.audio().get_adpc_bytes(channel, block_index)
+    }
+
+    pub fn try_get_adpc_bytes(&self, channel: u8, block_index: u32) -> Result<&'a [u8; 4], Error> {
+        self.audio().try_get_adpc_bytes(channel, block_index)
+    }
+
+    /// panics if the entry doesn't exist, see [`Self::try_get_adpc_values`]
+    pub fn get_adpc_values(&self, channel: u8, block_index: u32) -> (i16, i16) {
+        self.audio().get_adpc_values(channel, block_index)
+    }
+
+    pub fn try_get_adpc_values(&self, channel: u8, block_index: u32) -> Result<(i16, i16), Error> {
+        self.audio().try_get_adpc_values(channel, block_index)
+    }
+
+    /// panics if the block doesn't exist, see [`Self::try_get_data_block`]
+    pub fn get_data_block(&self, channel: u8, block_index: u32) -> &'a [u8] {
+        self.audio().get_data_block(channel, block_index)
+    }
+
+    pub fn try_get_data_block(&self, channel: u8, block_index: u32) -> Result<&'a [u8], Error> {
+        self.audio().try_get_data_block(channel, block_index)
+    }
+
+    pub fn get_data_block_with_samplecount(
+        &self,
+        channel: u8,
+        block_index: u32,
+    ) -> (&'a [u8], u32) {
+        (
+            self.get_data_block(channel, block_index),
+            self.info.info.block_samples(block_index),
+        )
+    }
+
+    /// panics if the channel doesn't exist, see [`Self::try_get_pcm`]
+    pub fn get_pcm(&self, channel: u8) -> Vec<i16> {
+        self.try_get_pcm(channel).unwrap()
+    }
+
+    pub fn try_get_pcm(&self, channel: u8) -> Result<Vec<i16>, Error> {
+        self.try_get_pcm_range(channel, 0, u32::MAX)
+    }
+
+    /// see [`BrstmInfoWithData::get_pcm_range`]
+    pub fn get_pcm_range(&self, channel: u8, start: u32, end: u32) -> Vec<i16> {
+        self.try_get_pcm_range(channel, start, end).unwrap()
+    }
+
+    pub fn try_get_pcm_range(&self, channel: u8, start: u32, end: u32) -> Result<Vec<i16>, Error> {
+        self.audio().try_get_pcm_range(channel, start, end)
+    }
+
+    /// [`Self::get_pcm_range`] for all channels
+    pub fn get_pcm_range_all(&self, start: u32, end: u32) -> Vec<Vec<i16>> {
+        (0..self.info.channel_count())
+            .map(|channel| self.get_pcm_range(channel, start, end))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::Cursor;
+
+    use crate::{encoder::encode_brstm, view::BrstmView, BrstmInformation, Error};
+
+    #[test]
+    pub fn view_matches_owned() {
+        let channels = vec![(0..40_000).map(|i| (i % 500) as i16 * 40).collect(); 2];
+        let brstm = encode_brstm(&channels, 32000, Some(500)).unwrap();
+        let mut written = Cursor::new(Vec::new());
+        brstm.write_brstm(&mut written).unwrap();
+        let file = written.into_inner();
+        written = Cursor::new(file.clone());
+        let owned = BrstmInformation::from_reader(&mut written)
+            .unwrap()
+            .into_with_data(&mut written)
+            .unwrap();
+
+        let view = BrstmView::from_bytes(&file).unwrap();
+        assert_eq!(view.data_bytes, owned.data_bytes.as_slice());
+        // borrowed straight from the file
+        assert!(file.as_ptr_range().contains(&view.data_bytes.as_ptr()));
+        for channel in 0..2 {
+            assert_eq!(view.get_pcm(channel), owned.get_pcm(channel));
+            assert_eq!(
+                view.get_pcm_range(channel, 20_000, 30_000),
+                owned.get_pcm_range(channel, 20_000, 30_000)
+            );
+            for block in 0..view.info.info.total_blocks {
+                assert_eq!(
+                    view.get_adpc_values(channel, block),
+                    owned.get_adpc_values(channel, block)
+                );
+                assert_eq!(
+                    view.get_data_block(channel, block),
+                    owned.get_data_block(channel, block)
+                );
+            }
+        }
+        assert!(matches!(
+            view.try_get_adpc_bytes(2, 0),
+            Err(Error::ChannelOutOfRange { .. })
+        ));
+        let mut rewritten = Cursor::new(Vec::new());
+        view.to_owned_data()
+            .write_brstm_lossless(&mut rewritten)
+            .unwrap();
+        assert_eq!(rewritten.into_inner(), file);
+
+        assert!(BrstmView::from_bytes(&file[..file.len() - 0x100]).is_err());
+    }
+}
@@ -0,0 +1,478 @@
+use std::io::{self, Read, Write};
+
+use thiserror::Error;
+
+use crate::{structs::Channels, BrstmInfoWithData};
+
+// note: only 16 bit PCM WAVs are written, but 8/16/24/32 bit integer and 32/64 bit float
+// WAVs can be read. Loop points go into a `smpl` chunk, where the loop end is inclusive.
+
+const FORMAT_PCM: u16 = 1;
+const FORMAT_FLOAT: u16 = 3;
+const FORMAT_EXTENSIBLE: u16 = 0xFFFE;
+
+#[derive(Error, Debug)]
+#[non_exhaustive]
+pub enum WavError {
+    #[error("IO error: {0}")]
+    Io(#[from] io::Error),
+    #[error("Track {0} doesn't exist")]
+    TrackNotExistent(usize),
+    #[error("Channel {0} doesn't exist")]
+    ChannelNotExistent(u8),
+    #[error("All Channels must have the same length, got {0:?}")]
+    MissmatchedLengths(Vec<usize>),
+    #[error("Invalid WAV: {0}")]
+    Invalid(&'static str),
+    #[error("Unsupported WAV format {format_tag:#X} with {bits_per_sample} bits per sample")]
+    UnsupportedFormat {
+        format_tag: u16,
+        bits_per_sample: u16,
+    },
+    #[error("{channels} channels of {samples} samples at {sample_rate} Hz don't fit in a WAV")]
+    TooLarge {
+        channels: usize,
+        samples: usize,
+        sample_rate: u32,
+    },
+}
+
+/// decoded audio of a WAV file
+#[derive(Debug, Clone, Default)]
+pub struct WavData {
+    pub channels: Vec<Vec<i16>>,
+    pub sample_rate: u32,
+    /// loop start and (exclusive) loop end in samples, from the first loop of the `smpl` chunk
+    pub loop_points: Option<(u32, u32)>,
+}
+
+struct WavFormat {
+    format_tag: u16,
+    channel_count: u16,
+    sample_rate: u32,
+    block_align: u16,
+    bits_per_sample: u16,
+}
+
+fn u16_at(buf: &[u8], off: usize) -> u16 {
+    u16::from_le_bytes([buf[off], buf[off + 1]])
+}
+
+fn u32_at(buf: &[u8], off: usize) -> u32 {
+    u32::from_le_bytes(buf[off..][..4].try_into().unwrap())
+}
+
+impl WavFormat {
+    fn parse(chunk: &[u8]) -> Result<Self, WavError> {
+        if chunk.len() < 16 {
+            return Err(WavError::Invalid("fmt chunk too short"));
+        }
+        let mut format_tag = u16_at(chunk, 0);
+        if format_tag == FORMAT_EXTENSIBLE {
+            // the actual format is in the first 2 bytes of the sub format GUID
+            if chunk.len() < 26 {
+                return Err(WavError::Invalid("extensible fmt chunk too short"));
+            }
+            format_tag = u16_at(chunk, 24);
+        }
+        let format = WavFormat {
+            format_tag,
+            channel_count: u16_at(chunk, 2),
+            sample_rate: u32_at(chunk, 4),
+            block_align: u16_at(chunk, 12),
+            bits_per_sample: u16_at(chunk, 14),
+        };
+        let supported = match format.format_tag {
+            FORMAT_PCM => matches!(format.bits_per_sample, 8 | 16 | 24 | 32),
+            FORMAT_FLOAT => matches!(format.bits_per_sample, 32 | 64),
+            _ => false,
+        };
+        if !supported {
+            return Err(WavError::UnsupportedFormat {
+                format_tag: format.format_tag,
+                bits_per_sample: format.bits_per_sample,
+            });
+        }
+        if format.channel_count == 0 {
+            return Err(WavError::Invalid("no channels"));
+        }
+        if (format.block_align as u32)
+            < format.channel_count as u32 * (format.bits_per_sample as u32 / 8)
+        {
+            return Err(WavError::Invalid("block align too small"));
+        }
+        Ok(format)
+    }
+
+    /// converts a single sample to 16 bit
+    fn convert_sample(&self, bytes: &[u8]) -> i16 {
+        match (self.format_tag, self.bits_per_sample) {
+            // 8 bit is unsigned
+            (FORMAT_PCM, 8) => ((bytes[0] as i16) - 128) << 8,
+            (FORMAT_PCM, 16) => i16::from_le_bytes([bytes[0], bytes[1]]),
+            (FORMAT_PCM, 24) => i16::from_le_bytes([bytes[1], bytes[2]]),
+            (FORMAT_PCM, 32) => i16::from_le_bytes([bytes[2], bytes[3]]),
+            (FORMAT_FLOAT, 32) => {
+                float_to_i16(f32::from_le_bytes(bytes[..4].try_into().unwrap()).into())
+            }
+            (FORMAT_FLOAT, 64) => float_to_i16(f64::from_le_bytes(bytes[..8].try_into().unwrap())),
+            _ => unreachable!("format is checked when parsing"),
+        }
+    }
+}
+
+fn float_to_i16(sample: f64) -> i16 {
+    // NaN gets turned into 0 by the cast
+    (sample.clamp(-1.0, 1.0) * i16::MAX as f64).round() as i16
+}
+
+fn write_chunk_header<W: Write>(w: &mut W, id: &[u8; 4], len: u32) -> io::Result<()> {
+    w.write_all(id)?;
+    w.write_all(&len.to_le_bytes())
+}
+
+fn write_u32s<W: Write>(w: &mut W, values: &[u32]) -> io::Result<()> {
+    for value in values {
+        w.write_all(&value.to_le_bytes())?;
+    }
+    Ok(())
+}
+
+/// writes the channels as an interleaved 16 bit WAV,
+/// `loop_points` are the loop start and the (exclusive) loop end in samples
+pub fn write_wav<W: Write>(
+    w: &mut W,
+    channels: &[Vec<i16>],
+    sample_rate: u32,
+    loop_points: Option<(u32, u32)>,
+) -> Result<(), WavError> {
+    let sample_count = channels.first().map_or(0, Vec::len);
+    if channels.iter().any(|c| c.len() != sample_count) {
+        return Err(WavError::MissmatchedLengths(
+            channels.iter().map(Vec::len).collect(),
+        ));
+    }
+    let too_large = || WavError::TooLarge {
+        channels: channels.len(),
+        samples: sample_count,
+        sample_rate,
+    };
+    // the block align is only 16 bits
+    let block_align = channels
+        .len()
+        .checked_mul(2)
+        .and_then(|len| u16::try_from(len).ok())
+        .ok_or_else(too_large)?;
+    let channel_count = block_align / 2;
+    let byte_rate = sample_rate
+        .checked_mul(block_align.into())
+        .ok_or_else(too_large)?;
+    let data_len = u32::try_from(sample_count)
+        .ok()
+        .and_then(|count| count.checked_mul(block_align.into()))
+        .ok_or_else(too_large)?;
+    // header + a single loop
+    let smpl_len = 36 + 24;
+    // "WAVE" + fmt chunk + data chunk (+ smpl chunk)
+    let mut header_len = 4 + (8 + 16) + 8;
+    if loop_points.is_some() {
+        header_len += 8 + smpl_len;
+    }
+    let riff_len = data_len.checked_add(header_len).ok_or_else(too_large)?;
+
+    write_chunk_header(w, b"RIFF", riff_len)?;
+    w.write_all(b"WAVE")?;
+
+    write_chunk_header(w, b"fmt ", 16)?;
+    // PCM
+    w.write_all(&1u16.to_le_bytes())?;
+    w.write_all(&channel_count.to_le_bytes())?;
+    write_u32s(w, &[sample_rate, byte_rate])?;
+    w.write_all(&block_align.to_le_bytes())?;
+    // bits per sample
+    w.write_all(&16u16.to_le_bytes())?;
+
+    if let Some((loop_start, loop_end)) = loop_points {
+        write_chunk_header(w, b"smpl", smpl_len)?;
+        let sample_period = 1_000_000_000u32.checked_div(sample_rate).unwrap_or(0);
+        write_u32s(
+            w,
+            &[
+                // manufacturer, product
+                0,
+                0,
+                sample_period,
+                // MIDI unity note, pitch fraction, SMPTE format and offset
+                60,
+                0,
+                0,
+                0,
+                // one loop, no sampler data
+                1,
+                0,
+                // cue point id, forward loop
+                0,
+                0,
+                loop_start,
+                loop_end.saturating_sub(1),
+                // fraction, loop forever
+                0,
+                0,
+            ],
+        )?;
+    }
+
+    write_chunk_header(w, b"data", data_len)?;
+    // interleave in chunks to avoid lots of tiny writes
+    let mut buf = Vec::with_capacity(4096 * block_align as usize);
+    for chunk_start in (0..sample_count).step_by(4096) {
+        buf.clear();
+        for sample_idx in chunk_start..(chunk_start + 4096).min(sample_count) {
+            for channel in channels {
+                buf.extend_from_slice(&channel[sample_idx].to_le_bytes());
+            }
+        }
+        w.write_all(&buf)?;
+    }
+    w.flush()?;
+    Ok(())
+}
+
+/// reads a WAV file and converts all samples to 16 bit
+pub fn read_wav<R: Read>(r: &mut R) -> Result<WavData, WavError> {
+    let mut header = [0; 12];
+    r.read_exact(&mut header)?;
+    if &header[..4] != b"RIFF" || &header[8..] != b"WAVE" {
+        return Err(WavError::Invalid("not a RIFF WAVE file"));
+    }
+    let mut format = None;
+    let mut data = None;
+    let mut loop_points = None;
+    loop {
+        let mut chunk_header = [0; 8];
+        match r.read_exact(&mut chunk_header) {
+            Ok(()) => (),
+            // some files have a wrong RIFF size, so just read until the end
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => break,
+            Err(e) => return Err(e.into()),
+        }
+        let chunk_len = u32_at(&chunk_header, 4) as u64;
+        // chunks are padded to an even length
+        let padded_len = chunk_len + (chunk_len & 1);
+        match &chunk_header[..4] {
+            b"fmt " | b"data" | b"smpl" => {
+                let mut chunk = Vec::new();
+                r.by_ref().take(padded_len).read_to_end(&mut chunk)?;
+                // the data chunk is allowed to be cut off
+                if chunk.len() < chunk_len as usize && &chunk_header[..4] != b"data" {
+                    return Err(WavError::Invalid("chunk cut off"));
+                }
+                chunk.truncate(chunk_len as usize);
+                match &chunk_header[..4] {
+                    b"fmt " => format = Some(WavFormat::parse(&chunk)?),
+                    b"data" => data = Some(chunk),
+                    _ => {
+                        // the number of loops is at 28, the first loop starts at 36
+                        if chunk.len() >= 36 + 24 && u32_at(&chunk, 28) > 0 {
+                            let loop_start = u32_at(&chunk, 36 + 8);
+                            let loop_end = u32_at(&chunk, 36 + 12);
+                            if loop_start <= loop_end {
+                                loop_points = Some((loop_start, loop_end.saturating_add(1)));
+                            }
+                        }
+                    }
+                }
+            }
+            _ => {
+                io::copy(&mut r.by_ref().take(padded_len), &mut io::sink())?;
+            }
+        }
+    }
+    let format = format.ok_or(WavError::Invalid("no fmt chunk"))?;
+    let data = data.ok_or(WavError::Invalid("no data chunk"))?;
+    let bytes_per_sample = format.bits_per_sample as usize / 8;
+    let frame_count = data.len() / format.block_align as usize;
+    let mut channels: Vec<Vec<i16>> = (0..format.channel_count)
+        .map(|_| Vec::with_capacity(frame_count))
+        .collect();
+    for frame in data.chunks_exact(format.block_align as usize) {
+        for (channel_idx, channel) in channels.iter_mut().enumerate() {
+            channel.push(format.convert_sample(&frame[channel_idx * bytes_per_sample..]));
+        }
+    }
+    Ok(WavData {
+        channels,
+        sample_rate: format.sample_rate,
+        loop_points,
+    })
+}
+
+fn loop_points(brstm: &BrstmInfoWithData) -> Option<(u32, u32)> {
+    let head1 = &brstm.info.info;
+    head1
+        .is_looping()
+        .then_some((head1.loop_start, head1.total_samples))
+}
+
+/// decodes all channels of the BRSTM and writes them into a single WAV
+pub fn brstm_to_wav<W: Write>(brstm: &BrstmInfoWithData, w: &mut W) -> Result<(), WavError> {
+    let channels: Vec<_> = (0..brstm.info.channel_count())
+        .map(|channel| brstm.get_pcm(channel))
+        .collect();
+    write_wav(
+        w,
+        &channels,
+        brstm.info.info.sample_rate.into(),
+        loop_points(brstm),
+    )
+}
+
+/// decodes only the channels belonging to the track and writes them into a mono or stereo WAV
+pub fn track_to_wav<W: Write>(
+    brstm: &BrstmInfoWithData,
+    track: usize,
+    w: &mut W,
+) -> Result<(), WavError> {
+    let track_channels = match brstm
+        .info
+        .tracks
+        .get(track)
+        .ok_or(WavError::TrackNotExistent(track))?
+        .channels
+    {
+        Channels::Mono(c) => vec![c],
+        Channels::Stereo(l, r) => vec![l, r],
+    };
+    let channels = track_channels
+        .into_iter()
+        .map(|channel| {
+            if channel >= brstm.info.channel_count() {
+                return Err(WavError::ChannelNotExistent(channel));
+            }
+            Ok(brstm.get_pcm(channel))
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    write_wav(
+        w,
+        &channels,
+        brstm.info.info.sample_rate.into(),
+        loop_points(brstm),
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{
+        encoder::{encode_brstm_with_options, EncodeOptions},
+        structs::Codec,
+    };
+
+    use super::{read_wav, track_to_wav, write_wav, WavError};
+
+    #[test]
+    pub fn write_track() {
+        let channels: Vec<Vec<i16>> = (0..4)
+            .map(|c| (0..1000).map(|i| (i * (c + 1)) as i16).collect())
+            .collect();
+        let brstm = encode_brstm_with_options(
+            &channels,
+            22050,
+            Some(100),
+            &EncodeOptions {
+                codec: Codec::Pcm16,
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        let mut wav = Vec::new();
+        track_to_wav(&brstm, 1, &mut wav).unwrap();
+
+        let u32_at = |off: usize| u32::from_le_bytes(wav[off..][..4].try_into().unwrap());
+        assert_eq!(&wav[..4], b"RIFF");
+        assert_eq!(u32_at(4) as usize, wav.len() - 8);
+        // stereo, 22050Hz
+        assert_eq!(wav[22], 2);
+        assert_eq!(u32_at(24), 22050);
+        assert_eq!(&wav[36..40], b"smpl");
+        // loop start and inclusive end
+        assert_eq!(u32_at(44 + 44), 100);
+        assert_eq!(u32_at(44 + 48), 999);
+        let data_off = 44 + 60;
+        assert_eq!(&wav[data_off..][..4], b"data");
+        assert_eq!(u32_at(data_off + 4), 4000);
+        let samples: Vec<i16> = wav[data_off + 8..]
+            .chunks_exact(2)
+            .map(|b| i16::from_le_bytes([b[0], b[1]]))
+            .collect();
+        // track 1 consists of channel 2 and 3
+        assert_eq!(
+            samples[..2 * 10],
+            [0, 0, 3, 4, 6, 8, 9, 12, 12, 16, 15, 20, 18, 24, 21, 28, 24, 32, 27, 36]
+        );
+        assert!(track_to_wav(&brstm, 2, &mut Vec::new()).is_err());
+    }
+
+    #[test]
+    pub fn write_too_large() {
+        let too_large = |channels: &[Vec<i16>], sample_rate| {
+            matches!(
+                write_wav(&mut Vec::new(), channels, sample_rate, None),
+                Err(WavError::TooLarge { .. })
+            )
+        };
+        // the block align doesn't fit in 16 bits
+        assert!(too_large(&vec![Vec::new(); 40_000], 44100));
+        // neither does the byte rate in 32
+        assert!(too_large(&[vec![0; 10], vec![0; 10]], u32::MAX));
+        assert!(!too_large(&vec![vec![0; 10]; 32767], 44100));
+    }
+
+    #[test]
+    pub fn read_formats() {
+        let channels = vec![vec![0, 1000, -1000, i16::MAX], vec![i16::MIN, -1, 1, 0]];
+        let mut wav = Vec::new();
+        write_wav(&mut wav, &channels, 32000, Some((1, 4))).unwrap();
+        let read = read_wav(&mut wav.as_slice()).unwrap();
+        assert_eq!(read.channels, channels);
+        assert_eq!(read.sample_rate, 32000);
+        assert_eq!(read.loop_points, Some((1, 4)));
+
+        // build a 24 bit mono and a float stereo WAV by hand
+        let build = |format_tag: u16, channel_count: u16, bits: u16, data: &[u8]| {
+            let block_align = channel_count * bits / 8;
+            let mut wav = Vec::new();
+            wav.extend_from_slice(b"RIFF");
+            wav.extend_from_slice(&(4 + 24 + 8 + data.len() as u32).to_le_bytes());
+            wav.extend_from_slice(b"WAVEfmt ");
+            wav.extend_from_slice(&16u32.to_le_bytes());
+            wav.extend_from_slice(&format_tag.to_le_bytes());
+            wav.extend_from_slice(&channel_count.to_le_bytes());
+            wav.extend_from_slice(&44100u32.to_le_bytes());
+            wav.extend_from_slice(&(44100 * block_align as u32).to_le_bytes());
+            wav.extend_from_slice(&block_align.to_le_bytes());
+            wav.extend_from_slice(&bits.to_le_bytes());
+            wav.extend_from_slice(b"data");
+            wav.extend_from_slice(&(data.len() as u32).to_le_bytes());
+            wav.extend_from_slice(data);
+            wav
+        };
+        let wav = build(1, 1, 24, &[0xFF, 0x34, 0x12, 0x00, 0x00, 0x80]);
+        let read = read_wav(&mut wav.as_slice()).unwrap();
+        assert_eq!(read.channels, vec![vec![0x1234, i16::MIN]]);
+        assert_eq!(read.loop_points, None);
+
+        let data: Vec<u8> = [0.5f32, -1.0, 2.0, 0.0]
+            .iter()
+            .flat_map(|f| f.to_le_bytes())
+            .collect();
+        let wav = build(3, 2, 32, &data);
+        let read = read_wav(&mut wav.as_slice()).unwrap();
+        assert_eq!(
+            read.channels,
+            vec![vec![16384, i16::MAX], vec![-i16::MAX, 0]]
+        );
+
+        let wav = build(2, 1, 4, &[0; 4]);
+        assert!(read_wav(&mut wav.as_slice()).is_err());
+    }
+}